@@ -0,0 +1,305 @@
+//! Adaptive particle resolution: particles entering a refinement region split
+//! into four daughters, and close pairs in a coarsening region merge into one.
+//! Both conserve mass and momentum, and keep h proportional to the particle
+//! spacing, h^2 ~ m in two dimensions.
+
+use crate::neighbours::ParticleNeighbours;
+use crate::scene::Domain;
+use crate::sph::{Duck, Parameters, Particle};
+
+/// Tolerance on the mass ratio limits, which are reached by repeated halving
+const MASS_TOLERANCE: f64 = 1e-9;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Region {
+    Rectangle {
+        min_x: f64,
+        min_y: f64,
+        max_x: f64,
+        max_y: f64,
+    },
+    /// Disc moving with the duck
+    AroundDuck { radius: f64 },
+}
+
+impl Region {
+    fn contains(&self, particle: &Particle, duck: &Duck) -> bool {
+        match *self {
+            Region::Rectangle {
+                min_x,
+                min_y,
+                max_x,
+                max_y,
+            } => (min_x..max_x).contains(&particle.x) && (min_y..max_y).contains(&particle.y),
+            Region::AroundDuck { radius } => {
+                (particle.x - duck.x).hypot(particle.y - duck.y) < radius
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AdaptiveResolution {
+    pub refine: Vec<Region>,
+    /// Particles in both a refinement and a coarsening region are refined
+    pub coarsen: Vec<Region>,
+    /// Lightest particle splitting may produce, relative to `Parameters::mass`
+    pub min_mass_ratio: f64,
+    /// Heaviest particle merging may produce, relative to `Parameters::mass`.
+    /// Widens the neighbour search to the largest smoothing length this allows.
+    pub max_mass_ratio: f64,
+    /// Steps between passes
+    pub interval: u64,
+}
+
+impl AdaptiveResolution {
+    /// Refine to a quarter of the mass around the duck and coarsen to twice
+    /// the mass in the deepest fifth of `domain`
+    pub fn new(domain: &Domain) -> AdaptiveResolution {
+        AdaptiveResolution {
+            refine: vec![Region::AroundDuck { radius: 1.0 }],
+            coarsen: vec![Region::Rectangle {
+                min_x: domain.min_x,
+                min_y: domain.max_y - 0.2 * (domain.max_y - domain.min_y),
+                max_x: domain.max_x,
+                max_y: domain.max_y,
+            }],
+            min_mass_ratio: 0.25,
+            max_mass_ratio: 2.0,
+            interval: 10,
+        }
+    }
+
+    /// Split and merge the particles once. Merging pairs each particle with
+    /// its nearest neighbour, so `neighbours` must index `particles` as they
+    /// are. Returns whether the particles changed.
+    pub fn adapt<N: ParticleNeighbours>(
+        &self,
+        particles: &mut Vec<Particle>,
+        neighbours: &N,
+        parameters: &Parameters,
+        duck: &Duck,
+    ) -> bool {
+        let min_mass = self.min_mass_ratio * parameters.mass * (1.0 - MASS_TOLERANCE);
+        let max_mass = self.max_mass_ratio * parameters.mass * (1.0 + MASS_TOLERANCE);
+        let refined = |particle: &Particle| {
+            self.refine
+                .iter()
+                .any(|region| region.contains(particle, duck))
+        };
+        let coarsened = |particle: &Particle| {
+            !refined(particle)
+                && self
+                    .coarsen
+                    .iter()
+                    .any(|region| region.contains(particle, duck))
+        };
+        let mut merged = vec![false; particles.len()];
+        let mut changed = false;
+        let mut adapted = Vec::with_capacity(particles.len());
+        for (i, particle) in particles.iter().enumerate() {
+            if merged[i] {
+                continue;
+            }
+            if refined(particle) && 0.25 * particle.mass >= min_mass {
+                adapted.extend_from_slice(&split(particle, parameters));
+                changed = true;
+            } else if coarsened(particle) {
+                // Later particles only, the earlier ones are already placed.
+                // Closer than the spacing of a particle of twice the mass.
+                let mut nearest = None;
+                let mut nearest_r2 = 2.0 * particle.mass / parameters.rest_density;
+                neighbours.for_each_neighbour_of(particles, i, |j| {
+                    let other = &particles[j as usize];
+                    let r2 = (particle.x - other.x).powi(2) + (particle.y - other.y).powi(2);
+                    if j as usize > i
+                        && !merged[j as usize]
+                        && r2 < nearest_r2
+                        && particle.mass + other.mass <= max_mass
+                        && coarsened(other)
+                    {
+                        nearest = Some(j as usize);
+                        nearest_r2 = r2;
+                    }
+                });
+                match nearest {
+                    Some(j) => {
+                        merged[j] = true;
+                        adapted.push(merge(particle, &particles[j]));
+                        changed = true;
+                    }
+                    None => adapted.push(particle.clone()),
+                }
+            } else {
+                adapted.push(particle.clone());
+            }
+        }
+        *particles = adapted;
+        changed
+    }
+}
+
+/// Four daughters of a quarter of the mass each, on the corners of a square
+/// of half the particle spacing, with the velocity, density and forces
+/// (per unit volume) of the parent and half its smoothing length
+fn split(particle: &Particle, parameters: &Parameters) -> [Particle; 4] {
+    let offset = 0.25 * (particle.mass / parameters.rest_density).sqrt();
+    [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)].map(|(sx, sy)| Particle {
+        x: particle.x + sx * offset,
+        y: particle.y + sy * offset,
+        mass: 0.25 * particle.mass,
+        h: 0.5 * particle.h,
+        asleep: false,
+        calm_steps: 0,
+        ..particle.clone()
+    })
+}
+
+/// One particle at the centre of mass with the total mass and momentum of the
+/// pair, and the smoothing length of their combined area
+fn merge(particle1: &Particle, particle2: &Particle) -> Particle {
+    let mass = particle1.mass + particle2.mass;
+    let average = |a: f64, b: f64| (particle1.mass * a + particle2.mass * b) / mass;
+    Particle {
+        x: average(particle1.x, particle2.x),
+        y: average(particle1.y, particle2.y),
+        vx: average(particle1.vx, particle2.vx),
+        vy: average(particle1.vy, particle2.vy),
+        fx: average(particle1.fx, particle2.fx),
+        fy: average(particle1.fy, particle2.fy),
+        density: average(particle1.density, particle2.density),
+        density_rate: average(particle1.density_rate, particle2.density_rate),
+        pressure: average(particle1.pressure, particle2.pressure),
+        mass,
+        h: particle1.h.hypot(particle2.h),
+        asleep: false,
+        calm_steps: 0,
+        ..particle1.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::neighbours::BruteForce;
+
+    /// Totals of mass and momentum
+    fn totals(particles: &[Particle]) -> (f64, f64, f64) {
+        particles
+            .iter()
+            .fold((0.0, 0.0, 0.0), |(m, px, py), particle| {
+                (
+                    m + particle.mass,
+                    px + particle.mass * particle.vx,
+                    py + particle.mass * particle.vy,
+                )
+            })
+    }
+
+    fn close(a: (f64, f64, f64), b: (f64, f64, f64)) -> bool {
+        (a.0 - b.0).abs() < 1e-9 * a.0 && (a.1 - b.1).abs() < 1e-9 && (a.2 - b.2).abs() < 1e-9
+    }
+
+    #[test]
+    fn test_split_and_merge_conserve_mass_and_momentum() {
+        let parameters = Parameters::new();
+        let region = Region::Rectangle {
+            min_x: 0.0,
+            min_y: 0.0,
+            max_x: 1.0,
+            max_y: 1.0,
+        };
+        let mut particles: Vec<Particle> = (0..3)
+            .map(|i| {
+                let mut particle = parameters.particle(0.5 + 0.3 * i as f64, 0.5);
+                particle.vx = i as f64;
+                particle.vy = 1.0 - i as f64;
+                particle.density = parameters.rest_density;
+                particle
+            })
+            .collect();
+        let start = totals(&particles);
+        let refine = AdaptiveResolution {
+            refine: vec![region.clone()],
+            coarsen: Vec::new(),
+            ..AdaptiveResolution::new(&parameters.domain)
+        };
+        let search = BruteForce::new(&particles, parameters.support_radius());
+        assert!(refine.adapt(&mut particles, &search, &parameters, &Duck::new()));
+        // The third particle is outside the region
+        assert_eq!(particles.len(), 9);
+        assert!(close(totals(&particles), start));
+        assert!(particles[..8]
+            .iter()
+            .all(|particle| particle.h == 0.5 * parameters.h));
+        // The daughters are already at the smallest mass
+        let search = BruteForce::new(&particles, parameters.support_radius());
+        assert!(!refine.adapt(&mut particles, &search, &parameters, &Duck::new()));
+
+        let coarsen = AdaptiveResolution {
+            refine: Vec::new(),
+            coarsen: vec![region],
+            ..AdaptiveResolution::new(&parameters.domain)
+        };
+        for _ in 0..2 {
+            let search = BruteForce::new(&particles, parameters.support_radius());
+            assert!(coarsen.adapt(&mut particles, &search, &parameters, &Duck::new()));
+        }
+        assert_eq!(particles.len(), 3);
+        assert!(close(totals(&particles), start));
+        for particle in particles.iter() {
+            assert!((particle.mass - parameters.mass).abs() < 1e-9 * parameters.mass);
+            assert!((particle.h - parameters.h).abs() < 1e-9 * parameters.h);
+        }
+    }
+
+    #[test]
+    fn test_balanced_split_and_merge_are_changes() {
+        // One particle splits into four while three close pairs merge, which
+        // leaves the count as it was
+        let parameters = Parameters::new();
+        let spacing = parameters.particle_spacing();
+        let mut particles = vec![parameters.particle(0.5, 0.5)];
+        for i in 0..3 {
+            let x = 2.0 + i as f64;
+            particles.push(parameters.particle(x, 2.0));
+            particles.push(parameters.particle(x + 0.5 * spacing, 2.0));
+        }
+        let adaptive = AdaptiveResolution {
+            refine: vec![Region::Rectangle {
+                min_x: 0.0,
+                min_y: 0.0,
+                max_x: 1.0,
+                max_y: 1.0,
+            }],
+            coarsen: vec![Region::Rectangle {
+                min_x: 1.5,
+                min_y: 1.5,
+                max_x: 4.5,
+                max_y: 2.5,
+            }],
+            ..AdaptiveResolution::new(&parameters.domain)
+        };
+        let search = BruteForce::new(&particles, parameters.support_radius());
+        assert!(adaptive.adapt(&mut particles, &search, &parameters, &Duck::new()));
+        assert_eq!(particles.len(), 7);
+    }
+
+    #[test]
+    fn test_default_coarsening_follows_the_domain() {
+        let domain = Domain {
+            min_x: -10.0,
+            min_y: 0.0,
+            max_x: 10.0,
+            max_y: 20.0,
+        };
+        let adaptive = AdaptiveResolution::new(&domain);
+        let parameters = Parameters::new();
+        let duck = Duck::new();
+        let deep = parameters.particle(-9.0, 19.0);
+        let shallow = parameters.particle(-9.0, 10.0);
+        assert!(adaptive.coarsen[0].contains(&deep, &duck));
+        assert!(!adaptive.coarsen[0].contains(&shallow, &duck));
+    }
+}
@@ -0,0 +1,208 @@
+//! Wall clock benchmarks of the solver, run with `x86 bench`
+
+use crate::grid;
+use crate::kernel_table::{Interpolation, Tabulated};
+use crate::kernels::Kernel;
+use crate::math::GOLDEN_ANGLE;
+use crate::sph;
+
+use std::time;
+
+const DT: f64 = 0.0001;
+const STEPS: u32 = 3;
+
+/// Average time of a full `update_state` after one warm-up step
+fn time_steps(parameters: &sph::Parameters) -> f64 {
+    let mut state = sph::create_initial_state(parameters.clone());
+    sph::update_state(&mut state, DT, sph::SPHDebug::new());
+    let t1 = time::Instant::now();
+    for _ in 0..STEPS {
+        sph::update_state(&mut state, DT, sph::SPHDebug::new());
+    }
+    t1.elapsed().as_secs_f64() * 1000.0 / STEPS as f64
+}
+
+/// Serial against parallel density, force and integration passes
+fn parallel(parameters: &sph::Parameters) {
+    for &resolution in [100, 317].iter() {
+        let serial = time_steps(&sph::Parameters {
+            parallel: false,
+            ..sph::Parameters::with_resolution(resolution)
+        });
+        let parallel = time_steps(&sph::Parameters {
+            parallel: true,
+            threads: parameters.threads,
+            ..sph::Parameters::with_resolution(resolution)
+        });
+        println!(
+            "{} particles: serial {:.1} ms/step, parallel {:.1} ms/step, speed-up {:.2}",
+            resolution * resolution,
+            serial,
+            parallel,
+            serial / parallel
+        );
+    }
+}
+
+/// Allocating `get_neighbours` against the `for_each_neighbour` callback
+fn neighbours(_parameters: &sph::Parameters) {
+    let mut state = sph::create_initial_state(sph::Parameters::with_resolution(100));
+    sph::update_state(&mut state, DT, sph::SPHDebug::new());
+    let grid = state.search.grid().unwrap();
+    let particles = &state.particles;
+    let visit_all = |query: &dyn Fn(&grid::Grid, f64, f64) -> f64| {
+        let t1 = time::Instant::now();
+        let mut sum = 0.0;
+        for _ in 0..STEPS {
+            for particle in particles.iter() {
+                sum += query(grid, particle.x, particle.y);
+            }
+        }
+        (t1.elapsed().as_secs_f64() * 1000.0 / STEPS as f64, sum)
+    };
+    let (allocating, visited1) = visit_all(&|grid, x, y| {
+        let mut sum = 0.0;
+        for j in grid.get_neighbours(x, y) {
+            sum += particles[j as usize].x;
+        }
+        sum
+    });
+    let (callback, visited2) = visit_all(&|grid, x, y| {
+        let mut sum = 0.0;
+        grid.for_each_neighbour(x, y, |j| sum += particles[j as usize].x);
+        sum
+    });
+    assert_eq!(visited1, visited2);
+    println!(
+        "{} particles: get_neighbours {:.1} ms/pass, for_each_neighbour {:.1} ms/pass, speed-up {:.2}",
+        state.particles.len(),
+        allocating,
+        callback,
+        allocating / callback
+    );
+}
+
+/// Per-cell vectors against the compact cell list, with and without sorting
+/// the particles along a Morton curve
+fn cells(parameters: &sph::Parameters) {
+    let base = sph::Parameters {
+        threads: parameters.threads,
+        ..sph::Parameters::with_resolution(100)
+    };
+    let cells = time_steps(&base);
+    let compact = time_steps(&sph::Parameters {
+        grid_backend: grid::GridBackend::Compact,
+        ..base.clone()
+    });
+    let sorted = time_steps(&sph::Parameters {
+        grid_backend: grid::GridBackend::Compact,
+        reorder: Some(grid::Ordering::Morton),
+        reorder_interval: 1,
+        ..base.clone()
+    });
+    println!(
+        "{} particles: cells {:.1} ms/step, compact {:.1} ms/step, compact + morton {:.1} ms/step",
+        base.resolution * base.resolution,
+        cells,
+        compact,
+        sorted
+    );
+}
+
+/// Analytic against tabulated kernel evaluation, alone and in full steps
+fn kernels(parameters: &sph::Parameters) {
+    const PAIRS: usize = 1_000_000;
+    let h = 1.0;
+    let displacements: Vec<(f64, f64)> = (0..PAIRS)
+        .map(|i| {
+            let angle = i as f64 * GOLDEN_ANGLE;
+            let r = 2.0 * h * ((i * 7919) % PAIRS) as f64 / PAIRS as f64;
+            (r * angle.cos(), r * angle.sin())
+        })
+        .collect();
+    let throughput = |kernel: &dyn Kernel| {
+        let t1 = time::Instant::now();
+        let mut sum = 0.0;
+        for &(x, y) in displacements.iter() {
+            let r2 = x * x + y * y;
+            let (gx, gy) = kernel.gradient(x, y, h);
+            sum += kernel.value_r2(r2, h) + gx + gy + kernel.laplacian_r2(r2, h);
+        }
+        assert!(sum.is_finite());
+        PAIRS as f64 / t1.elapsed().as_secs_f64() / 1e6
+    };
+    let analytic = throughput(&parameters.kernel);
+    for &interpolation in [Interpolation::Linear, Interpolation::Cubic].iter() {
+        let table = Tabulated::new(&parameters.kernel, 4096, interpolation);
+        let tabulated = throughput(&table);
+        println!(
+            "{:?} table: analytic {:.1} M pairs/s, tabulated {:.1} M pairs/s, speed-up {:.2}",
+            interpolation,
+            analytic,
+            tabulated,
+            tabulated / analytic
+        );
+    }
+    let base = sph::Parameters {
+        kernel: parameters.kernel,
+        threads: parameters.threads,
+        ..sph::Parameters::with_resolution(100)
+    };
+    print!(
+        "{} particles: analytic {:.1} ms/step",
+        base.resolution * base.resolution,
+        time_steps(&base)
+    );
+    for &size in [256, 1024, 4096].iter() {
+        let mut tabulated = base.clone();
+        tabulated.tabulate(size, Interpolation::Cubic);
+        print!(
+            ", cubic table of {} {:.1} ms/step",
+            size,
+            time_steps(&tabulated)
+        );
+    }
+    println!();
+}
+
+/// Per particle force loop against the symmetric pass over each pair once
+fn pairs(parameters: &sph::Parameters) {
+    for &resolution in [100, 317].iter() {
+        let base = sph::Parameters {
+            threads: parameters.threads,
+            ..sph::Parameters::with_resolution(resolution)
+        };
+        let per_particle = time_steps(&base);
+        let symmetric = time_steps(&sph::Parameters {
+            symmetric_forces: true,
+            ..base.clone()
+        });
+        println!(
+            "{} particles: per particle {:.1} ms/step, symmetric {:.1} ms/step, speed-up {:.2}",
+            resolution * resolution,
+            per_particle,
+            symmetric,
+            per_particle / symmetric
+        );
+    }
+}
+
+type Benchmark = (&'static str, fn(&sph::Parameters));
+
+const BENCHMARKS: [Benchmark; 5] = [
+    ("parallel", parallel),
+    ("neighbours", neighbours),
+    ("cells", cells),
+    ("kernels", kernels),
+    ("pairs", pairs),
+];
+
+/// Run the benchmark called `name`, or all of them
+pub fn run(name: Option<&str>, parameters: &sph::Parameters) {
+    for (benchmark, function) in BENCHMARKS.iter() {
+        if name.is_none() || name == Some(*benchmark) {
+            println!("{}", benchmark);
+            function(parameters);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use crate::math;
+use crate::neighbours::ParticleNeighbours;
+use crate::parallel;
+use crate::sph::{self, Parameters, Particle};
+use crate::walls;
+
+use std::str::FromStr;
+
+/// Below this determinant the neighbourhood is too sparse or too one-sided
+/// to invert, and the raw gradient is used
+const MIN_DETERMINANT: f64 = 0.05;
+
+/// Correction of kernel gradients for incomplete neighbourhoods, e.g. at the
+/// free surface and walls
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GradientCorrection {
+    None,
+    /// Matrix L_i applied to the kernel gradient, exact for linear fields
+    /// in the difference form sum V_j (f_j - f_i) grad W_ij
+    RandlesLibersky,
+    /// Matrix applied to the gradient of the Shepard-normalised kernel,
+    /// exact for linear fields in the plain form sum V_j f_j grad W_ij
+    BonetLok,
+}
+
+impl FromStr for GradientCorrection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(GradientCorrection::None),
+            "randles-libersky" => Ok(GradientCorrection::RandlesLibersky),
+            "bonet-lok" => Ok(GradientCorrection::BonetLok),
+            _ => Err(format!("Unknown gradient correction: {}", s)),
+        }
+    }
+}
+
+/// Per particle correction, computed from its neighbour set
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Correction {
+    /// Row major 2x2 renormalisation matrix
+    pub matrix: [f64; 4],
+    /// Shepard sum S_i = sum V_j W_ij, one without kernel correction
+    pub shepard: f64,
+    /// grad S_i / S_i, zero without kernel correction
+    pub gamma: (f64, f64),
+}
+
+impl Correction {
+    pub fn new() -> Correction {
+        Correction {
+            matrix: [1.0, 0.0, 0.0, 1.0],
+            shepard: 1.0,
+            gamma: (0.0, 0.0),
+        }
+    }
+
+    /// Corrected gradient from the raw kernel gradient and kernel value of a pair
+    pub fn gradient(&self, (gx, gy): (f64, f64), w: f64) -> (f64, f64) {
+        let gx = (gx - w * self.gamma.0) / self.shepard;
+        let gy = (gy - w * self.gamma.1) / self.shepard;
+        let m = &self.matrix;
+        (m[0] * gx + m[1] * gy, m[2] * gx + m[3] * gy)
+    }
+}
+
+/// Corrections of the configured kind for every particle, using the volumes
+/// m / rho of the current densities
+pub fn compute_corrections<N: ParticleNeighbours>(
+    particles: &[Particle],
+    neighbours: &N,
+    parameters: &Parameters,
+) -> Vec<Correction> {
+    compute(
+        particles,
+        neighbours,
+        parameters,
+        parameters.gradient_correction,
+    )
+}
+
+/// Corrections of kind `kind`, whatever the configuration, for terms that
+/// need them regardless
+pub fn compute<N: ParticleNeighbours>(
+    particles: &[Particle],
+    neighbours: &N,
+    parameters: &Parameters,
+    kind: GradientCorrection,
+) -> Vec<Correction> {
+    let kernel = parameters.kernel_evaluator();
+    if kind == GradientCorrection::None {
+        return vec![Correction::new(); particles.len()];
+    }
+    parallel::map(particles.len(), parameters.parallel, |i| {
+        let particle1 = &particles[i];
+        let walls = walls::Walls::near(particle1, parameters);
+        let mut correction = Correction::new();
+        if kind == GradientCorrection::BonetLok {
+            let (mut shepard, mut gamma_x, mut gamma_y) = (0.0, 0.0, 0.0);
+            neighbours.for_each_neighbour_of(particles, i, |j| {
+                let mut add = |particle2: &Particle| {
+                    let (rx, ry) = (particle1.x - particle2.x, particle1.y - particle2.y);
+                    let h = sph::pair_h(particle1, particle2);
+                    let volume = particle2.mass / particle2.density;
+                    let (gx, gy) = kernel.gradient(rx, ry, h);
+                    shepard += volume * kernel.value(math::length(rx, ry), h);
+                    gamma_x += volume * gx;
+                    gamma_y += volume * gy;
+                };
+                let particle2 = &particles[j as usize];
+                add(particle2);
+                walls.for_each_image(particle2, add);
+            });
+            correction.shepard = shepard;
+            correction.gamma = (gamma_x / shepard, gamma_y / shepard);
+        }
+        let mut a = [0.0; 4];
+        neighbours.for_each_neighbour_of(particles, i, |j| {
+            let mut add = |particle2: &Particle| {
+                let (rx, ry) = (particle1.x - particle2.x, particle1.y - particle2.y);
+                let h = sph::pair_h(particle1, particle2);
+                let volume = particle2.mass / particle2.density;
+                let w = kernel.value(math::length(rx, ry), h);
+                let (gx, gy) = correction.gradient(kernel.gradient(rx, ry, h), w);
+                a[0] -= volume * gx * rx;
+                a[1] -= volume * gx * ry;
+                a[2] -= volume * gy * rx;
+                a[3] -= volume * gy * ry;
+            };
+            let particle2 = &particles[j as usize];
+            add(particle2);
+            walls.for_each_image(particle2, add);
+        });
+        let determinant = a[0] * a[3] - a[1] * a[2];
+        if determinant.abs() >= MIN_DETERMINANT {
+            correction.matrix = [
+                a[3] / determinant,
+                -a[1] / determinant,
+                -a[2] / determinant,
+                a[0] / determinant,
+            ];
+        }
+        correction
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::kernels::Kernel;
+    use crate::neighbours::BruteForce;
+
+    /// Square lattice filling x >= 0, so the particles at x = 0 sit on a
+    /// boundary with no neighbours to their left
+    fn half_plane(dx: f64, parameters: &Parameters) -> Vec<Particle> {
+        let mut particles = Vec::new();
+        for i in 0..12 {
+            for j in -6..6 {
+                let mut particle = parameters.particle(i as f64 * dx, j as f64 * dx);
+                particle.density = parameters.mass / (dx * dx);
+                particles.push(particle);
+            }
+        }
+        particles
+    }
+
+    /// Gradient of f(x, y) = 2x - 3y + 1 at the boundary particle at the origin
+    fn linear_gradient(correction: GradientCorrection, difference: bool) -> (f64, f64) {
+        let dx = 0.1;
+        let parameters = Parameters {
+            h: 1.3 * dx,
+            mass: 1.0,
+            gradient_correction: correction,
+            parallel: false,
+            ..Parameters::new()
+        };
+        let particles = half_plane(dx, &parameters);
+        let search = BruteForce::new(&particles, parameters.support_radius());
+        let corrections = compute_corrections(&particles, &search, &parameters);
+        let f = |particle: &Particle| 2.0 * particle.x - 3.0 * particle.y + 1.0;
+        let i = particles
+            .iter()
+            .position(|particle| particle.x == 0.0 && particle.y == 0.0)
+            .unwrap();
+        let (kernel, h) = (parameters.kernel, parameters.h);
+        let particle1 = &particles[i];
+        let (mut fx, mut fy) = (0.0, 0.0);
+        for particle2 in particles.iter() {
+            let (rx, ry) = (particle1.x - particle2.x, particle1.y - particle2.y);
+            let volume = parameters.mass / particle2.density;
+            let w = kernel.value(math::length(rx, ry), h);
+            let (gx, gy) = corrections[i].gradient(kernel.gradient(rx, ry, h), w);
+            let value = if difference {
+                f(particle2) - f(particle1)
+            } else {
+                f(particle2)
+            };
+            fx += volume * value * gx;
+            fy += volume * value * gy;
+        }
+        (fx, fy)
+    }
+
+    #[test]
+    fn test_linear_gradient_at_boundary() {
+        let (gx, _) = linear_gradient(GradientCorrection::None, true);
+        assert!((gx - 2.0).abs() > 0.1);
+
+        let (gx, gy) = linear_gradient(GradientCorrection::RandlesLibersky, true);
+        assert!((gx - 2.0).abs() < 1e-9);
+        assert!((gy + 3.0).abs() < 1e-9);
+
+        let (gx, gy) = linear_gradient(GradientCorrection::BonetLok, false);
+        assert!((gx - 2.0).abs() < 1e-9);
+        assert!((gy + 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_isolated_particle_is_not_corrected() {
+        let parameters = Parameters {
+            gradient_correction: GradientCorrection::RandlesLibersky,
+            ..Parameters::new()
+        };
+        let particles = vec![parameters.particle(1.0, 1.0)];
+        let search = BruteForce::new(&particles, parameters.support_radius());
+        let corrections = compute_corrections(&particles, &search, &parameters);
+        assert_eq!(corrections[0].matrix, Correction::new().matrix);
+    }
+}
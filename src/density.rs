@@ -0,0 +1,288 @@
+use crate::correction::{self, GradientCorrection};
+use crate::neighbours::ParticleNeighbours;
+use crate::parallel;
+use crate::sph::{self, Parameters, Particle, GAS_CONST};
+use crate::walls;
+
+use std::str::FromStr;
+
+/// How the density of each particle is obtained
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DensityMethod {
+    /// Recomputed every evaluation as sum_j m_j W_ij
+    Summation,
+    /// Integrated in time from the continuity equation
+    /// d rho_i / dt = sum_j m_j (v_i - v_j) . grad W_ij, which does not lose
+    /// density where the neighbourhood is incomplete
+    Continuity,
+}
+
+impl FromStr for DensityMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "summation" => Ok(DensityMethod::Summation),
+            "continuity" => Ok(DensityMethod::Continuity),
+            _ => Err(format!("Unknown density method: {}", s)),
+        }
+    }
+}
+
+/// delta-SPH diffusive term added to the continuity equation,
+/// delta h c_0 sum_j V_j psi_ij . grad W_ij, which damps the high frequency
+/// density and pressure noise of the integrated density
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DensityDiffusion {
+    None,
+    /// Molteni and Colagrossi, psi_ij = 2 (rho_j - rho_i) r_ji / |r_ji|^2.
+    /// Also diffuses the hydrostatic density gradient.
+    MolteniColagrossi,
+    /// Antuono et al., which subtracts the renormalised density gradients of
+    /// both particles from psi_ij so that linear density fields are kept
+    Antuono,
+}
+
+impl FromStr for DensityDiffusion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(DensityDiffusion::None),
+            "molteni" => Ok(DensityDiffusion::MolteniColagrossi),
+            "antuono" => Ok(DensityDiffusion::Antuono),
+            _ => Err(format!("Unknown density diffusion: {}", s)),
+        }
+    }
+}
+
+/// Right hand side of the continuity equation for every particle, including
+/// any density diffusion, and the number of neighbours visited
+pub fn continuity_rates<N: ParticleNeighbours>(
+    particles: &[Particle],
+    neighbours: &N,
+    parameters: &Parameters,
+) -> Vec<(f64, usize)> {
+    let kernel = parameters.kernel_evaluator();
+    let diffusion = parameters.density_diffusion;
+    let gradients = match diffusion {
+        DensityDiffusion::Antuono => renormalised_gradients(particles, neighbours, parameters),
+        _ => Vec::new(),
+    };
+    // p = GAS_CONST (rho - rho_0), so c_0^2 = GAS_CONST
+    let strength = parameters.diffusion_coefficient * GAS_CONST.sqrt();
+    parallel::map(particles.len(), parameters.parallel, |i| {
+        let particle1 = &particles[i];
+        let walls = walls::Walls::near(particle1, parameters);
+        let mut rate = 0.0;
+        let mut n_neighbours = 0;
+        if particle1.asleep {
+            return (rate, n_neighbours);
+        }
+        neighbours.for_each_neighbour_of(particles, i, |j| {
+            n_neighbours += 1;
+            let particle2 = &particles[j as usize];
+            let (h, m) = (sph::pair_h(particle1, particle2), particle2.mass);
+            let rx = particle1.x - particle2.x;
+            let ry = particle1.y - particle2.y;
+            let (grad_x, grad_y) = kernel.gradient(rx, ry, h);
+            rate += m
+                * ((particle1.vx - particle2.vx) * grad_x + (particle1.vy - particle2.vy) * grad_y);
+            // The images add their velocity divergence, not their diffusion
+            walls.for_each_image(particle2, |image| {
+                let (grad_x, grad_y) =
+                    kernel.gradient(particle1.x - image.x, particle1.y - image.y, h);
+                rate +=
+                    m * ((particle1.vx - image.vx) * grad_x + (particle1.vy - image.vy) * grad_y);
+            });
+            let r2 = rx.powi(2) + ry.powi(2);
+            if diffusion == DensityDiffusion::None || r2 == 0.0 {
+                return;
+            }
+            let difference = 2.0 * (particle2.density - particle1.density) / r2;
+            let (mut psi_x, mut psi_y) = (-difference * rx, -difference * ry);
+            if diffusion == DensityDiffusion::Antuono {
+                let (gradient1, gradient2) = (gradients[i], gradients[j as usize]);
+                psi_x -= gradient1.0 + gradient2.0;
+                psi_y -= gradient1.1 + gradient2.1;
+            }
+            rate += strength * h * m / particle2.density * (psi_x * grad_x + psi_y * grad_y);
+        });
+        (rate, n_neighbours)
+    })
+}
+
+/// Density gradients sum_j V_j (rho_j - rho_i) L_i grad W_ij, exact for
+/// linear fields
+fn renormalised_gradients<N: ParticleNeighbours>(
+    particles: &[Particle],
+    neighbours: &N,
+    parameters: &Parameters,
+) -> Vec<(f64, f64)> {
+    let kernel = parameters.kernel_evaluator();
+    let corrections = correction::compute(
+        particles,
+        neighbours,
+        parameters,
+        GradientCorrection::RandlesLibersky,
+    );
+    parallel::map(particles.len(), parameters.parallel, |i| {
+        let particle1 = &particles[i];
+        let walls = walls::Walls::near(particle1, parameters);
+        let (mut gradient_x, mut gradient_y) = (0.0, 0.0);
+        neighbours.for_each_neighbour_of(particles, i, |j| {
+            // With the images the corrections were computed with
+            let mut add = |particle2: &Particle| {
+                let h = sph::pair_h(particle1, particle2);
+                let (grad_x, grad_y) = corrections[i].gradient(
+                    kernel.gradient(particle1.x - particle2.x, particle1.y - particle2.y, h),
+                    0.0,
+                );
+                let weight =
+                    particle2.mass / particle2.density * (particle2.density - particle1.density);
+                gradient_x += weight * grad_x;
+                gradient_y += weight * grad_y;
+            };
+            let particle2 = &particles[j as usize];
+            add(particle2);
+            walls.for_each_image(particle2, add);
+        });
+        (gradient_x, gradient_y)
+    })
+}
+
+/// Replace each density by its Shepard filtered value
+/// sum_j m_j W_ij / sum_j (m_j / rho_j) W_ij, which reproduces a constant field
+/// exactly and so removes the noise that integrating the continuity equation
+/// accumulates without biasing the free surface
+pub fn shepard_filter<N: ParticleNeighbours>(
+    particles: &mut [Particle],
+    neighbours: &N,
+    parameters: &Parameters,
+) {
+    let kernel = parameters.kernel_evaluator();
+    let particles_ref = &*particles;
+    let densities = parallel::map(particles.len(), parameters.parallel, |i| {
+        let particle1 = &particles_ref[i];
+        let walls = walls::Walls::near(particle1, parameters);
+        let (mut density, mut shepard) = (0.0, 0.0);
+        neighbours.for_each_neighbour_of(particles_ref, i, |j| {
+            let mut add = |particle2: &Particle| {
+                let r2 = (particle1.x - particle2.x).powi(2) + (particle1.y - particle2.y).powi(2);
+                let w = kernel.value_r2(r2, sph::pair_h(particle1, particle2));
+                density += particle2.mass * w;
+                shepard += particle2.mass / particle2.density * w;
+            };
+            let particle2 = &particles_ref[j as usize];
+            add(particle2);
+            walls.for_each_image(particle2, add);
+        });
+        density / shepard
+    });
+    for (particle, density) in particles.iter_mut().zip(densities) {
+        particle.density = density;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fixtures::{lattice, parameters};
+    use crate::neighbours::BruteForce;
+
+    #[test]
+    fn test_rate_of_linear_compression() {
+        // div v = -3, so d rho / dt = 3 rho away from the edges, with rho the
+        // summed density the lattice is consistent with
+        let parameters = parameters();
+        let particles = lattice(0..15, &parameters, |x, y| (-x, -2.0 * y));
+        let search = BruteForce::new(&particles, parameters.support_radius());
+        let rates = continuity_rates(&particles, &search, &parameters);
+        let centre = &particles[7 * 15 + 7];
+        let kernel = parameters.kernel_evaluator();
+        let density: f64 = particles
+            .iter()
+            .map(|particle| {
+                let r = ((centre.x - particle.x).powi(2) + (centre.y - particle.y).powi(2)).sqrt();
+                parameters.mass * kernel.value(r, parameters.h)
+            })
+            .sum();
+        let rate = rates[7 * 15 + 7].0;
+        assert!(
+            (rate - 3.0 * density).abs() < 1e-2 * rate,
+            "{} {}",
+            rate,
+            3.0 * density
+        );
+
+        let translating = lattice(0..15, &parameters, |_, _| (1.0, -0.5));
+        let rates = continuity_rates(&translating, &search, &parameters);
+        assert!(rates.iter().all(|&(rate, _)| rate == 0.0));
+    }
+
+    #[test]
+    fn test_rates_follow_the_particle_mass() {
+        // Particles split or placed at their own spacing keep their mass and
+        // smoothing length whatever the parameters say
+        let parameters = parameters();
+        let particles = lattice(0..15, &parameters, |x, y| (-x, -2.0 * y));
+        let search = BruteForce::new(&particles, parameters.support_radius());
+        let coarse = Parameters {
+            mass: 4.0 * parameters.mass,
+            h: 2.0 * parameters.h,
+            density_diffusion: DensityDiffusion::MolteniColagrossi,
+            ..parameters.clone()
+        };
+        let diffusive = Parameters {
+            density_diffusion: DensityDiffusion::MolteniColagrossi,
+            ..parameters
+        };
+        assert_eq!(
+            continuity_rates(&particles, &search, &coarse),
+            continuity_rates(&particles, &search, &diffusive)
+        );
+    }
+
+    /// Rates of a lattice at rest whose density varies linearly in y, as in a
+    /// hydrostatic column
+    fn diffusion_rates(diffusion: DensityDiffusion) -> Vec<f64> {
+        let parameters = Parameters {
+            density_diffusion: diffusion,
+            ..parameters()
+        };
+        let mut particles = lattice(0..15, &parameters, |_, _| (0.0, 0.0));
+        for particle in particles.iter_mut() {
+            particle.density *= 1.0 + 0.1 * particle.y;
+        }
+        let search = BruteForce::new(&particles, parameters.support_radius());
+        continuity_rates(&particles, &search, &parameters)
+            .into_iter()
+            .map(|(rate, _)| rate)
+            .collect()
+    }
+
+    #[test]
+    fn test_diffusion_of_linear_density() {
+        let scale = 0.1 * 0.2 * GAS_CONST.sqrt() * parameters().mass / 0.01;
+        let none = diffusion_rates(DensityDiffusion::None);
+        assert!(none.iter().all(|&rate| rate == 0.0));
+        // Molteni-Colagrossi diffuses the gradient at the edges of the lattice
+        let molteni = diffusion_rates(DensityDiffusion::MolteniColagrossi);
+        assert!(molteni.iter().any(|rate| rate.abs() > 0.1 * scale));
+        let antuono = diffusion_rates(DensityDiffusion::Antuono);
+        assert!(antuono.iter().all(|rate| rate.abs() < 1e-9 * scale));
+    }
+
+    #[test]
+    fn test_shepard_filter_keeps_constant_density() {
+        let parameters = parameters();
+        let mut particles = lattice(0..10, &parameters, |_, _| (0.0, 0.0));
+        let rest_density = particles[0].density;
+        let search = BruteForce::new(&particles, parameters.support_radius());
+        shepard_filter(&mut particles, &search, &parameters);
+        for particle in particles.iter() {
+            assert!((particle.density - rest_density).abs() < 1e-9 * rest_density);
+        }
+    }
+}
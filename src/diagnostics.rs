@@ -0,0 +1,124 @@
+use crate::sph::{self, Duck, Parameters, Particle};
+
+/// Conserved (or nearly conserved) quantities of the whole system
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Diagnostics {
+    pub kinetic_energy: f64,
+    pub potential_energy: f64,
+    pub internal_energy: f64,
+    pub duck_energy: f64,
+    pub momentum_x: f64,
+    pub momentum_y: f64,
+    pub angular_momentum: f64,
+    pub total_mass: f64,
+}
+
+impl Diagnostics {
+    pub fn new() -> Diagnostics {
+        Diagnostics {
+            kinetic_energy: 0.0,
+            potential_energy: 0.0,
+            internal_energy: 0.0,
+            duck_energy: 0.0,
+            momentum_x: 0.0,
+            momentum_y: 0.0,
+            angular_momentum: 0.0,
+            total_mass: 0.0,
+        }
+    }
+
+    pub fn total_energy(&self) -> f64 {
+        self.kinetic_energy + self.potential_energy + self.internal_energy + self.duck_energy
+    }
+
+    /// Difference of every quantity relative to `initial`
+    pub fn drift(&self, initial: &Diagnostics) -> Diagnostics {
+        Diagnostics {
+            kinetic_energy: self.kinetic_energy - initial.kinetic_energy,
+            potential_energy: self.potential_energy - initial.potential_energy,
+            internal_energy: self.internal_energy - initial.internal_energy,
+            duck_energy: self.duck_energy - initial.duck_energy,
+            momentum_x: self.momentum_x - initial.momentum_x,
+            momentum_y: self.momentum_y - initial.momentum_y,
+            angular_momentum: self.angular_momentum - initial.angular_momentum,
+            total_mass: self.total_mass - initial.total_mass,
+        }
+    }
+
+    /// Total energy drift as a fraction of the initial total energy
+    pub fn relative_energy_drift(&self, initial: &Diagnostics) -> f64 {
+        let initial_energy = initial.total_energy();
+        if initial_energy == 0.0 {
+            return 0.0;
+        }
+        (self.total_energy() - initial_energy) / initial_energy.abs()
+    }
+}
+
+/// Specific (per unit mass) elastic energy stored by the linear equation of
+/// state p = k * (rho - rho0), i.e. the integral of p / rho^2 from rho0 to rho.
+pub fn internal_energy_per_mass(density: f64, gas_const: f64, rest_density: f64) -> f64 {
+    gas_const * ((density / rest_density).ln() + rest_density / density - 1.0)
+}
+
+/// Potential energy is measured from the bottom of the domain since gravity
+/// points along positive y.
+pub fn measure(particles: &[Particle], duck: &Duck, parameters: &Parameters) -> Diagnostics {
+    let mut diagnostics = Diagnostics::new();
+    let (gravity, floor) = (parameters.gravity, parameters.domain.max_y);
+    for particle in particles {
+        let m = particle.mass;
+        diagnostics.total_mass += m;
+        diagnostics.kinetic_energy += 0.5 * m * (particle.vx.powi(2) + particle.vy.powi(2));
+        diagnostics.potential_energy += m * gravity * (floor - particle.y);
+        diagnostics.internal_energy +=
+            m * internal_energy_per_mass(particle.density, sph::GAS_CONST, parameters.rest_density);
+        diagnostics.momentum_x += m * particle.vx;
+        diagnostics.momentum_y += m * particle.vy;
+        diagnostics.angular_momentum += m * (particle.x * particle.vy - particle.y * particle.vx);
+    }
+    diagnostics.duck_energy = 0.5 * sph::DUCK_MASS * (duck.vx.powi(2) + duck.vy.powi(2))
+        + sph::DUCK_MASS * gravity * (floor - duck.y);
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_internal_energy_at_rest_density() {
+        assert!(internal_energy_per_mass(2.0, 1000.0, 2.0).abs() < 1e-12);
+        assert!(internal_energy_per_mass(2.5, 1000.0, 2.0) > 0.0);
+        assert!(internal_energy_per_mass(1.5, 1000.0, 2.0) > 0.0);
+    }
+
+    #[test]
+    fn test_measure_moving_particle() {
+        let parameters = Parameters::new();
+        let m = parameters.mass;
+        let mut particle = parameters.particle(1.0, 2.0);
+        particle.vx = 3.0;
+        particle.density = parameters.rest_density;
+        let diagnostics = measure(&[particle], &Duck::new(), &parameters);
+        let tolerance = 1e-9;
+        assert!((diagnostics.total_mass - m).abs() < tolerance);
+        assert!((diagnostics.kinetic_energy - 0.5 * m * 9.0).abs() < tolerance);
+        assert!((diagnostics.momentum_x - 3.0 * m).abs() < tolerance);
+        assert!((diagnostics.angular_momentum - (-2.0 * 3.0 * m)).abs() < tolerance);
+        assert!(diagnostics.internal_energy.abs() < tolerance);
+    }
+
+    #[test]
+    fn test_drift() {
+        let mut initial = Diagnostics::new();
+        initial.kinetic_energy = 2.0;
+        initial.potential_energy = 2.0;
+        let mut current = initial;
+        current.kinetic_energy = 3.0;
+        let drift = current.drift(&initial);
+        assert_eq!(drift.kinetic_energy, 1.0);
+        assert_eq!(drift.potential_energy, 0.0);
+        assert_eq!(current.relative_energy_drift(&initial), 0.25);
+    }
+}
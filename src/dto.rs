@@ -15,6 +15,12 @@ struct ParticleDto {
     velocity: VectorN<f64, U3>,
     density: f64,
     pressure: f64,
+    normal: VectorN<f64, U3>,
+    surface: bool,
+    vorticity: f64,
+    eddy_viscosity: f64,
+    mass: f64,
+    h: f64,
 }
 
 impl From<Particle> for ParticleDto {
@@ -24,6 +30,12 @@ impl From<Particle> for ParticleDto {
             velocity: VectorN::<f64, U3>::new(particle.vx, particle.vy, 0.0),
             density: particle.density,
             pressure: particle.pressure,
+            normal: VectorN::<f64, U3>::new(particle.nx, particle.ny, 0.0),
+            surface: particle.surface,
+            vorticity: particle.vorticity,
+            eddy_viscosity: particle.eddy_viscosity,
+            mass: particle.mass,
+            h: particle.h,
         }
     }
 }
@@ -37,10 +49,18 @@ impl From<ParticleDto> for Particle {
             vy: particle.velocity.y,
             fx: 0.0,
             fy: 0.0,
-            ofx: 0.0,
-            ofy: 0.0,
+            mass: particle.mass,
+            h: particle.h,
             density: particle.density,
+            density_rate: 0.0,
             pressure: particle.pressure,
+            surface: particle.surface,
+            nx: particle.normal.x,
+            ny: particle.normal.y,
+            vorticity: particle.vorticity,
+            eddy_viscosity: particle.eddy_viscosity,
+            asleep: false,
+            calm_steps: 0,
         }
     }
 }
@@ -59,14 +79,21 @@ pub fn write_to_io(
 ) -> std::io::Result<()> {
     let length = particles.len();
 
-    buffer.write(&(length as u64).to_le_bytes())?;
+    buffer.write_all(&(length as u64).to_le_bytes())?;
     for particle in particles {
         let position = VectorN::<f64, U3>::new(particle.x, particle.y, 0.0);
         let velocity = VectorN::<f64, U3>::new(particle.vx, particle.vy, 0.0);
-        buffer.write(&to_le_bytes(&position))?;
-        buffer.write(&to_le_bytes(&velocity))?;
-        buffer.write(&particle.density.to_le_bytes())?;
-        buffer.write(&particle.pressure.to_le_bytes())?;
+        let normal = VectorN::<f64, U3>::new(particle.nx, particle.ny, 0.0);
+        buffer.write_all(&to_le_bytes(&position))?;
+        buffer.write_all(&to_le_bytes(&velocity))?;
+        buffer.write_all(&particle.density.to_le_bytes())?;
+        buffer.write_all(&particle.pressure.to_le_bytes())?;
+        buffer.write_all(&to_le_bytes(&normal))?;
+        buffer.write_all(&(particle.surface as u64).to_le_bytes())?;
+        buffer.write_all(&particle.vorticity.to_le_bytes())?;
+        buffer.write_all(&particle.eddy_viscosity.to_le_bytes())?;
+        buffer.write_all(&particle.mass.to_le_bytes())?;
+        buffer.write_all(&particle.h.to_le_bytes())?;
     }
     buffer.flush()?;
 
@@ -81,12 +108,18 @@ fn future_write_to_io(
 ) -> std::io::Result<()> {
     let length = particles.len();
 
-    buffer.write(&(length as u64).to_le_bytes())?;
+    buffer.write_all(&(length as u64).to_le_bytes())?;
     for particle in particles {
-        buffer.write(&to_le_bytes(&particle.position))?;
-        buffer.write(&to_le_bytes(&particle.velocity))?;
-        buffer.write(&particle.density.to_le_bytes())?;
-        buffer.write(&particle.pressure.to_le_bytes())?;
+        buffer.write_all(&to_le_bytes(&particle.position))?;
+        buffer.write_all(&to_le_bytes(&particle.velocity))?;
+        buffer.write_all(&particle.density.to_le_bytes())?;
+        buffer.write_all(&particle.pressure.to_le_bytes())?;
+        buffer.write_all(&to_le_bytes(&particle.normal))?;
+        buffer.write_all(&(particle.surface as u64).to_le_bytes())?;
+        buffer.write_all(&particle.vorticity.to_le_bytes())?;
+        buffer.write_all(&particle.eddy_viscosity.to_le_bytes())?;
+        buffer.write_all(&particle.mass.to_le_bytes())?;
+        buffer.write_all(&particle.h.to_le_bytes())?;
     }
     buffer.flush()?;
 
@@ -98,6 +131,12 @@ fn take_particle(input: &[u8]) -> IResult<&[u8], ParticleDto> {
     let (input, velocity) = le_vector3(input)?;
     let (input, density) = le_f64(input)?;
     let (input, pressure) = le_f64(input)?;
+    let (input, normal) = le_vector3(input)?;
+    let (input, surface) = le_u64(input)?;
+    let (input, vorticity) = le_f64(input)?;
+    let (input, eddy_viscosity) = le_f64(input)?;
+    let (input, mass) = le_f64(input)?;
+    let (input, h) = le_f64(input)?;
 
     Ok((
         input,
@@ -106,6 +145,12 @@ fn take_particle(input: &[u8]) -> IResult<&[u8], ParticleDto> {
             velocity,
             density,
             pressure,
+            normal,
+            surface: surface != 0,
+            vorticity,
+            eddy_viscosity,
+            mass,
+            h,
         },
     ))
 }
@@ -140,12 +185,12 @@ mod test {
 
     #[test]
     fn map() {
-        let initial = Particle::new(1.0, 0.0);
+        let initial = sph::Parameters::new().particle(1.0, 0.0);
 
-        let converted: ParticleDto = initial.into();
+        let converted: ParticleDto = initial.clone().into();
         let result: Particle = converted.into();
 
-        assert_eq!(result, Particle::new(1.0, 0.0));
+        assert_eq!(result, initial);
     }
 
     #[test]
@@ -155,11 +200,17 @@ mod test {
             velocity: na::VectorN::<f64, U3>::new(1.0, 0.0, 0.0),
             density: 0.0,
             pressure: 0.0,
+            normal: na::VectorN::<f64, U3>::new(-1.0, 0.0, 0.0),
+            surface: true,
+            vorticity: -2.0,
+            eddy_viscosity: 1e-3,
+            mass: 1.0,
+            h: 0.5,
         }];
         let expected = (particles.len() as u64).to_le_bytes();
 
         let mut result = Vec::<u8>::new();
-        write_to_io_internal(&particles, &mut result).unwrap();
+        future_write_to_io(&particles, &mut result).unwrap();
 
         let u64_size = std::mem::size_of::<u64>();
         assert_eq!(result[..u64_size], expected);
@@ -172,10 +223,16 @@ mod test {
             velocity: na::VectorN::<f64, U3>::new(1.0, 0.0, 0.0),
             density: 0.0,
             pressure: 0.0,
+            normal: na::VectorN::<f64, U3>::new(-1.0, 0.0, 0.0),
+            surface: true,
+            vorticity: -2.0,
+            eddy_viscosity: 1e-3,
+            mass: 1.0,
+            h: 0.5,
         }];
 
         let mut data = Vec::<u8>::new();
-        write_to_io_internal(&particles, &mut data).unwrap();
+        future_write_to_io(&particles, &mut data).unwrap();
 
         let (_, result) = take_particles(&data).unwrap();
 
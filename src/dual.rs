@@ -0,0 +1,212 @@
+//! Forward-mode automatic differentiation to second order, used to check
+//! hand derived kernel derivatives
+
+// Only the tests differentiate so far
+#![allow(dead_code)]
+
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+/// Number type the kernel profiles are written against, so that they can be
+/// evaluated both on `f64` and on `Dual`
+pub trait Scalar:
+    Copy
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Neg<Output = Self>
+    + Add<f64, Output = Self>
+    + Sub<f64, Output = Self>
+    + Mul<f64, Output = Self>
+    + Div<f64, Output = Self>
+{
+    fn constant(value: f64) -> Self;
+    fn value(self) -> f64;
+    fn powi(self, n: i32) -> Self;
+    fn sqrt(self) -> Self;
+}
+
+impl Scalar for f64 {
+    fn constant(value: f64) -> f64 {
+        value
+    }
+
+    fn value(self) -> f64 {
+        self
+    }
+
+    fn powi(self, n: i32) -> f64 {
+        f64::powi(self, n)
+    }
+
+    fn sqrt(self) -> f64 {
+        f64::sqrt(self)
+    }
+}
+
+/// Truncated Taylor expansion f + f' e + f'' e^2 / 2 along one variable
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Dual {
+    pub value: f64,
+    pub first: f64,
+    pub second: f64,
+}
+
+impl Dual {
+    /// The variable being differentiated by, at `value`
+    pub fn variable(value: f64) -> Dual {
+        Dual {
+            value,
+            first: 1.0,
+            second: 0.0,
+        }
+    }
+
+    /// Apply f with f(v), f'(v) and f''(v) by the chain rule
+    fn chain(self, f: f64, df: f64, d2f: f64) -> Dual {
+        Dual {
+            value: f,
+            first: df * self.first,
+            second: d2f * self.first * self.first + df * self.second,
+        }
+    }
+}
+
+impl Scalar for Dual {
+    fn constant(value: f64) -> Dual {
+        Dual {
+            value,
+            first: 0.0,
+            second: 0.0,
+        }
+    }
+
+    fn value(self) -> f64 {
+        self.value
+    }
+
+    fn powi(self, n: i32) -> Dual {
+        let v = self.value;
+        let n_f = n as f64;
+        self.chain(
+            v.powi(n),
+            n_f * v.powi(n - 1),
+            n_f * (n_f - 1.0) * v.powi(n - 2),
+        )
+    }
+
+    fn sqrt(self) -> Dual {
+        let s = self.value.sqrt();
+        self.chain(s, 0.5 / s, -0.25 / (s * s * s))
+    }
+}
+
+impl Add for Dual {
+    type Output = Dual;
+
+    fn add(self, other: Dual) -> Dual {
+        Dual {
+            value: self.value + other.value,
+            first: self.first + other.first,
+            second: self.second + other.second,
+        }
+    }
+}
+
+impl Sub for Dual {
+    type Output = Dual;
+
+    fn sub(self, other: Dual) -> Dual {
+        self + -other
+    }
+}
+
+impl Mul for Dual {
+    type Output = Dual;
+
+    fn mul(self, other: Dual) -> Dual {
+        Dual {
+            value: self.value * other.value,
+            first: self.first * other.value + self.value * other.first,
+            second: self.second * other.value
+                + 2.0 * self.first * other.first
+                + self.value * other.second,
+        }
+    }
+}
+
+impl Div for Dual {
+    type Output = Dual;
+
+    fn div(self, other: Dual) -> Dual {
+        let v = other.value;
+        self * other.chain(1.0 / v, -1.0 / (v * v), 2.0 / (v * v * v))
+    }
+}
+
+impl Neg for Dual {
+    type Output = Dual;
+
+    fn neg(self) -> Dual {
+        Dual {
+            value: -self.value,
+            first: -self.first,
+            second: -self.second,
+        }
+    }
+}
+
+impl Add<f64> for Dual {
+    type Output = Dual;
+
+    fn add(self, other: f64) -> Dual {
+        self + Dual::constant(other)
+    }
+}
+
+impl Sub<f64> for Dual {
+    type Output = Dual;
+
+    fn sub(self, other: f64) -> Dual {
+        self - Dual::constant(other)
+    }
+}
+
+impl Mul<f64> for Dual {
+    type Output = Dual;
+
+    fn mul(self, other: f64) -> Dual {
+        Dual {
+            value: self.value * other,
+            first: self.first * other,
+            second: self.second * other,
+        }
+    }
+}
+
+impl Div<f64> for Dual {
+    type Output = Dual;
+
+    fn div(self, other: f64) -> Dual {
+        self * (1.0 / other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_derivatives_of_expression() {
+        // f(x) = sqrt(x^2 + 1) / (x - 3)^3 at x = 2
+        let x = Dual::variable(2.0);
+        let f = (x * x + 1.0).sqrt() / (x - 3.0).powi(3);
+        let g = |x: f64| (x * x + 1.0).sqrt() / (x - 3.0).powi(3);
+        let e = 1e-4;
+        let first = (g(2.0 + e) - g(2.0 - e)) / (2.0 * e);
+        let second = (g(2.0 + e) - 2.0 * g(2.0) + g(2.0 - e)) / (e * e);
+        assert_eq!(f.value, g(2.0));
+        assert!((f.first - first).abs() < 1e-6);
+        assert!((f.second - second).abs() < 1e-5);
+    }
+}
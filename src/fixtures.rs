@@ -0,0 +1,40 @@
+//! Particle arrangements shared by the unit tests
+
+use crate::sph::{Parameters, Particle};
+
+/// Distance between neighbours in `lattice`
+pub const SPACING: f64 = 0.1;
+
+/// Unit mass particles whose rest spacing is that of the lattice, with h
+/// twice the spacing, on one thread
+pub fn parameters() -> Parameters {
+    Parameters {
+        h: 2.0 * SPACING,
+        mass: 1.0,
+        rest_density: 1.0 / SPACING.powi(2),
+        parallel: false,
+        ..Parameters::new()
+    }
+}
+
+/// Square lattice with a particle at (i, j) * `SPACING` for every i and j of
+/// `indices`, at the density the lattice has for the particle mass, moving
+/// with velocity field `velocity`
+pub fn lattice(
+    indices: impl Iterator<Item = i32> + Clone,
+    parameters: &Parameters,
+    velocity: impl Fn(f64, f64) -> (f64, f64),
+) -> Vec<Particle> {
+    let mut particles = Vec::new();
+    for i in indices.clone() {
+        for j in indices.clone() {
+            let mut particle = parameters.particle(i as f64 * SPACING, j as f64 * SPACING);
+            let (vx, vy) = velocity(particle.x, particle.y);
+            particle.vx = vx;
+            particle.vy = vy;
+            particle.density = parameters.mass / SPACING.powi(2);
+            particles.push(particle);
+        }
+    }
+    particles
+}
@@ -1,85 +1,336 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
 #[derive(Clone)]
 pub struct Cell {
     particles: Vec<u32>,
 }
 
+/// How the particles of each cell are stored
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GridBackend {
+    /// A separate vector per cell
+    Cells,
+    /// Counting sort into one offsets array and one sorted index array
+    Compact,
+    /// Hash map from signed cell coordinates, unbounded
+    Hash,
+}
+
+impl FromStr for GridBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cells" => Ok(GridBackend::Cells),
+            "compact" => Ok(GridBackend::Compact),
+            "hash" => Ok(GridBackend::Hash),
+            _ => Err(format!("Unknown grid backend: {}", s)),
+        }
+    }
+}
+
+/// Space filling order used to sort particles for cache locality
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ordering {
+    /// Row by row through the cells
+    Cell,
+    /// Morton (Z-order) curve through the cells
+    Morton,
+}
+
+impl FromStr for Ordering {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cell" => Ok(Ordering::Cell),
+            "morton" => Ok(Ordering::Morton),
+            _ => Err(format!("Unknown ordering: {}", s)),
+        }
+    }
+}
+
+enum Storage {
+    Cells(Vec<Cell>),
+    /// Particles of cell c are `sorted[offsets[c]..offsets[c + 1]]`, valid
+    /// after `finish`. Until then insertions collect in `inserted`.
+    Compact {
+        inserted: Vec<(u32, u32)>,
+        offsets: Vec<u32>,
+        sorted: Vec<u32>,
+    },
+    Hash(HashMap<(i64, i64), Vec<u32>>),
+}
+
 pub struct Grid {
-    grid: Vec<Cell>,
+    storage: Storage,
     grid_width: u64,
     grid_height: u64,
     sx: f64,
     sy: f64,
-    h: f64,
+    ex: f64,
+    ey: f64,
+    cell_size: f64,
+    /// Particles inserted outside [sx, ex] x [sy, ey]
+    escaped: Vec<u32>,
 }
 
-fn world_to_grid(h: f64, sx: f64, sy: f64, x: f64, y: f64) -> (u64, u64) {
-    let gx = ((x - sx) / (2.0 * h)).floor() as u64;
-    let gy = ((y - sy) / (2.0 * h)).floor() as u64;
+/// Signed cell coordinates, so positions below (sx, sy) stay meaningful
+fn world_to_grid(cell_size: f64, sx: f64, sy: f64, x: f64, y: f64) -> (i64, i64) {
+    let gx = ((x - sx) / cell_size).floor() as i64;
+    let gy = ((y - sy) / cell_size).floor() as i64;
     (gx, gy)
 }
 
-pub fn create_grid(h: f64, sx: f64, ex: f64, sy: f64, ey: f64) -> Grid {
-    let (last_gx, last_gy) = world_to_grid(h, sx, sy, ex, ey);
-    let (grid_width, grid_height) = (last_gx + 1, last_gy + 1);
-    Grid {
-        grid: vec![
+/// The 3x3 block of cells around a cell, in the order they are visited
+const STENCIL: [(i64, i64); 9] = [
+    (0, 0),
+    (1, 0),
+    (0, 1),
+    (1, 1),
+    (-1, 0),
+    (0, -1),
+    (-1, -1),
+    (1, -1),
+    (-1, 1),
+];
+
+/// The cells after a cell in row order within its 3x3 block, so that every
+/// pair of neighbouring cells is visited from one side only
+const HALF_STENCIL: [(i64, i64); 4] = [(1, 0), (-1, 1), (0, 1), (1, 1)];
+
+/// Interleave the bits of the cell coordinates
+fn morton_key(gx: u64, gy: u64) -> u64 {
+    fn spread(v: u64) -> u64 {
+        let mut v = v & 0xffff_ffff;
+        v = (v | (v << 16)) & 0x0000_ffff_0000_ffff;
+        v = (v | (v << 8)) & 0x00ff_00ff_00ff_00ff;
+        v = (v | (v << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+        v = (v | (v << 2)) & 0x3333_3333_3333_3333;
+        v = (v | (v << 1)) & 0x5555_5555_5555_5555;
+        v
+    }
+    spread(gx) | (spread(gy) << 1)
+}
+
+/// Square cells of side `cell_size`, which must be at least the interaction
+/// radius for the 3x3 stencil to find every neighbour
+pub fn create_grid(cell_size: f64, sx: f64, ex: f64, sy: f64, ey: f64) -> Grid {
+    create_grid_with_backend(GridBackend::Cells, cell_size, sx, ex, sy, ey)
+}
+
+pub fn create_grid_with_backend(
+    backend: GridBackend,
+    cell_size: f64,
+    sx: f64,
+    ex: f64,
+    sy: f64,
+    ey: f64,
+) -> Grid {
+    let (last_gx, last_gy) = world_to_grid(cell_size, sx, sy, ex, ey);
+    let (grid_width, grid_height) = (last_gx as u64 + 1, last_gy as u64 + 1);
+    let storage = match backend {
+        GridBackend::Cells => Storage::Cells(vec![
             Cell {
                 particles: Vec::new()
             };
             (grid_width * grid_height) as usize
-        ],
+        ]),
+        GridBackend::Compact => Storage::Compact {
+            inserted: Vec::new(),
+            offsets: Vec::new(),
+            sorted: Vec::new(),
+        },
+        GridBackend::Hash => Storage::Hash(HashMap::new()),
+    };
+    Grid {
+        storage,
         grid_width,
         grid_height,
         sx,
         sy,
-        h: h,
+        ex,
+        ey,
+        cell_size,
+        escaped: Vec::new(),
     }
 }
 
 impl Grid {
-    fn grid_index(&self, (gx, gy): (u64, u64)) -> usize {
-        (gy * self.grid_width + gx) as usize
+    pub fn grid_width(&self) -> u64 {
+        self.grid_width
     }
 
-    fn grid_get(&self, gx: u64, gy: u64) -> &Cell {
-        &self.grid[self.grid_index((gx, gy))]
+    /// Particles that were added outside the domain. The bounded backends
+    /// store them in the nearest border cell, the hash backend where they are.
+    pub fn escaped(&self) -> &[u32] {
+        &self.escaped
     }
 
-    pub fn add_particle(&mut self, index: u32, x: f64, y: f64) {
-        let (gx, gy) = world_to_grid(self.h, self.sx, self.sy, x, y);
+    fn grid_index(&self, (gx, gy): (i64, i64)) -> usize {
+        (gy as u64 * self.grid_width + gx as u64) as usize
+    }
+
+    /// Cell containing (x, y), clamped to the domain unless the grid is unbounded
+    fn cell_of(&self, x: f64, y: f64) -> (i64, i64) {
+        let (gx, gy) = world_to_grid(self.cell_size, self.sx, self.sy, x, y);
+        match self.storage {
+            Storage::Hash(_) => (gx, gy),
+            _ => (
+                gx.max(0).min(self.grid_width as i64 - 1),
+                gy.max(0).min(self.grid_height as i64 - 1),
+            ),
+        }
+    }
+
+    /// Particles of the cell offset by (dx, dy) from (gx, gy). Cells past the
+    /// range of i64, where the hash backend keeps non-finite or huge
+    /// positions, have none.
+    fn cell_particles_at(&self, (gx, gy): (i64, i64), (dx, dy): (i64, i64)) -> &[u32] {
+        match (gx.checked_add(dx), gy.checked_add(dy)) {
+            (Some(gx), Some(gy)) => self.cell_particles(gx, gy),
+            _ => &[],
+        }
+    }
+
+    fn cell_particles(&self, gx: i64, gy: i64) -> &[u32] {
+        if let Storage::Hash(cells) = &self.storage {
+            return cells.get(&(gx, gy)).map_or(&[], |cell| &cell[..]);
+        }
+        if gx < 0 || gy < 0 || gx as u64 >= self.grid_width || gy as u64 >= self.grid_height {
+            return &[];
+        }
         let grid_index = self.grid_index((gx, gy));
-        self.grid[grid_index].particles.push(index);
+        match &self.storage {
+            Storage::Cells(cells) => &cells[grid_index].particles,
+            Storage::Compact {
+                offsets, sorted, ..
+            } => &sorted[offsets[grid_index] as usize..offsets[grid_index + 1] as usize],
+            Storage::Hash(_) => unreachable!(),
+        }
     }
 
-    pub fn get_neighbours(&self, x: f64, y: f64) -> Vec<u32> {
-        let mut neighbours = Vec::new();
-        let (gx, gy) = world_to_grid(self.h, self.sx, self.sy, x, y);
-        neighbours.extend(&self.grid_get(gx, gy).particles);
-        if gx + 1 < self.grid_width {
-            neighbours.extend(&self.grid_get(gx + 1, gy).particles);
+    pub fn add_particle(&mut self, index: u32, x: f64, y: f64) {
+        if !(self.sx <= x && x <= self.ex && self.sy <= y && y <= self.ey) {
+            self.escaped.push(index);
         }
-        if gy + 1 < self.grid_height {
-            neighbours.extend(&self.grid_get(gx, gy + 1).particles);
+        let (gx, gy) = self.cell_of(x, y);
+        if let Storage::Hash(cells) = &mut self.storage {
+            cells.entry((gx, gy)).or_default().push(index);
+            return;
         }
-        if gx + 1 < self.grid_width && gy + 1 < self.grid_height {
-            neighbours.extend(&self.grid_get(gx + 1, gy + 1).particles);
+        let grid_index = self.grid_index((gx, gy));
+        match &mut self.storage {
+            Storage::Cells(cells) => cells[grid_index].particles.push(index),
+            Storage::Compact { inserted, .. } => inserted.push((grid_index as u32, index)),
+            Storage::Hash(_) => unreachable!(),
         }
-        if gx > 0 {
-            neighbours.extend(&self.grid_get(gx - 1, gy).particles);
+    }
+
+    /// Must be called after the last `add_particle` and before any query.
+    /// The compact backend does its counting sort here, keeping the
+    /// insertion order within each cell.
+    pub fn finish(&mut self) {
+        let n_cells = (self.grid_width * self.grid_height) as usize;
+        if let Storage::Compact {
+            inserted,
+            offsets,
+            sorted,
+        } = &mut self.storage
+        {
+            offsets.clear();
+            offsets.resize(n_cells + 1, 0);
+            for &(cell, _) in inserted.iter() {
+                offsets[cell as usize + 1] += 1;
+            }
+            for cell in 0..n_cells {
+                offsets[cell + 1] += offsets[cell];
+            }
+            sorted.clear();
+            sorted.resize(inserted.len(), 0);
+            let mut next = offsets[..n_cells].to_vec();
+            for &(cell, index) in inserted.iter() {
+                sorted[next[cell as usize] as usize] = index;
+                next[cell as usize] += 1;
+            }
+            inserted.clear();
         }
-        if gy > 0 {
-            neighbours.extend(&self.grid_get(gx, gy - 1).particles);
+    }
+
+    /// Sort key of the cell containing (x, y) along `ordering`
+    pub fn order_key(&self, ordering: Ordering, x: f64, y: f64) -> u64 {
+        let (gx, gy) = world_to_grid(self.cell_size, self.sx, self.sy, x, y);
+        let gx = gx.max(0).min(self.grid_width as i64 - 1);
+        let gy = gy.max(0).min(self.grid_height as i64 - 1);
+        match ordering {
+            Ordering::Cell => self.grid_index((gx, gy)) as u64,
+            Ordering::Morton => morton_key(gx as u64, gy as u64),
         }
-        if gx > 0 && gy > 0 {
-            neighbours.extend(&self.grid_get(gx - 1, gy - 1).particles);
+    }
+
+    /// Call `f` with every particle in the 3x3 block of cells around (x, y)
+    /// without allocating.
+    pub fn for_each_neighbour(&self, x: f64, y: f64, mut f: impl FnMut(u32)) {
+        let cell = self.cell_of(x, y);
+        for &offset in STENCIL.iter() {
+            for &index in self.cell_particles_at(cell, offset) {
+                f(index);
+            }
         }
+    }
 
-        if gx + 1 < self.grid_width && gy > 0 {
-            neighbours.extend(&self.grid_get(gx + 1, gy - 1).particles);
+    /// Call `f` once with every unordered pair of distinct particles in the
+    /// same or neighbouring cells from block `block` of `blocks` runs of
+    /// rows, cell by cell in row order. The blocks between them visit every
+    /// pair once, and the pairs and blocks are the same, in the same order,
+    /// for every backend.
+    pub fn for_each_pair_in(&self, block: usize, blocks: usize, mut f: impl FnMut(u32, u32)) {
+        let height = self.grid_height as usize;
+        let (start, end) = (block * height / blocks, (block + 1) * height / blocks);
+        if let Storage::Hash(cells) = &self.storage {
+            // Rows outside the domain go with the first and last blocks
+            let mut keys: Vec<_> = cells
+                .keys()
+                .copied()
+                .filter(|&(_, gy)| {
+                    (block == 0 || gy >= start as i64) && (block + 1 == blocks || gy < end as i64)
+                })
+                .collect();
+            keys.sort_unstable_by_key(|&(gx, gy)| (gy, gx));
+            for (gx, gy) in keys {
+                self.for_each_pair_from(gx, gy, &mut f);
+            }
+            return;
         }
-        if gx > 0 && gy + 1 < self.grid_height {
-            neighbours.extend(&self.grid_get(gx - 1, gy + 1).particles);
+        for gy in start..end {
+            for gx in 0..self.grid_width as i64 {
+                self.for_each_pair_from(gx, gy as i64, &mut f);
+            }
         }
+    }
+
+    /// Pairs within cell (gx, gy) and between it and its half stencil
+    fn for_each_pair_from(&self, gx: i64, gy: i64, f: &mut impl FnMut(u32, u32)) {
+        let cell = self.cell_particles(gx, gy);
+        for (k, &index1) in cell.iter().enumerate() {
+            for &index2 in &cell[k + 1..] {
+                f(index1, index2);
+            }
+        }
+        for &offset in HALF_STENCIL.iter() {
+            let other = self.cell_particles_at((gx, gy), offset);
+            for &index1 in cell {
+                for &index2 in other {
+                    f(index1, index2);
+                }
+            }
+        }
+    }
+
+    pub fn get_neighbours(&self, x: f64, y: f64) -> Vec<u32> {
+        let mut neighbours = Vec::new();
+        self.for_each_neighbour(x, y, |index| neighbours.push(index));
         return neighbours;
     }
 }
@@ -90,13 +341,13 @@ mod tests {
 
     #[test]
     fn test_world_to_grid() {
-        let (gx, gy) = world_to_grid(0.5, -1.0, -1.0, -0.75, -0.75);
+        let (gx, gy) = world_to_grid(1.0, -1.0, -1.0, -0.75, -0.75);
         assert!(gx == 0);
         assert!(gy == 0);
     }
     #[test]
     fn test_grid() {
-        let mut grid = create_grid(0.5, -2.0, 2.0, -2.0, 2.0);
+        let mut grid = create_grid(1.0, -2.0, 2.0, -2.0, 2.0);
         grid.add_particle(1, -0.5, -0.5);
         grid.add_particle(2, -0.2, -0.2);
         grid.add_particle(3, 1.0, 1.0);
@@ -111,4 +362,127 @@ mod tests {
         assert!(neighbours3.contains(&1));
         assert!(neighbours3.contains(&2));
     }
+
+    #[test]
+    fn test_compact_matches_cells() {
+        let mut cells = create_grid(1.0, -2.0, 2.0, -2.0, 2.0);
+        let mut compact = create_grid_with_backend(GridBackend::Compact, 1.0, -2.0, 2.0, -2.0, 2.0);
+        for index in 0..200 {
+            let x = -2.0 + 4.0 * ((index * 37) % 200) as f64 / 200.0;
+            let y = -2.0 + 4.0 * ((index * 91) % 200) as f64 / 200.0;
+            cells.add_particle(index, x, y);
+            compact.add_particle(index, x, y);
+        }
+        cells.finish();
+        compact.finish();
+        for &(x, y) in [(-2.0, -2.0), (0.1, 0.3), (1.9, -1.2), (2.0, 2.0)].iter() {
+            assert_eq!(cells.get_neighbours(x, y), compact.get_neighbours(x, y));
+        }
+    }
+
+    #[test]
+    fn test_hash_matches_cells() {
+        let mut cells = create_grid(1.0, -2.0, 2.0, -2.0, 2.0);
+        let mut hash = create_grid_with_backend(GridBackend::Hash, 1.0, -2.0, 2.0, -2.0, 2.0);
+        for index in 0..200 {
+            let x = -2.0 + 4.0 * ((index * 37) % 200) as f64 / 200.0;
+            let y = -2.0 + 4.0 * ((index * 91) % 200) as f64 / 200.0;
+            cells.add_particle(index, x, y);
+            hash.add_particle(index, x, y);
+        }
+        for &(x, y) in [(-2.0, -2.0), (0.1, 0.3), (1.9, -1.2), (2.0, 2.0)].iter() {
+            assert_eq!(cells.get_neighbours(x, y), hash.get_neighbours(x, y));
+        }
+        assert!(hash.escaped().is_empty());
+    }
+
+    #[test]
+    fn test_particles_outside_domain() {
+        for &backend in [GridBackend::Cells, GridBackend::Compact, GridBackend::Hash].iter() {
+            let mut grid = create_grid_with_backend(backend, 1.0, 0.0, 2.0, 0.0, 2.0);
+            grid.add_particle(1, -0.1, 1.0);
+            grid.add_particle(2, 0.1, 1.0);
+            grid.add_particle(3, 2.5, 2.5);
+            grid.add_particle(4, -30.0, 1e6);
+            grid.finish();
+            assert_eq!(grid.escaped(), &[1, 3, 4]);
+            let neighbours = grid.get_neighbours(-0.1, 1.0);
+            assert!(neighbours.contains(&1));
+            assert!(neighbours.contains(&2));
+            assert!(grid.get_neighbours(2.5, 2.5).contains(&3));
+        }
+        let mut hash = create_grid_with_backend(GridBackend::Hash, 1.0, 0.0, 2.0, 0.0, 2.0);
+        hash.add_particle(1, -5.0, -5.0);
+        hash.add_particle(2, -5.5, -4.5);
+        assert_eq!(hash.get_neighbours(-5.2, -5.0), vec![2, 1]);
+        assert!(hash.get_neighbours(0.5, 0.5).is_empty());
+    }
+
+    #[test]
+    fn test_hash_keeps_non_finite_positions() {
+        let mut hash = create_grid_with_backend(GridBackend::Hash, 1.0, 0.0, 2.0, 0.0, 2.0);
+        hash.add_particle(1, f64::INFINITY, 1.0);
+        hash.add_particle(2, 1.0, f64::NEG_INFINITY);
+        hash.add_particle(3, 1e300, -1e300);
+        hash.add_particle(4, f64::NAN, 0.5);
+        hash.add_particle(5, 1.5, 1.5);
+        hash.finish();
+        assert_eq!(hash.escaped(), &[1, 2, 3, 4]);
+        assert_eq!(hash.get_neighbours(f64::INFINITY, 1.0), vec![1]);
+        assert_eq!(hash.get_neighbours(1e300, -1e300), vec![3]);
+        let mut pairs = 0;
+        hash.for_each_pair_in(0, 1, |_, _| pairs += 1);
+        assert_eq!(pairs, 1);
+    }
+
+    #[test]
+    fn test_pairs_match_full_stencil() {
+        for &backend in [GridBackend::Cells, GridBackend::Compact, GridBackend::Hash].iter() {
+            let mut grid = create_grid_with_backend(backend, 1.0, -2.0, 2.0, -2.0, 2.0);
+            let mut positions = Vec::new();
+            for index in 0..200 {
+                let x = -2.0 + 4.0 * ((index * 37) % 200) as f64 / 200.0;
+                let y = -2.0 + 4.0 * ((index * 91) % 200) as f64 / 200.0;
+                grid.add_particle(index, x, y);
+                positions.push((x, y));
+            }
+            grid.finish();
+            let mut pairs = Vec::new();
+            grid.for_each_pair_in(0, 1, |index1, index2| {
+                pairs.push((index1.min(index2), index1.max(index2)))
+            });
+            let mut expected = Vec::new();
+            for (index1, &(x, y)) in positions.iter().enumerate() {
+                grid.for_each_neighbour(x, y, |index2| {
+                    if index2 > index1 as u32 {
+                        expected.push((index1 as u32, index2));
+                    }
+                });
+            }
+            // Blocks of rows split the same walk
+            let mut blocks = Vec::new();
+            for block in 0..3 {
+                grid.for_each_pair_in(block, 3, |index1, index2| {
+                    blocks.push((index1.min(index2), index1.max(index2)))
+                });
+            }
+            assert_eq!(blocks, pairs);
+            let n_pairs = pairs.len();
+            pairs.sort_unstable();
+            pairs.dedup();
+            expected.sort_unstable();
+            assert_eq!(pairs.len(), n_pairs);
+            assert_eq!(pairs, expected);
+        }
+    }
+
+    #[test]
+    fn test_morton_key() {
+        assert_eq!(morton_key(0, 0), 0);
+        assert_eq!(morton_key(1, 0), 1);
+        assert_eq!(morton_key(0, 1), 2);
+        assert_eq!(morton_key(1, 1), 3);
+        assert_eq!(morton_key(2, 0), 4);
+        assert_eq!(morton_key(3, 3), 15);
+    }
 }
@@ -0,0 +1,136 @@
+//! Hydrostatic initialisation: the fluid starts at rest under gravity, with
+//! the densities and pressures of the equation of state and the columns
+//! compressed to match, instead of at the rest density everywhere
+
+use crate::sph::{Parameters, Particle, GAS_CONST};
+
+/// Half width of a column, relative to the spacing of its particles. Wide
+/// enough for the staggered rows of a hexagonal lattice, which are half a
+/// column apart, and narrow enough to leave out the next column.
+const COLUMN_HALF_WIDTH: f64 = 0.75;
+
+/// Density at `depth` below the free surface, measured before compression,
+/// so that depth * rest density is the mass per unit area above. With
+/// p = GAS_CONST (rho - rho0) and dp/dy = rho g the density grows
+/// exponentially with the compressed depth, linearly with this one.
+pub fn density(depth: f64, parameters: &Parameters) -> f64 {
+    parameters.rest_density * (1.0 + parameters.gravity.abs() * depth / GAS_CONST)
+}
+
+/// Depth below the free surface after compression of fluid at `depth`
+/// before it
+pub fn compressed_depth(depth: f64, parameters: &Parameters) -> f64 {
+    let scale = GAS_CONST / parameters.gravity.abs();
+    scale * (depth / scale).ln_1p()
+}
+
+/// Compress each column of particles along gravity towards its bottom, half
+/// a spacing beyond its deepest particle, which stays put like the floor the
+/// column rests on, and give every particle the density and pressure of its
+/// depth. The column of a particle is the vertical strip around it,
+/// `COLUMN_HALF_WIDTH` of its own spacing to either side, which follows the
+/// lattice it was placed on. The free surface is half a spacing above the
+/// first particle of the column.
+pub fn initialise(particles: &mut [Particle], parameters: &Parameters) {
+    if parameters.gravity == 0.0 {
+        return;
+    }
+    let down = parameters.gravity.signum();
+    let spacing = |particle: &Particle| particle.spacing(parameters.rest_density);
+    let mut order: Vec<usize> = (0..particles.len()).collect();
+    order.sort_by(|&i, &j| particles[i].x.total_cmp(&particles[j].x));
+    let xs: Vec<f64> = order.iter().map(|&i| particles[i].x).collect();
+    // Shallowest and deepest particle of each column, along gravity
+    let columns: Vec<(f64, f64)> = particles
+        .iter()
+        .map(|particle| {
+            let half_width = COLUMN_HALF_WIDTH * spacing(particle);
+            let start = xs.partition_point(|&x| x < particle.x - half_width);
+            let end = xs.partition_point(|&x| x <= particle.x + half_width);
+            order[start..end]
+                .iter()
+                .map(|&j| down * particles[j].y)
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(first, last), s| {
+                    (first.min(s), last.max(s))
+                })
+        })
+        .collect();
+    for (particle, (first, last)) in particles.iter_mut().zip(columns) {
+        let surface = first - 0.5 * spacing(particle);
+        let bottom = last + 0.5 * spacing(particle);
+        let depth = down * particle.y - surface;
+        let bottom_depth = compressed_depth(bottom - surface, parameters);
+        particle.y = down * (bottom - bottom_depth + compressed_depth(depth, parameters));
+        particle.density = density(depth, parameters);
+        particle.pressure = GAS_CONST * (particle.density - parameters.rest_density);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::packing::{self, Packing};
+
+    #[test]
+    fn test_hexagonal_rows_stay_level() {
+        let parameters = Parameters::new();
+        let bounds = (1.0, 2.0, 3.0, 4.0);
+        let positions = packing::fill(
+            Packing::Hexagonal,
+            parameters.particle_spacing(),
+            bounds,
+            |_, _| true,
+        );
+        let mut particles: Vec<Particle> = positions
+            .iter()
+            .map(|&(x, y)| parameters.particle(x, y))
+            .collect();
+        initialise(&mut particles, &parameters);
+        // Particles of a row, staggered or not, keep a common depth
+        for (i, particle1) in particles.iter().enumerate() {
+            for (j, particle2) in particles.iter().enumerate() {
+                if (positions[i].1 - positions[j].1).abs() < 1e-9 {
+                    assert!((particle1.y - particle2.y).abs() < 1e-9);
+                    assert!((particle1.density - particle2.density).abs() < 1e-9);
+                }
+            }
+        }
+        // The deepest row sinks into the half spacing above the floor
+        let deepest = positions.iter().map(|&(_, y)| y).fold(0.0, f64::max);
+        let bottom = particles
+            .iter()
+            .map(|particle| particle.y)
+            .fold(0.0, f64::max);
+        assert!(bottom > deepest && bottom < deepest + 0.5 * parameters.particle_spacing());
+    }
+
+    #[test]
+    fn test_column_holds_its_weight() {
+        let parameters = Parameters::new();
+        let spacing = parameters.particle_spacing();
+        let mut particles: Vec<Particle> = (0..20)
+            .map(|j| parameters.particle(1.0, 2.0 + (j as f64 + 0.5) * spacing))
+            .collect();
+        initialise(&mut particles, &parameters);
+        // The floor stays half a compressed spacing below the deepest
+        // particle, and the surface sinks
+        let floor = 2.0 + 20.0 * spacing;
+        let last_spacing = particles[19].y - particles[18].y;
+        assert!(((floor - particles[19].y) / last_spacing - 0.5).abs() < 0.01);
+        assert!(particles[0].y > 2.0 + 0.5 * spacing);
+        for pair in particles.windows(2) {
+            let (upper, lower) = (&pair[0], &pair[1]);
+            assert!(lower.y > upper.y && lower.density > upper.density);
+            // The pressure difference carries the weight between them
+            let weight = parameters.gravity * 0.5 * (upper.density + lower.density);
+            let gradient = (lower.pressure - upper.pressure) / (lower.y - upper.y);
+            assert!(
+                (gradient / weight - 1.0).abs() < 1e-3,
+                "{} {}",
+                gradient,
+                weight
+            );
+        }
+    }
+}
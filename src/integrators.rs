@@ -0,0 +1,488 @@
+use crate::parallel;
+use crate::sph::Particle;
+
+use std::str::FromStr;
+
+/// The physics an integrator advances in time
+pub trait System {
+    /// Enforce walls and obstacles on particle positions and velocities
+    fn constrain(&mut self, particles: &mut [Particle]);
+    /// Enforce them on the intermediate state of a multi-stage step, without
+    /// exchanging momentum with moving bodies, which the final state does
+    fn constrain_stage(&mut self, particles: &mut [Particle]) {
+        self.constrain(particles);
+    }
+    /// Recompute density (or its rate of change), pressure and forces
+    /// (fx, fy) at the current positions
+    fn evaluate(&mut self, particles: &mut [Particle]);
+}
+
+/// Time integration scheme. On entry to `step` the forces stored on the
+/// particles belong to their current positions, and so they must on exit.
+/// Particles asleep are left as they are.
+/// The density is advanced by its rate alongside the velocity; with density
+/// summation the rate is zero and `evaluate` overwrites the density anyway.
+pub trait Integrator: Send {
+    fn step(&mut self, particles: &mut [Particle], dt: f64, system: &mut dyn System);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IntegratorType {
+    VelocityVerlet,
+    SymplecticEuler,
+    Leapfrog,
+    RungeKutta4,
+    PredictorCorrector,
+}
+
+impl FromStr for IntegratorType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "verlet" => Ok(IntegratorType::VelocityVerlet),
+            "euler" => Ok(IntegratorType::SymplecticEuler),
+            "leapfrog" => Ok(IntegratorType::Leapfrog),
+            "rk4" => Ok(IntegratorType::RungeKutta4),
+            "pc" => Ok(IntegratorType::PredictorCorrector),
+            _ => Err(format!("Unknown integrator: {}", s)),
+        }
+    }
+}
+
+/// With `parallel` the per-particle loops run on the rayon thread pool
+pub fn create_integrator(integrator: IntegratorType, parallel: bool) -> Box<dyn Integrator> {
+    match integrator {
+        IntegratorType::VelocityVerlet => Box::new(VelocityVerlet::new(parallel)),
+        IntegratorType::SymplecticEuler => Box::new(SymplecticEuler { parallel }),
+        IntegratorType::Leapfrog => Box::new(Leapfrog { parallel }),
+        IntegratorType::RungeKutta4 => Box::new(RungeKutta4::new(parallel)),
+        IntegratorType::PredictorCorrector => Box::new(PredictorCorrector::new(parallel)),
+    }
+}
+
+fn acceleration(particle: &Particle) -> (f64, f64) {
+    (
+        particle.fx / particle.density,
+        particle.fy / particle.density,
+    )
+}
+
+fn kick(particles: &mut [Particle], dt: f64, parallel: bool) {
+    parallel::for_each(particles, parallel, |particle| {
+        if particle.asleep {
+            return;
+        }
+        let (ax, ay) = acceleration(particle);
+        particle.vx += ax * dt;
+        particle.vy += ay * dt;
+        particle.density += particle.density_rate * dt;
+    });
+}
+
+fn drift(particles: &mut [Particle], dt: f64, parallel: bool) {
+    parallel::for_each(particles, parallel, |particle| {
+        if particle.asleep {
+            return;
+        }
+        particle.x += particle.vx * dt;
+        particle.y += particle.vy * dt;
+    });
+}
+
+/// Position, velocity and density of a particle at the start of a step
+#[derive(Clone, Copy, Default)]
+struct Phase {
+    x: f64,
+    y: f64,
+    vx: f64,
+    vy: f64,
+    density: f64,
+}
+
+impl Phase {
+    fn of(particle: &Particle) -> Phase {
+        Phase {
+            x: particle.x,
+            y: particle.y,
+            vx: particle.vx,
+            vy: particle.vy,
+            density: particle.density,
+        }
+    }
+}
+
+/// Time derivative of a particle's phase space coordinates
+#[derive(Clone, Copy, Default)]
+struct Derivative {
+    dx: f64,
+    dy: f64,
+    dvx: f64,
+    dvy: f64,
+    ddensity: f64,
+}
+
+impl Derivative {
+    fn of(particle: &Particle) -> Derivative {
+        let (ax, ay) = acceleration(particle);
+        Derivative {
+            dx: particle.vx,
+            dy: particle.vy,
+            dvx: ax,
+            dvy: ay,
+            ddensity: particle.density_rate,
+        }
+    }
+}
+
+pub struct VelocityVerlet {
+    parallel: bool,
+    old_forces: Vec<(f64, f64)>,
+}
+
+impl VelocityVerlet {
+    pub fn new(parallel: bool) -> VelocityVerlet {
+        VelocityVerlet {
+            parallel,
+            old_forces: Vec::new(),
+        }
+    }
+}
+
+impl Integrator for VelocityVerlet {
+    fn step(&mut self, particles: &mut [Particle], dt: f64, system: &mut dyn System) {
+        self.old_forces.resize(particles.len(), (0.0, 0.0));
+        parallel::for_each_zip(
+            particles,
+            &mut self.old_forces,
+            self.parallel,
+            |particle, old_force| {
+                if particle.asleep {
+                    return;
+                }
+                *old_force = (particle.fx, particle.fy);
+                particle.x = particle.x
+                    + particle.vx * dt
+                    + 0.5 * (particle.fx / particle.density) * dt * dt;
+                particle.y = particle.y
+                    + particle.vy * dt
+                    + 0.5 * (particle.fy / particle.density) * dt * dt;
+                particle.density += particle.density_rate * dt;
+            },
+        );
+        system.constrain(particles);
+        system.evaluate(particles);
+        parallel::for_each_zip(
+            particles,
+            &mut self.old_forces,
+            self.parallel,
+            |particle, (ofx, ofy)| {
+                if particle.asleep {
+                    return;
+                }
+                particle.vx += (*ofx + particle.fx) / particle.density / 2.0 * dt;
+                particle.vy += (*ofy + particle.fy) / particle.density / 2.0 * dt;
+            },
+        );
+    }
+}
+
+pub struct SymplecticEuler {
+    parallel: bool,
+}
+
+impl Integrator for SymplecticEuler {
+    fn step(&mut self, particles: &mut [Particle], dt: f64, system: &mut dyn System) {
+        kick(particles, dt, self.parallel);
+        drift(particles, dt, self.parallel);
+        system.constrain(particles);
+        system.evaluate(particles);
+    }
+}
+
+/// Kick-drift-kick leapfrog
+pub struct Leapfrog {
+    parallel: bool,
+}
+
+impl Integrator for Leapfrog {
+    fn step(&mut self, particles: &mut [Particle], dt: f64, system: &mut dyn System) {
+        kick(particles, 0.5 * dt, self.parallel);
+        drift(particles, dt, self.parallel);
+        system.constrain(particles);
+        system.evaluate(particles);
+        kick(particles, 0.5 * dt, self.parallel);
+    }
+}
+
+/// Classic fourth order Runge-Kutta
+pub struct RungeKutta4 {
+    parallel: bool,
+    /// Start of step and weighted sum of the stage derivatives so far
+    stages: Vec<(Phase, Derivative)>,
+}
+
+impl RungeKutta4 {
+    pub fn new(parallel: bool) -> RungeKutta4 {
+        RungeKutta4 {
+            parallel,
+            stages: Vec::new(),
+        }
+    }
+
+    fn stage(&mut self, particles: &mut [Particle], dt: f64, weight: f64) {
+        parallel::for_each_zip(
+            particles,
+            &mut self.stages,
+            self.parallel,
+            |particle, (start, sum)| {
+                if particle.asleep {
+                    return;
+                }
+                let k = Derivative::of(particle);
+                sum.dx += weight * k.dx;
+                sum.dy += weight * k.dy;
+                sum.dvx += weight * k.dvx;
+                sum.dvy += weight * k.dvy;
+                sum.ddensity += weight * k.ddensity;
+                particle.x = start.x + k.dx * dt;
+                particle.y = start.y + k.dy * dt;
+                particle.vx = start.vx + k.dvx * dt;
+                particle.vy = start.vy + k.dvy * dt;
+                particle.density = start.density + k.ddensity * dt;
+            },
+        );
+    }
+}
+
+impl Integrator for RungeKutta4 {
+    fn step(&mut self, particles: &mut [Particle], dt: f64, system: &mut dyn System) {
+        self.stages.clear();
+        self.stages.extend(
+            particles
+                .iter()
+                .map(|particle| (Phase::of(particle), Derivative::default())),
+        );
+
+        self.stage(particles, 0.5 * dt, 1.0);
+        system.constrain_stage(particles);
+        system.evaluate(particles);
+        self.stage(particles, 0.5 * dt, 2.0);
+        system.constrain_stage(particles);
+        system.evaluate(particles);
+        self.stage(particles, dt, 2.0);
+        system.constrain_stage(particles);
+        system.evaluate(particles);
+
+        parallel::for_each_zip(
+            particles,
+            &mut self.stages,
+            self.parallel,
+            |particle, (start, sum)| {
+                if particle.asleep {
+                    return;
+                }
+                let k = Derivative::of(particle);
+                particle.x = start.x + (sum.dx + k.dx) * dt / 6.0;
+                particle.y = start.y + (sum.dy + k.dy) * dt / 6.0;
+                particle.vx = start.vx + (sum.dvx + k.dvx) * dt / 6.0;
+                particle.vy = start.vy + (sum.dvy + k.dvy) * dt / 6.0;
+                particle.density = start.density + (sum.ddensity + k.ddensity) * dt / 6.0;
+            },
+        );
+        system.constrain(particles);
+        system.evaluate(particles);
+    }
+}
+
+/// Heun's method: explicit Euler predictor followed by a trapezoidal corrector
+pub struct PredictorCorrector {
+    parallel: bool,
+    /// Start of step and the derivative used by the predictor
+    predictor: Vec<(Phase, Derivative)>,
+}
+
+impl PredictorCorrector {
+    pub fn new(parallel: bool) -> PredictorCorrector {
+        PredictorCorrector {
+            parallel,
+            predictor: Vec::new(),
+        }
+    }
+}
+
+impl Integrator for PredictorCorrector {
+    fn step(&mut self, particles: &mut [Particle], dt: f64, system: &mut dyn System) {
+        self.predictor.clear();
+        self.predictor.extend(
+            particles
+                .iter()
+                .map(|particle| (Phase::of(particle), Derivative::of(particle))),
+        );
+
+        drift(particles, dt, self.parallel);
+        kick(particles, dt, self.parallel);
+        system.constrain_stage(particles);
+        system.evaluate(particles);
+
+        parallel::for_each_zip(
+            particles,
+            &mut self.predictor,
+            self.parallel,
+            |particle, (start, predictor)| {
+                if particle.asleep {
+                    return;
+                }
+                let corrector = Derivative::of(particle);
+                particle.x = start.x + 0.5 * (predictor.dx + corrector.dx) * dt;
+                particle.y = start.y + 0.5 * (predictor.dy + corrector.dy) * dt;
+                particle.vx = start.vx + 0.5 * (predictor.dvx + corrector.dvx) * dt;
+                particle.vy = start.vy + 0.5 * (predictor.dvy + corrector.dvy) * dt;
+                particle.density =
+                    start.density + 0.5 * (predictor.ddensity + corrector.ddensity) * dt;
+            },
+        );
+        system.constrain(particles);
+        system.evaluate(particles);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::sph::Parameters;
+
+    use std::f64::consts::PI;
+
+    /// Unit mass particles on springs of unit stiffness, period 2 pi
+    struct Springs {
+        evaluations: usize,
+    }
+
+    impl System for Springs {
+        fn constrain(&mut self, _particles: &mut [Particle]) {}
+
+        fn evaluate(&mut self, particles: &mut [Particle]) {
+            self.evaluations += 1;
+            for particle in particles.iter_mut() {
+                particle.fx = -particle.x * particle.density;
+                particle.fy = -particle.y * particle.density;
+            }
+        }
+    }
+
+    fn one_period(integrator: IntegratorType, steps: usize) -> (Particle, usize) {
+        let mut particles = vec![Parameters::new().particle(1.0, 0.0)];
+        let mut system = Springs { evaluations: 0 };
+        system.evaluate(&mut particles);
+        system.evaluations = 0;
+        let mut integrator = create_integrator(integrator, false);
+        for _ in 0..steps {
+            integrator.step(&mut particles, 2.0 * PI / steps as f64, &mut system);
+        }
+        (particles[0].clone(), system.evaluations / steps)
+    }
+
+    #[test]
+    fn test_integrators_return_after_one_period() {
+        let cases = [
+            (IntegratorType::VelocityVerlet, 1e-3, 1),
+            (IntegratorType::SymplecticEuler, 1e-2, 1),
+            (IntegratorType::Leapfrog, 1e-3, 1),
+            (IntegratorType::RungeKutta4, 1e-8, 4),
+            (IntegratorType::PredictorCorrector, 1e-3, 2),
+        ];
+        for &(integrator, tolerance, evaluations) in cases.iter() {
+            let (particle, n) = one_period(integrator, 1000);
+            assert!((particle.x - 1.0).abs() < tolerance, "{:?}", integrator);
+            assert!(particle.vx.abs() < tolerance, "{:?}", integrator);
+            assert_eq!(n, evaluations, "{:?}", integrator);
+        }
+    }
+
+    /// Particles thrown at a wall at x = 1, which must never be evaluated
+    /// beyond it
+    struct Wall;
+
+    impl System for Wall {
+        fn constrain(&mut self, particles: &mut [Particle]) {
+            for particle in particles.iter_mut().filter(|particle| particle.x > 1.0) {
+                particle.x = 1.0;
+                particle.vx = -particle.vx;
+            }
+        }
+
+        fn evaluate(&mut self, particles: &mut [Particle]) {
+            for particle in particles.iter_mut() {
+                assert!(particle.x <= 1.0, "{}", particle.x);
+                particle.fx = 0.0;
+                particle.fy = 0.0;
+            }
+        }
+    }
+
+    #[test]
+    fn test_stages_are_constrained() {
+        for &kind in [
+            IntegratorType::VelocityVerlet,
+            IntegratorType::SymplecticEuler,
+            IntegratorType::Leapfrog,
+            IntegratorType::RungeKutta4,
+            IntegratorType::PredictorCorrector,
+        ]
+        .iter()
+        {
+            let mut particles = vec![Particle {
+                vx: 10.0,
+                ..Parameters::new().particle(0.0, 0.0)
+            }];
+            let mut integrator = create_integrator(kind, false);
+            for _ in 0..20 {
+                integrator.step(&mut particles, 0.01, &mut Wall);
+            }
+            assert!(particles[0].x <= 1.0, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn test_sleeping_particles_are_not_moved() {
+        for &kind in [
+            IntegratorType::VelocityVerlet,
+            IntegratorType::SymplecticEuler,
+            IntegratorType::Leapfrog,
+            IntegratorType::RungeKutta4,
+            IntegratorType::PredictorCorrector,
+        ]
+        .iter()
+        {
+            let awake = Parameters::new().particle(1.0, 0.0);
+            let asleep = Particle {
+                vx: 0.5,
+                asleep: true,
+                ..awake.clone()
+            };
+            let mut particles = vec![awake, asleep.clone()];
+            let mut system = Springs { evaluations: 0 };
+            system.evaluate(&mut particles);
+            let mut integrator = create_integrator(kind, false);
+            for _ in 0..10 {
+                integrator.step(&mut particles, 0.1, &mut system);
+            }
+            assert!(particles[0].x < 1.0, "{:?}", kind);
+            let (expected, particle) = (&asleep, &particles[1]);
+            assert_eq!(
+                (particle.x, particle.y, particle.vx, particle.density),
+                (expected.x, expected.y, expected.vx, expected.density),
+                "{:?}",
+                kind
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_integrator() {
+        assert_eq!("rk4".parse(), Ok(IntegratorType::RungeKutta4));
+        assert!("midpoint".parse::<IntegratorType>().is_err());
+    }
+}
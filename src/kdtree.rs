@@ -0,0 +1,76 @@
+use crate::sph::Particle;
+
+use std::cmp::Ordering;
+
+/// Two dimensional k-d tree stored implicitly: the point splitting a range
+/// of `points` is its middle element, with the axis alternating per level.
+pub struct KdTree {
+    points: Vec<(f64, f64, u32)>,
+    radius: f64,
+}
+
+fn coordinate(point: &(f64, f64, u32), axis: usize) -> f64 {
+    if axis == 0 {
+        point.0
+    } else {
+        point.1
+    }
+}
+
+fn build(points: &mut [(f64, f64, u32)], axis: usize) {
+    if points.len() <= 1 {
+        return;
+    }
+    let mid = points.len() / 2;
+    points.select_nth_unstable_by(mid, |a, b| {
+        coordinate(a, axis)
+            .partial_cmp(&coordinate(b, axis))
+            .unwrap_or(Ordering::Equal)
+    });
+    let (left, right) = points.split_at_mut(mid);
+    build(left, 1 - axis);
+    build(&mut right[1..], 1 - axis);
+}
+
+impl KdTree {
+    /// Tree answering queries for all particles within `radius`
+    pub fn new(particles: &[Particle], radius: f64) -> KdTree {
+        let mut points: Vec<_> = particles
+            .iter()
+            .enumerate()
+            .map(|(index, particle)| (particle.x, particle.y, index as u32))
+            .collect();
+        build(&mut points, 0);
+        KdTree { points, radius }
+    }
+
+    /// Call `f` with exactly the particles within the radius of (x, y)
+    pub fn for_each_neighbour(&self, x: f64, y: f64, mut f: impl FnMut(u32)) {
+        self.query(&self.points, 0, x, y, &mut f);
+    }
+
+    fn query(
+        &self,
+        points: &[(f64, f64, u32)],
+        axis: usize,
+        x: f64,
+        y: f64,
+        f: &mut impl FnMut(u32),
+    ) {
+        if points.is_empty() {
+            return;
+        }
+        let mid = points.len() / 2;
+        let point = &points[mid];
+        if (point.0 - x).powi(2) + (point.1 - y).powi(2) <= self.radius.powi(2) {
+            f(point.2);
+        }
+        let offset = coordinate(&(x, y, 0), axis) - coordinate(point, axis);
+        if offset <= self.radius {
+            self.query(&points[..mid], 1 - axis, x, y, f);
+        }
+        if offset >= -self.radius {
+            self.query(&points[mid + 1..], 1 - axis, x, y, f);
+        }
+    }
+}
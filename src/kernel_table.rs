@@ -0,0 +1,266 @@
+use crate::kernels::{Dimension, Kernel};
+
+use std::str::FromStr;
+
+/// The gradient and Laplacian have terms odd in q, which are not smooth in
+/// q^2 near the origin. The first intervals are interpolated linearly in q.
+const ORIGIN_INTERVALS: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interpolation {
+    Linear,
+    /// Catmull-Rom through the four nearest samples
+    Cubic,
+}
+
+impl FromStr for Interpolation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(Interpolation::Linear),
+            "cubic" => Ok(Interpolation::Cubic),
+            _ => Err(format!("Unknown interpolation: {}", s)),
+        }
+    }
+}
+
+/// A kernel sampled at uniform steps of q^2 = (r / h)^2, so that evaluating
+/// it needs neither a square root nor any powers of q
+#[derive(Debug)]
+pub struct Tabulated {
+    dimension: Dimension,
+    support: f64,
+    /// Samples per unit of q^2
+    resolution: f64,
+    /// For each of the three columns and each interval k, the coefficients
+    /// of the interpolating polynomial in t = q^2 * resolution - k
+    polynomials: [Vec<[f64; 4]>; 3],
+    /// For the intervals near the origin, the nodes in q and the Newton
+    /// coefficients of each column's polynomial in q
+    origin: Vec<([f64; 4], [[f64; 4]; 3])>,
+}
+
+/// Newton divided differences of the samples at `nodes`
+fn newton(nodes: &[f64], values: &[f64]) -> [f64; 4] {
+    let mut coefficients = [0.0; 4];
+    coefficients[..values.len()].copy_from_slice(values);
+    for j in 1..nodes.len() {
+        for i in (j..nodes.len()).rev() {
+            coefficients[i] = (coefficients[i] - coefficients[i - 1]) / (nodes[i] - nodes[i - j]);
+        }
+    }
+    coefficients
+}
+
+impl Tabulated {
+    /// Sample `kernel` at `size` points from q = 0 to the edge of its
+    /// support, at least the two ends
+    pub fn new(kernel: &dyn Kernel, size: usize, interpolation: Interpolation) -> Tabulated {
+        assert!(size >= 2, "A kernel table needs at least 2 samples");
+        let support = kernel.support();
+        let resolution = (size - 1) as f64 / (support * support);
+        // Value, dW/dr / r and Laplacian at h = 1, padded with zeros past the
+        // support so the cubic stencil never runs off the end
+        let mut samples: Vec<_> = (0..size)
+            .map(|i| {
+                let q = (i as f64 / resolution).sqrt();
+                [
+                    kernel.value(q, 1.0),
+                    kernel.derivative(q, 1.0) / q,
+                    kernel.laplacian(q, 1.0),
+                ]
+            })
+            .collect();
+        // For kernels flat at the origin dW/dr / r tends to W''(0), which is
+        // the Laplacian there divided by the dimension
+        samples[0][1] = kernel.laplacian(0.0, 1.0) / kernel.dimension() as i32 as f64;
+        // Columns singular at the origin, those of spiky and Müller's
+        // viscosity Laplacian, are held at the next sample so that coincident
+        // particles get a large but finite value
+        let next = samples[1];
+        for (sample, next) in samples[0].iter_mut().zip(next.iter()) {
+            if !sample.is_finite() {
+                *sample = *next;
+            }
+        }
+        samples.extend_from_slice(&[[0.0; 3]; 2]);
+        let polynomial = |column: usize, k: usize| {
+            let p1 = samples[k][column];
+            let p2 = samples[k + 1][column];
+            match interpolation {
+                Interpolation::Linear => [p1, p2 - p1, 0.0, 0.0],
+                Interpolation::Cubic => {
+                    let p0 = samples[k.max(1) - 1][column];
+                    let p3 = samples[k + 2][column];
+                    [
+                        p1,
+                        0.5 * (p2 - p0),
+                        0.5 * (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3),
+                        0.5 * (3.0 * (p1 - p2) + p3 - p0),
+                    ]
+                }
+            }
+        };
+        let polynomials =
+            [0, 1, 2].map(|column| (0..size).map(|k| polynomial(column, k)).collect());
+        let origin = (0..ORIGIN_INTERVALS.min(size))
+            .map(|k| {
+                let (first, last) = match interpolation {
+                    Interpolation::Linear => (k, k + 1),
+                    Interpolation::Cubic => (k.max(1) - 1, k.max(1) + 2),
+                };
+                let mut nodes = [0.0; 4];
+                for (node, i) in nodes.iter_mut().zip(first..=last) {
+                    *node = (i as f64 / resolution).sqrt();
+                }
+                let n = last - first + 1;
+                let columns = [0, 1, 2].map(|column| {
+                    let values: Vec<f64> = (first..=last).map(|i| samples[i][column]).collect();
+                    newton(&nodes[..n], &values)
+                });
+                (nodes, columns)
+            })
+            .collect();
+        Tabulated {
+            dimension: kernel.dimension(),
+            support,
+            resolution,
+            polynomials,
+            origin,
+        }
+    }
+
+    /// Interpolated samples at r^2, scaled by 1 / h^(d + extra). Most pairs
+    /// handed to a kernel lie outside its support, so those return before
+    /// paying for the division and power
+    fn lookup(&self, r2: f64, h: f64, column: usize, extra: i32) -> f64 {
+        let x = r2 / (h * h) * self.resolution;
+        let k = x as usize;
+        let c = match self.polynomials[column].get(k) {
+            Some(c) => c,
+            None => return 0.0,
+        };
+        let sample = if k < ORIGIN_INTERVALS {
+            self.lookup_in_q(x, k, column)
+        } else {
+            let t = x - k as f64;
+            c[0] + t * (c[1] + t * (c[2] + t * c[3]))
+        };
+        sample / h.powi(self.dimension as i32 + extra)
+    }
+
+    /// Interpolation in q around interval `k`, whose samples are unevenly
+    /// spaced in q
+    fn lookup_in_q(&self, x: f64, k: usize, column: usize) -> f64 {
+        let q = (x / self.resolution).sqrt();
+        let (nodes, columns) = &self.origin[k];
+        let c = &columns[column];
+        c[0] + (q - nodes[0]) * (c[1] + (q - nodes[1]) * (c[2] + (q - nodes[2]) * c[3]))
+    }
+}
+
+impl Kernel for Tabulated {
+    fn dimension(&self) -> Dimension {
+        self.dimension
+    }
+
+    fn support(&self) -> f64 {
+        self.support
+    }
+
+    fn value(&self, r: f64, h: f64) -> f64 {
+        self.value_r2(r * r, h)
+    }
+
+    fn derivative(&self, r: f64, h: f64) -> f64 {
+        self.lookup(r * r, h, 1, 2) * r
+    }
+
+    fn laplacian(&self, r: f64, h: f64) -> f64 {
+        self.laplacian_r2(r * r, h)
+    }
+
+    fn gradient(&self, x: f64, y: f64, h: f64) -> (f64, f64) {
+        let grad = self.lookup(x * x + y * y, h, 1, 2);
+        (grad * x, grad * y)
+    }
+
+    fn value_r2(&self, r2: f64, h: f64) -> f64 {
+        self.lookup(r2, h, 0, 0)
+    }
+
+    fn laplacian_r2(&self, r2: f64, h: f64) -> f64 {
+        self.lookup(r2, h, 2, 2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::kernels::{KernelType, WendlandC2};
+
+    /// Largest error of value, gradient and Laplacian over the support,
+    /// relative to the largest magnitude of each
+    fn max_error(size: usize, interpolation: Interpolation) -> f64 {
+        let kernel = WendlandC2(Dimension::Two);
+        let table = Tabulated::new(&kernel, size, interpolation);
+        let h = 0.3;
+        let n = 10_000;
+        let mut errors = [0.0f64; 3];
+        let mut magnitudes = [0.0f64; 3];
+        for i in 0..=n {
+            let x = 2.1 * h * i as f64 / n as f64;
+            let y = 0.5 * x;
+            let r = (x * x + y * y).sqrt();
+            let exact = [
+                kernel.value(r, h),
+                kernel.gradient(x, y, h).0,
+                kernel.laplacian(r, h),
+            ];
+            let tabulated = [
+                table.value(r, h),
+                table.gradient(x, y, h).0,
+                table.laplacian(r, h),
+            ];
+            for k in 0..3 {
+                errors[k] = errors[k].max((exact[k] - tabulated[k]).abs());
+                magnitudes[k] = magnitudes[k].max(exact[k].abs());
+            }
+        }
+        (0..3)
+            .map(|k| errors[k] / magnitudes[k])
+            .fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn test_accuracy_against_wendland() {
+        assert!(max_error(1024, Interpolation::Linear) < 5e-3);
+        assert!(max_error(4096, Interpolation::Linear) < 1e-3);
+        assert!(max_error(1024, Interpolation::Cubic) < 1e-5);
+        assert!(max_error(4096, Interpolation::Cubic) < 1e-6);
+    }
+
+    #[test]
+    fn test_zero_outside_support() {
+        let table = Tabulated::new(&WendlandC2(Dimension::Two), 256, Interpolation::Cubic);
+        assert!(table.value(2.0 * 0.5, 0.5).abs() < 1e-12);
+        assert_eq!(table.value(3.0, 0.5), 0.0);
+        assert_eq!(table.gradient(3.0, 0.0, 0.5), (0.0, 0.0));
+    }
+
+    #[test]
+    fn test_singular_kernels_stay_finite() {
+        for &kernel in [KernelType::Spiky, KernelType::Muller].iter() {
+            for &size in [2, 256].iter() {
+                let table = Tabulated::new(&kernel, size, Interpolation::Cubic);
+                for &r in [0.0, 1e-6, 0.1].iter() {
+                    assert!(table.value(r, 0.5).is_finite(), "{:?} {}", kernel, r);
+                    assert!(table.gradient(r, 0.0, 0.5).0.is_finite());
+                    assert!(table.laplacian(r, 0.5).is_finite());
+                }
+            }
+        }
+    }
+}
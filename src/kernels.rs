@@ -1,48 +1,472 @@
+use crate::dual::Scalar;
 use crate::math;
 
 use std::f64::consts::PI;
+use std::str::FromStr;
 
-/// Wendland quintic kernel
-pub fn kernel_2d(r: f64, h: f64) -> f64 {
-    let q = r / h;
+/// Radius of the support of every kernel here, as a multiple of h
+const SUPPORT: f64 = 2.0;
+
+/// Smoothing kernel W(r, h)
+pub trait Kernel: Send + Sync {
+    fn dimension(&self) -> Dimension;
+
+    /// Radius outside which the kernel vanishes, as a multiple of h
+    fn support(&self) -> f64;
+
+    fn value(&self, r: f64, h: f64) -> f64;
+
+    /// Radial derivative dW/dr
+    fn derivative(&self, r: f64, h: f64) -> f64;
 
-    if q > 2.0 {
-        return 0.0;
+    fn laplacian(&self, r: f64, h: f64) -> f64;
+
+    /// Gradient with respect to the first particle, for the displacement
+    /// (x, y) from the second particle to the first
+    fn gradient(&self, x: f64, y: f64, h: f64) -> (f64, f64) {
+        let r = math::length(x, y);
+        if r == 0.0 {
+            return (0.0, 0.0);
+        }
+        let grad = self.derivative(r, h) / r;
+        (grad * x, grad * y)
+    }
+
+    /// Value from the squared distance, which tabulated kernels use without
+    /// taking the square root
+    fn value_r2(&self, r2: f64, h: f64) -> f64 {
+        self.value(r2.sqrt(), h)
     }
 
-    let alpha_d = 7.0 / 4.0 / PI / h.powi(2);
-    return (1.0 - 0.5 * q).powi(4) * (2.0 * q + 1.0) * alpha_d;
+    fn laplacian_r2(&self, r2: f64, h: f64) -> f64 {
+        self.laplacian(r2.sqrt(), h)
+    }
 }
 
-/// Gradient of Wendland quintic kernel
-pub fn grad_kernel_2d(x: f64, y: f64, h: f64) -> (f64, f64) {
-    let r = math::length(x, y);
-    let q = r / h;
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dimension {
+    Two = 2,
+    Three = 3,
+}
+
+/// Radial kernel W(r, h) = sigma / R^d * f(r / R), where R is the support radius
+pub trait Profile: Send + Sync {
+    fn dimension(&self) -> Dimension;
+
+    /// Normalisation for a support radius of one
+    fn sigma(&self) -> f64;
+
+    /// f(s) on s in [0, 1], generic so that it can be differentiated
+    /// automatically
+    fn f<T: Scalar>(&self, s: T) -> T;
+
+    fn df(&self, s: f64) -> f64;
+
+    fn d2f(&self, s: f64) -> f64;
 
-    if q > 2.0 {
-        return (0.0, 0.0);
+    /// Limit of f'' + (d - 1) f' / s at s = 0, which for profiles flat at
+    /// the origin is d f''(0). Profiles with f'(0) != 0 diverge there.
+    fn laplacian_at_origin(&self) -> f64 {
+        self.dimension() as i32 as f64 * self.d2f(0.0)
     }
 
-    let alpha_d = 7.0 / 4.0 / PI / h.powi(2);
-    let grad = alpha_d * 5.0 * (q - 2.0).powi(3) / (8.0 * h * h);
-    return (grad * x, grad * y);
+    fn value_of<T: Scalar>(&self, r: T, h: f64) -> T {
+        let radius = SUPPORT * h;
+        let s = r / radius;
+        if s.value() > 1.0 {
+            return T::constant(0.0);
+        }
+        self.f(s) * (self.sigma() / radius.powi(self.dimension() as i32))
+    }
 }
 
-/// Laplacian of Wendland quintic kernel
-pub fn laplace_kernel_2d(r: f64, h: f64) -> f64 {
-    let q = r / h;
-    if q > 2.0 {
-        return 0.0;
+fn one_minus<T: Scalar>(s: T) -> T {
+    T::constant(1.0) - s
+}
+
+impl<P: Profile> Kernel for P {
+    fn dimension(&self) -> Dimension {
+        Profile::dimension(self)
+    }
+
+    fn support(&self) -> f64 {
+        SUPPORT
+    }
+
+    fn value(&self, r: f64, h: f64) -> f64 {
+        self.value_of(r, h)
+    }
+
+    fn derivative(&self, r: f64, h: f64) -> f64 {
+        let radius = SUPPORT * h;
+        let s = r / radius;
+        if s > 1.0 {
+            return 0.0;
+        }
+        self.sigma() / radius.powi(self.dimension() as i32 + 1) * self.df(s)
+    }
+
+    /// f'' + (d - 1) f' / s, with `laplacian_at_origin` at s = 0
+    fn laplacian(&self, r: f64, h: f64) -> f64 {
+        let radius = SUPPORT * h;
+        let s = r / radius;
+        if s > 1.0 {
+            return 0.0;
+        }
+        let d = self.dimension() as i32;
+        let laplacian = if s > 0.0 {
+            self.d2f(s) + (d - 1) as f64 * self.df(s) / s
+        } else {
+            self.laplacian_at_origin()
+        };
+        self.sigma() / radius.powi(d + 2) * laplacian
+    }
+}
+
+/// Monaghan's M4 cubic B-spline
+pub struct CubicSpline(pub Dimension);
+
+impl Profile for CubicSpline {
+    fn dimension(&self) -> Dimension {
+        self.0
+    }
+
+    fn sigma(&self) -> f64 {
+        match self.0 {
+            Dimension::Two => 40.0 / 7.0 / PI,
+            Dimension::Three => 8.0 / PI,
+        }
+    }
+
+    fn f<T: Scalar>(&self, s: T) -> T {
+        if s.value() < 0.5 {
+            s * s * (s * 6.0 - 6.0) + 1.0
+        } else {
+            one_minus(s).powi(3) * 2.0
+        }
+    }
+
+    fn df(&self, s: f64) -> f64 {
+        if s < 0.5 {
+            -12.0 * s + 18.0 * s * s
+        } else {
+            -6.0 * (1.0 - s).powi(2)
+        }
+    }
+
+    fn d2f(&self, s: f64) -> f64 {
+        if s < 0.5 {
+            -12.0 + 36.0 * s
+        } else {
+            12.0 * (1.0 - s)
+        }
+    }
+}
+
+/// Wendland C2, the quintic used by default
+pub struct WendlandC2(pub Dimension);
+
+impl Profile for WendlandC2 {
+    fn dimension(&self) -> Dimension {
+        self.0
+    }
+
+    fn sigma(&self) -> f64 {
+        match self.0 {
+            Dimension::Two => 7.0 / PI,
+            Dimension::Three => 21.0 / 2.0 / PI,
+        }
+    }
+
+    fn f<T: Scalar>(&self, s: T) -> T {
+        one_minus(s).powi(4) * (s * 4.0 + 1.0)
+    }
+
+    fn df(&self, s: f64) -> f64 {
+        -20.0 * s * (1.0 - s).powi(3)
+    }
+
+    fn d2f(&self, s: f64) -> f64 {
+        20.0 * (1.0 - s).powi(2) * (4.0 * s - 1.0)
+    }
+}
+
+pub struct WendlandC4(pub Dimension);
+
+impl Profile for WendlandC4 {
+    fn dimension(&self) -> Dimension {
+        self.0
+    }
+
+    fn sigma(&self) -> f64 {
+        match self.0 {
+            Dimension::Two => 9.0 / PI,
+            Dimension::Three => 495.0 / 32.0 / PI,
+        }
+    }
+
+    fn f<T: Scalar>(&self, s: T) -> T {
+        one_minus(s).powi(6) * (s * 6.0 + s * s * (35.0 / 3.0) + 1.0)
+    }
+
+    fn df(&self, s: f64) -> f64 {
+        -56.0 / 3.0 * s * (1.0 + 5.0 * s) * (1.0 - s).powi(5)
+    }
+
+    fn d2f(&self, s: f64) -> f64 {
+        -56.0 / 3.0 * (1.0 - s).powi(4) * (1.0 + 4.0 * s - 35.0 * s * s)
+    }
+}
+
+pub struct WendlandC6(pub Dimension);
+
+impl Profile for WendlandC6 {
+    fn dimension(&self) -> Dimension {
+        self.0
+    }
+
+    fn sigma(&self) -> f64 {
+        match self.0 {
+            Dimension::Two => 78.0 / 7.0 / PI,
+            Dimension::Three => 1365.0 / 64.0 / PI,
+        }
+    }
+
+    fn f<T: Scalar>(&self, s: T) -> T {
+        one_minus(s).powi(8) * (s * 8.0 + s * s * 25.0 + s * s * s * 32.0 + 1.0)
+    }
+
+    fn df(&self, s: f64) -> f64 {
+        -22.0 * s * (1.0 + 7.0 * s + 16.0 * s * s) * (1.0 - s).powi(7)
+    }
+
+    fn d2f(&self, s: f64) -> f64 {
+        -22.0 * (1.0 - s).powi(6) * (1.0 + 6.0 * s - 15.0 * s * s - 160.0 * s * s * s)
+    }
+}
+
+/// Müller et al. (2003) density kernel
+pub struct Poly6(pub Dimension);
+
+impl Profile for Poly6 {
+    fn dimension(&self) -> Dimension {
+        self.0
+    }
+
+    fn sigma(&self) -> f64 {
+        match self.0 {
+            Dimension::Two => 4.0 / PI,
+            Dimension::Three => 315.0 / 64.0 / PI,
+        }
+    }
+
+    fn f<T: Scalar>(&self, s: T) -> T {
+        one_minus(s * s).powi(3)
+    }
+
+    fn df(&self, s: f64) -> f64 {
+        -6.0 * s * (1.0 - s * s).powi(2)
+    }
+
+    fn d2f(&self, s: f64) -> f64 {
+        6.0 * (1.0 - s * s) * (5.0 * s * s - 1.0)
+    }
+}
+
+/// Müller et al. (2003) pressure kernel, its gradient does not vanish at the
+/// origin so close particles keep repelling
+pub struct Spiky(pub Dimension);
+
+impl Profile for Spiky {
+    fn dimension(&self) -> Dimension {
+        self.0
+    }
+
+    fn sigma(&self) -> f64 {
+        match self.0 {
+            Dimension::Two => 10.0 / PI,
+            Dimension::Three => 15.0 / PI,
+        }
+    }
+
+    fn f<T: Scalar>(&self, s: T) -> T {
+        one_minus(s).powi(3)
+    }
+
+    fn df(&self, s: f64) -> f64 {
+        -3.0 * (1.0 - s).powi(2)
+    }
+
+    fn d2f(&self, s: f64) -> f64 {
+        6.0 * (1.0 - s)
+    }
+
+    /// f'(0) = -3, so (d - 1) f' / s tends to minus infinity
+    fn laplacian_at_origin(&self) -> f64 {
+        f64::NEG_INFINITY
+    }
+}
+
+/// Müller et al. (2003) viscosity kernel, with a Laplacian that is positive
+/// everywhere inside the support. Singular at the origin.
+pub struct Viscosity(pub Dimension);
+
+impl Profile for Viscosity {
+    fn dimension(&self) -> Dimension {
+        self.0
+    }
+
+    fn sigma(&self) -> f64 {
+        match self.0 {
+            Dimension::Two => 10.0 / 3.0 / PI,
+            Dimension::Three => 15.0 / 2.0 / PI,
+        }
+    }
+
+    fn f<T: Scalar>(&self, s: T) -> T {
+        -(s * s * s) * 0.5 + s * s + T::constant(0.5) / s - 1.0
+    }
+
+    fn df(&self, s: f64) -> f64 {
+        -1.5 * s * s + 2.0 * s - 0.5 / (s * s)
+    }
+
+    fn d2f(&self, s: f64) -> f64 {
+        -3.0 * s + 2.0 + 1.0 / (s * s * s)
+    }
+
+    /// In three dimensions the 1 / s^3 terms cancel, leaving 6 (1 - s). In
+    /// two half of it remains.
+    fn laplacian_at_origin(&self) -> f64 {
+        match self.0 {
+            Dimension::Two => f64::INFINITY,
+            Dimension::Three => 6.0,
+        }
+    }
+}
+
+/// The classic trio: poly6 for density, spiky for the pressure gradient and
+/// viscosity for the Laplacian
+pub struct Muller(pub Dimension);
+
+impl Kernel for Muller {
+    fn dimension(&self) -> Dimension {
+        self.0
+    }
+
+    fn support(&self) -> f64 {
+        SUPPORT
+    }
+
+    fn value(&self, r: f64, h: f64) -> f64 {
+        Poly6(self.0).value(r, h)
+    }
+
+    fn derivative(&self, r: f64, h: f64) -> f64 {
+        Spiky(self.0).derivative(r, h)
+    }
+
+    fn laplacian(&self, r: f64, h: f64) -> f64 {
+        Viscosity(self.0).laplacian(r, h)
+    }
+}
+
+/// Kernel used by the simulation, always in two dimensions. The viscosity
+/// kernel is singular at the origin so it is only offered as part of `Muller`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KernelType {
+    CubicSpline,
+    WendlandC2,
+    WendlandC4,
+    WendlandC6,
+    Poly6,
+    Spiky,
+    Muller,
+}
+
+impl KernelType {
+    fn kernel(self) -> &'static dyn Kernel {
+        match self {
+            KernelType::CubicSpline => &CubicSpline(Dimension::Two),
+            KernelType::WendlandC2 => &WendlandC2(Dimension::Two),
+            KernelType::WendlandC4 => &WendlandC4(Dimension::Two),
+            KernelType::WendlandC6 => &WendlandC6(Dimension::Two),
+            KernelType::Poly6 => &Poly6(Dimension::Two),
+            KernelType::Spiky => &Spiky(Dimension::Two),
+            KernelType::Muller => &Muller(Dimension::Two),
+        }
+    }
+}
+
+impl Kernel for KernelType {
+    fn dimension(&self) -> Dimension {
+        Dimension::Two
+    }
+
+    fn support(&self) -> f64 {
+        self.kernel().support()
+    }
+
+    fn value(&self, r: f64, h: f64) -> f64 {
+        self.kernel().value(r, h)
+    }
+
+    fn derivative(&self, r: f64, h: f64) -> f64 {
+        self.kernel().derivative(r, h)
+    }
+
+    fn laplacian(&self, r: f64, h: f64) -> f64 {
+        self.kernel().laplacian(r, h)
+    }
+
+    fn gradient(&self, x: f64, y: f64, h: f64) -> (f64, f64) {
+        self.kernel().gradient(x, y, h)
+    }
+}
+
+impl FromStr for KernelType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cubic" => Ok(KernelType::CubicSpline),
+            "wendland2" => Ok(KernelType::WendlandC2),
+            "wendland4" => Ok(KernelType::WendlandC4),
+            "wendland6" => Ok(KernelType::WendlandC6),
+            "poly6" => Ok(KernelType::Poly6),
+            "spiky" => Ok(KernelType::Spiky),
+            "muller" => Ok(KernelType::Muller),
+            _ => Err(format!("Unknown kernel: {}", s)),
+        }
     }
+}
+
+/// Wendland quintic kernel
+#[allow(dead_code)]
+pub fn kernel_2d(r: f64, h: f64) -> f64 {
+    WendlandC2(Dimension::Two).value(r, h)
+}
+
+/// Gradient of Wendland quintic kernel
+#[allow(dead_code)]
+pub fn grad_kernel_2d(x: f64, y: f64, h: f64) -> (f64, f64) {
+    WendlandC2(Dimension::Two).gradient(x, y, h)
+}
 
-    let alpha_d = 7.0 / 4.0 / PI / h.powi(2);
-    return alpha_d * 5.0 * (5.0 * q * q * q - 24.0 * q * q + 36.0 * q - 16.0) / (8.0 * h * h);
+/// Laplacian of Wendland quintic kernel
+#[allow(dead_code)]
+pub fn laplace_kernel_2d(r: f64, h: f64) -> f64 {
+    WendlandC2(Dimension::Two).laplacian(r, h)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use crate::dual::Dual;
+    use crate::math::Random;
+
     #[test]
     fn test_kernel_2d() {
         let tolerance = 1e-15;
@@ -67,4 +491,187 @@ mod tests {
         let laplacian = laplace_kernel_2d(f64::sqrt(5.0), 10.);
         assert!((laplacian - (-0.000316617746208086)).abs() < tolerance);
     }
+
+    fn all_kernels(dimension: Dimension) -> Vec<Box<dyn Kernel>> {
+        vec![
+            Box::new(CubicSpline(dimension)),
+            Box::new(WendlandC2(dimension)),
+            Box::new(WendlandC4(dimension)),
+            Box::new(WendlandC6(dimension)),
+            Box::new(Poly6(dimension)),
+            Box::new(Spiky(dimension)),
+            Box::new(Viscosity(dimension)),
+        ]
+    }
+
+    #[test]
+    fn test_derivatives_match_finite_differences() {
+        let (h, e) = (0.7, 1e-6);
+        for &dimension in [Dimension::Two, Dimension::Three].iter() {
+            let d = dimension as i32;
+            for kernel in all_kernels(dimension) {
+                for i in 1..20 {
+                    let r = 0.07 * i as f64;
+                    let derivative = (kernel.value(r + e, h) - kernel.value(r - e, h)) / (2.0 * e);
+                    let second =
+                        (kernel.derivative(r + e, h) - kernel.derivative(r - e, h)) / (2.0 * e);
+                    let laplacian = second + (d - 1) as f64 * kernel.derivative(r, h) / r;
+                    let scale = 1.0 + kernel.value(r, h).abs();
+                    assert!((kernel.derivative(r, h) - derivative).abs() < 1e-6 * scale);
+                    assert!((kernel.laplacian(r, h) - laplacian).abs() < 1e-5 * scale);
+                }
+                assert_eq!(kernel.value(SUPPORT * h + 1e-9, h), 0.0);
+            }
+        }
+    }
+
+    const SAMPLES: usize = 5000;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() <= 1e-9 * (1.0 + a.abs().max(b.abs()))
+    }
+
+    /// Value, gradient and Laplacian of the kernel centred at the origin,
+    /// by differentiating along each coordinate of `position` in turn
+    fn differentiate<P: Profile>(profile: &P, position: &[f64], h: f64) -> (f64, Vec<f64>, f64) {
+        let (mut value, mut gradient, mut laplacian) = (0.0, Vec::new(), 0.0);
+        for axis in 0..position.len() {
+            let mut r2 = Dual::constant(0.0);
+            for (k, &x) in position.iter().enumerate() {
+                let x = if k == axis {
+                    Dual::variable(x)
+                } else {
+                    Dual::constant(x)
+                };
+                r2 = r2 + x * x;
+            }
+            let w = profile.value_of(r2.sqrt(), h);
+            value = w.value;
+            gradient.push(w.first);
+            laplacian += w.second;
+        }
+        (value, gradient, laplacian)
+    }
+
+    /// Compare the hand derived derivatives of a kernel with automatic
+    /// differentiation at random points in and around its support
+    fn check_against_dual<P: Profile>(profile: P) {
+        let d = profile.dimension() as usize;
+        let mut random = Random::new(17);
+        for _ in 0..SAMPLES {
+            let h = 0.2 + random.next();
+            let position: Vec<f64> = (0..d)
+                .map(|_| 1.2 * SUPPORT * h * (2.0 * random.next() - 1.0))
+                .collect();
+            let r = position.iter().map(|x| x * x).sum::<f64>().sqrt();
+            if r < 0.05 * SUPPORT * h {
+                continue;
+            }
+            let (value, gradient, laplacian) = differentiate(&profile, &position, h);
+            assert!(close(profile.value(r, h), value));
+            for (x, grad) in position.iter().zip(gradient.iter()) {
+                assert!(close(profile.derivative(r, h) * x / r, *grad));
+            }
+            if d == 2 {
+                let (gx, gy) = profile.gradient(position[0], position[1], h);
+                assert!(close(gx, gradient[0]) && close(gy, gradient[1]));
+            }
+            assert!(close(profile.laplacian(r, h), laplacian));
+        }
+    }
+
+    #[test]
+    fn test_derivatives_match_dual_numbers() {
+        for &dimension in [Dimension::Two, Dimension::Three].iter() {
+            check_against_dual(CubicSpline(dimension));
+            check_against_dual(WendlandC2(dimension));
+            check_against_dual(WendlandC4(dimension));
+            check_against_dual(WendlandC6(dimension));
+            check_against_dual(Poly6(dimension));
+            check_against_dual(Spiky(dimension));
+            check_against_dual(Viscosity(dimension));
+        }
+    }
+
+    #[test]
+    fn test_wrappers_match_dual_numbers() {
+        let mut random = Random::new(5);
+        for _ in 0..SAMPLES {
+            let h = 0.2 + random.next();
+            let (x, y) = (2.5 * h * random.next(), 2.5 * h * random.next());
+            let r = math::length(x, y);
+            let (value, gradient, laplacian) =
+                differentiate(&WendlandC2(Dimension::Two), &[x, y], h);
+            let (gx, gy) = grad_kernel_2d(x, y, h);
+            assert!(close(kernel_2d(r, h), value));
+            assert!(close(gx, gradient[0]) && close(gy, gradient[1]));
+            assert!(close(laplace_kernel_2d(r, h), laplacian));
+        }
+    }
+
+    #[test]
+    fn test_normalisation() {
+        let n = 100_000;
+        let h = 0.8;
+        let dr = SUPPORT * h / n as f64;
+        for &dimension in [Dimension::Two, Dimension::Three].iter() {
+            let mut kernels = all_kernels(dimension);
+            kernels.push(Box::new(Muller(dimension)));
+            for kernel in kernels {
+                let integral: f64 = (0..n)
+                    .map(|i| {
+                        let r = (i as f64 + 0.5) * dr;
+                        let shell = match dimension {
+                            Dimension::Two => 2.0 * PI * r,
+                            Dimension::Three => 4.0 * PI * r * r,
+                        };
+                        kernel.value(r, h) * shell * dr
+                    })
+                    .sum();
+                assert!((integral - 1.0).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn test_muller_uses_the_trio() {
+        let kernel = Muller(Dimension::Three);
+        let h = 0.5;
+        assert_eq!(kernel.value(0.3, h), Poly6(Dimension::Three).value(0.3, h));
+        assert_eq!(
+            kernel.gradient(0.3, 0.4, h),
+            Spiky(Dimension::Three).gradient(0.3, 0.4, h)
+        );
+        // 45 / (pi R^6) (R - r) in three dimensions
+        let radius = SUPPORT * h;
+        let expected = 45.0 / PI / radius.powi(6) * (radius - 0.3);
+        assert!((kernel.laplacian(0.3, h) - expected).abs() < 1e-12);
+        assert_eq!("muller".parse::<KernelType>(), Ok(KernelType::Muller));
+    }
+
+    #[test]
+    fn test_laplacian_at_origin() {
+        let h = 0.5;
+        for &dimension in [Dimension::Two, Dimension::Three].iter() {
+            let flat: Vec<Box<dyn Kernel>> = vec![
+                Box::new(CubicSpline(dimension)),
+                Box::new(WendlandC2(dimension)),
+                Box::new(WendlandC4(dimension)),
+                Box::new(WendlandC6(dimension)),
+                Box::new(Poly6(dimension)),
+            ];
+            for kernel in flat {
+                let origin = kernel.laplacian(0.0, h);
+                let close = kernel.laplacian(1e-6, h);
+                assert!((origin - close).abs() < 1e-4 * origin.abs());
+            }
+            assert_eq!(Spiky(dimension).laplacian(0.0, h), f64::NEG_INFINITY);
+            assert!(Spiky(dimension).laplacian(1e-6, h) < -1e5);
+        }
+        assert_eq!(Viscosity(Dimension::Two).laplacian(0.0, h), f64::INFINITY);
+        assert!(Viscosity(Dimension::Two).laplacian(1e-6, h) > 1e5);
+        let viscosity = Viscosity(Dimension::Three);
+        let origin = viscosity.laplacian(0.0, h);
+        assert!((origin - viscosity.laplacian(1e-4, h)).abs() < 1e-3 * origin);
+    }
 }
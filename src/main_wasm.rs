@@ -7,10 +7,30 @@ use stdweb::web::html_element::CanvasElement;
 use stdweb::web::{self, INonElementParentNode, TypedArray};
 use webgl_stdweb::{ANGLE_instanced_arrays, WebGLBuffer, WebGLRenderingContext as GL};
 
+mod adaptive;
+mod correction;
+mod density;
+mod diagnostics;
+mod dual;
 mod grid;
+mod hydrostatic;
+mod integrators;
+mod kdtree;
+mod kernel_table;
 mod kernels;
 mod math;
+mod neighbour_list;
+mod neighbours;
+mod packing;
+mod parallel;
+mod scene;
+mod shifting;
+mod sleeping;
 mod sph;
+mod surface;
+mod turbulence;
+mod vorticity;
+mod walls;
 
 macro_rules! log {
     ($message:expr) => {
@@ -105,9 +125,11 @@ fn main() {
     let tex_coord_data = TypedArray::<f32>::from(&tex_coord_internal[..]).buffer();
     ctx.buffer_data_1(GL::ARRAY_BUFFER, Some(&tex_coord_data), GL::STATIC_DRAW);
 
+    let state = sph::create_initial_state(sph::Parameters::new());
+
     let offset_buffer = ctx.create_buffer();
     ctx.bind_buffer(GL::ARRAY_BUFFER, offset_buffer.as_ref());
-    let offset_data = TypedArray::<f32>::from(&[0.0; sph::N_PARTICLES as usize * 2][..]).buffer();
+    let offset_data = TypedArray::<f32>::from(&vec![0.0; state.particles.len() * 2][..]).buffer();
     ctx.buffer_data_1(GL::ARRAY_BUFFER, Some(&offset_data), GL::STATIC_DRAW);
 
     // Create vertex shader
@@ -175,9 +197,7 @@ fn main() {
 
     ctx.clear_color(0.0, 0.0, 0.0, 1.0);
 
-    // ext.draw_elements_instanced_angle(GL::TRIANGLES, 6, GL::UNSIGNED_SHORT, 0, sph::N_PARTICLES as i32);
-
-    let state = sph::create_initial_state();
+    // ext.draw_elements_instanced_angle(GL::TRIANGLES, 6, GL::UNSIGNED_SHORT, 0, state.particles.len() as i32);
 
     // ctx.viewport(0, 0, width as i32, height as i32);
     let canvas_holder = Canvas {
@@ -193,10 +213,11 @@ fn main() {
 }
 
 fn main_loop(canvas: Canvas, mut state: sph::State, _dt: f64) {
-    let (_grid, _debug) = sph::update_state(&mut state, DT, sph::SPHDebug::new());
+    let _debug = sph::update_state(&mut state, DT, sph::SPHDebug::new());
 
-    let mut offsets = [0.0; sph::N_PARTICLES as usize * 2];
-    for i in 0..(sph::N_PARTICLES as usize) {
+    let n_particles = state.particles.len();
+    let mut offsets = vec![0.0; n_particles * 2];
+    for i in 0..n_particles {
         let x = state.particles[i].x;
         let y = state.particles[i].y;
         offsets[2 * i] = ((((x - sph::MIN_X) / (sph::MAX_X - sph::MIN_X)) - 0.5) * 2.0) as f32;
@@ -208,7 +229,7 @@ fn main_loop(canvas: Canvas, mut state: sph::State, _dt: f64) {
             .ctx
             .get_uniform_location(&canvas.shader, "size")
             .as_ref(),
-        0.5 * sph::H as f32,
+        0.5 * state.parameters.h as f32,
     );
     canvas
         .ctx
@@ -225,7 +246,7 @@ fn main_loop(canvas: Canvas, mut state: sph::State, _dt: f64) {
         6,
         GL::UNSIGNED_SHORT,
         0,
-        sph::N_PARTICLES as i32,
+        n_particles as i32,
     );
 
     let mut offsets = [0.0; 2];
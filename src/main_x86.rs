@@ -10,32 +10,53 @@ use std::io::{stdout, Write};
 use std::{thread, time};
 use termion::raw::IntoRawMode;
 
+mod adaptive;
+mod bench;
+mod correction;
+mod density;
+mod diagnostics;
 mod dto;
+mod dual;
+#[cfg(test)]
+mod fixtures;
 mod grid;
+mod hydrostatic;
+mod integrators;
+mod kdtree;
+mod kernel_table;
 mod kernels;
+mod mask;
 mod math;
+mod neighbour_list;
+mod neighbours;
+mod packing;
+mod parallel;
+mod scene;
+mod shifting;
+mod sleeping;
 mod sph;
+mod surface;
+mod turbulence;
+mod vorticity;
+mod walls;
 
 const DT: f64 = 0.0005;
 const WIDTH: u16 = 100;
 const HEIGHT: u16 = 30;
 
 #[cfg(target_arch = "x86_64")]
-fn render_state(
-    stdout: &mut std::io::Stdout,
-    state: &sph::State,
-    grid: &grid::Grid,
-    debug: sph::SPHDebug,
-) {
+fn render_state(stdout: &mut std::io::Stdout, state: &sph::State, debug: sph::SPHDebug) {
     let width = WIDTH;
     let height = HEIGHT;
+    let domain = &state.parameters.domain;
     for y in 0..height {
         for x in 0..width {
             let density = sph::density(
                 &state.particles,
-                &grid,
-                sph::MIN_X + x as f64 * (sph::MAX_X - sph::MIN_X) / width as f64,
-                sph::MIN_Y + y as f64 * (sph::MAX_Y - sph::MIN_Y) / height as f64,
+                &state.search,
+                &state.parameters,
+                domain.min_x + x as f64 * (domain.max_x - domain.min_x) / width as f64,
+                domain.min_y + y as f64 * (domain.max_y - domain.min_y) / height as f64,
             );
             let mut norm_density = (9. * density / (debug.max_density)).round() as i32;
             if norm_density > 9 {
@@ -76,21 +97,80 @@ fn render_state(
     );
     write!(
         stdout,
-        "{}H: {}",
+        "{}H: {} Grid width: {}",
         termion::cursor::Goto(1, height + 4),
-        debug.h
+        debug.h,
+        debug.grid_width
     );
+    // Like the lines above, a status line the terminal refuses is skipped
+    let _ = render_diagnostics(stdout, state, debug, height + 5);
+}
+
+/// The conservation diagnostics and solver counters, from row `top` down
+fn render_diagnostics(
+    stdout: &mut std::io::Stdout,
+    state: &sph::State,
+    debug: sph::SPHDebug,
+    top: u16,
+) -> std::io::Result<()> {
+    write!(
+        stdout,
+        "{}Energy: {:.6e} (drift {:+.3e})",
+        termion::cursor::Goto(1, top),
+        debug.diagnostics.total_energy(),
+        debug.energy_drift
+    )?;
+    write!(
+        stdout,
+        "{}Momentum: ({:.4e}, {:.4e}) Angular momentum: {:.4e}",
+        termion::cursor::Goto(1, top + 1),
+        debug.drift.momentum_x,
+        debug.drift.momentum_y,
+        debug.drift.angular_momentum
+    )?;
+    write!(
+        stdout,
+        "{}Mass: {} Duck energy: {:.6e}",
+        termion::cursor::Goto(1, top + 2),
+        debug.diagnostics.total_mass,
+        debug.diagnostics.duck_energy
+    )?;
+    write!(
+        stdout,
+        "{}Integrator: {:?}",
+        termion::cursor::Goto(1, top + 3),
+        state.parameters.integrator
+    )?;
+    write!(
+        stdout,
+        "{}Neighbour list rebuilds: {} ({:.1}% of evaluations) Escaped particles: {}",
+        termion::cursor::Goto(1, top + 4),
+        debug.neighbour_list_rebuilds,
+        100.0 * debug.neighbour_list_rebuild_rate,
+        debug.escaped_particles
+    )?;
+    write!(
+        stdout,
+        "{}Active particles: {} of {}",
+        termion::cursor::Goto(1, top + 5),
+        debug.active_particles,
+        state.particles.len()
+    )?;
+    Ok(())
 }
 
-fn render_png(state: &sph::State, grid: &grid::Grid, debug: sph::SPHDebug, frame: u32, size: u32) {
-    fs::create_dir("output");
+fn render_png(state: &sph::State, debug: sph::SPHDebug, frame: u32, size: u32) {
+    // Already there after the first frame
+    let _ = fs::create_dir("output");
     let mut img = image::GrayImage::new(size, size);
+    let domain = &state.parameters.domain;
     for (x, y, pixel) in img.enumerate_pixels_mut() {
         let density = sph::density(
             &state.particles,
-            &grid,
-            x as f64 * (sph::MAX_X - sph::MIN_X) / size as f64,
-            y as f64 * (sph::MAX_Y - sph::MIN_Y) / size as f64,
+            &state.search,
+            &state.parameters,
+            domain.min_x + x as f64 * (domain.max_x - domain.min_x) / size as f64,
+            domain.min_y + y as f64 * (domain.max_y - domain.min_y) / size as f64,
         );
         let mut norm_density = (255. * density / (debug.max_density)).round();
         if norm_density > 255.0 {
@@ -106,8 +186,40 @@ fn render_png(state: &sph::State, grid: &grid::Grid, debug: sph::SPHDebug, frame
     println!("{}", filename);
 }
 
+/// Signed vorticity in red (counter-clockwise) and blue (clockwise), relative
+/// to the largest magnitude of any particle
+fn render_vorticity_png(state: &sph::State, frame: u32, size: u32) {
+    // Already there after the first frame
+    let _ = fs::create_dir("output");
+    let max_vorticity = state
+        .particles
+        .iter()
+        .fold(0.0, |max: f64, particle| max.max(particle.vorticity.abs()));
+    let mut img = image::RgbImage::new(size, size);
+    let domain = &state.parameters.domain;
+    for (x, y, pixel) in img.enumerate_pixels_mut() {
+        let vorticity = sph::vorticity(
+            &state.particles,
+            &state.search,
+            &state.parameters,
+            domain.min_x + x as f64 * (domain.max_x - domain.min_x) / size as f64,
+            domain.min_y + y as f64 * (domain.max_y - domain.min_y) / size as f64,
+        );
+        let level = (255.0 * vorticity.abs() / max_vorticity.max(f64::MIN_POSITIVE)).min(255.0);
+        *pixel = if vorticity > 0.0 {
+            image::Rgb([level as u8, 0, 0])
+        } else {
+            image::Rgb([0, 0, level as u8])
+        };
+    }
+    let filename = format!("output/image{:04}.png", frame);
+    img.save(&filename).unwrap();
+    println!("{}", filename);
+}
+
 fn dump_dto(state: &sph::State, frame: u32) {
-    fs::create_dir("dto");
+    // Already there after the first frame
+    let _ = fs::create_dir("dto");
     let filename = format!("dto/frame{:04}.dto", frame);
     let mut file = File::create(filename).unwrap();
     dto::write_to_io(&state.particles[..], &mut file).unwrap();
@@ -115,49 +227,238 @@ fn dump_dto(state: &sph::State, frame: u32) {
 
 enum Mode {
     Terminal,
-    Image { size: u32 },
+    Image { size: u32, vorticity: bool },
     DtoDump,
+    Bench { name: Option<String> },
+}
+
+/// Remove `--name value` from the arguments and return the value
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let index = args.iter().position(|arg| arg == name)?;
+    if index + 1 >= args.len() {
+        panic!("Missing value for {}", name);
+    }
+    let value = args.remove(index + 1);
+    args.remove(index);
+    Some(value)
+}
+
+fn handle_parameters(args: &mut Vec<String>) -> sph::Parameters {
+    let mut parameters = sph::Parameters::new();
+    if let Some(resolution) = take_option(args, "--resolution") {
+        parameters = sph::Parameters::with_resolution(resolution.parse().unwrap());
+    }
+    if let Some(scene) = take_option(args, "--scene") {
+        match scene.parse() {
+            Ok(scene) => {
+                if scene == sph::Scene::Stretching {
+                    parameters.gravity = 0.0;
+                }
+                parameters.scene = scene
+            }
+            Err(_) if scene.ends_with(".png") => mask::load_mask(&scene, parameters.domain)
+                .and_then(|description| description.apply(&mut parameters))
+                .unwrap(),
+            Err(_) => scene::SceneDescription::load(&scene)
+                .and_then(|description| description.apply(&mut parameters))
+                .unwrap(),
+        }
+    }
+    if let Some(packing) = take_option(args, "--packing") {
+        parameters.packing = packing.parse().unwrap();
+    }
+    if let Some(seed) = take_option(args, "--seed") {
+        if let packing::Packing::Jittered { seed: jitter_seed } = &mut parameters.packing {
+            *jitter_seed = seed.parse().unwrap();
+        }
+    }
+    if let Some(tolerance) = take_option(args, "--relax") {
+        let mut relaxation = packing::Relaxation::new(tolerance.parse().unwrap());
+        if let Some(index) = args.iter().position(|arg| arg == "--relax-hydrostatic") {
+            args.remove(index);
+            relaxation.hydrostatic = true;
+        }
+        parameters.relaxation = Some(relaxation);
+    }
+    if let Some(index) = args.iter().position(|arg| arg == "--hydrostatic") {
+        args.remove(index);
+        parameters.hydrostatic = true;
+        // Keeps the hydrostatic densities, unless a later --density asks for
+        // summation, which `check` rejects
+        parameters.density_method = density::DensityMethod::Continuity;
+    }
+    if let Some(index) = args.iter().position(|arg| arg == "--wall-images") {
+        args.remove(index);
+        parameters.wall_images = true;
+    }
+    if let Some(integrator) = take_option(args, "--integrator") {
+        parameters.integrator = integrator.parse().unwrap();
+    }
+    if let Some(kernel) = take_option(args, "--kernel") {
+        parameters.kernel = kernel.parse().unwrap();
+    }
+    if let Some(ratio) = take_option(args, "--smoothing-length") {
+        parameters.h = ratio.parse::<f64>().unwrap() * parameters.particle_spacing();
+    }
+    if let Some(size) = take_option(args, "--table") {
+        let interpolation = take_option(args, "--interpolation").unwrap_or("cubic".to_string());
+        let size: usize = size.parse().unwrap();
+        if size < 2 {
+            panic!("--table needs at least 2 samples, got {}", size);
+        }
+        parameters.tabulate(size, interpolation.parse().unwrap());
+    }
+    if let Some(correction) = take_option(args, "--gradient-correction") {
+        parameters.gradient_correction = correction.parse().unwrap();
+    }
+    if let Some(index) = args.iter().position(|arg| arg == "--shepard") {
+        args.remove(index);
+        parameters.shepard_density = true;
+    }
+    if let Some(index) = args.iter().position(|arg| arg == "--symmetric") {
+        args.remove(index);
+        parameters.symmetric_forces = true;
+    }
+    if let Some(method) = take_option(args, "--density") {
+        parameters.density_method = method.parse().unwrap();
+    }
+    if let Some(interval) = take_option(args, "--shepard-interval") {
+        parameters.shepard_interval = Some(interval.parse().unwrap());
+    }
+    if let Some(diffusion) = take_option(args, "--density-diffusion") {
+        parameters.density_diffusion = diffusion.parse().unwrap();
+    }
+    if let Some(coefficient) = take_option(args, "--diffusion-coefficient") {
+        parameters.diffusion_coefficient = coefficient.parse().unwrap();
+    }
+    if let Some(coefficient) = take_option(args, "--shifting") {
+        parameters.shifting = Some(coefficient.parse().unwrap());
+    }
+    if let Some(detection) = take_option(args, "--surface") {
+        parameters.surface_detection = detection.parse().unwrap();
+    }
+    if let Some(index) = args.iter().position(|arg| arg == "--vorticity") {
+        args.remove(index);
+        parameters.vorticity = true;
+    }
+    if let Some(strength) = take_option(args, "--vorticity-confinement") {
+        parameters.vorticity_confinement = Some(strength.parse().unwrap());
+    }
+    if let Some(constant) = take_option(args, "--smagorinsky") {
+        parameters.smagorinsky = Some(constant.parse().unwrap());
+    }
+    if let Some(index) = args.iter().position(|arg| arg == "--sleeping") {
+        args.remove(index);
+        parameters.sleeping = Some(sleeping::Sleeping::new());
+    }
+    if let Some(index) = args.iter().position(|arg| arg == "--adaptive") {
+        args.remove(index);
+        // After --scene, which may move the walls
+        parameters.adaptive = Some(adaptive::AdaptiveResolution::new(&parameters.domain));
+    }
+    if let Some(epsilon) = take_option(args, "--artificial-pressure") {
+        let exponent =
+            take_option(args, "--artificial-pressure-exponent").unwrap_or("4".to_string());
+        parameters.artificial_pressure = Some(sph::ArtificialPressure {
+            epsilon: epsilon.parse().unwrap(),
+            exponent: exponent.parse().unwrap(),
+        });
+    }
+    if let Some(skin) = take_option(args, "--skin") {
+        parameters.skin = Some(skin.parse().unwrap());
+    }
+    if let Some(search) = take_option(args, "--search") {
+        parameters.search = search.parse().unwrap();
+    }
+    if let Some(backend) = take_option(args, "--grid") {
+        parameters.grid_backend = backend.parse().unwrap();
+    }
+    if let Some(ordering) = take_option(args, "--reorder") {
+        parameters.reorder = Some(ordering.parse().unwrap());
+    }
+    if let Some(interval) = take_option(args, "--reorder-interval") {
+        parameters.reorder_interval = interval.parse().unwrap();
+    }
+    if let Some(threads) = take_option(args, "--threads") {
+        parameters.threads = threads.parse().unwrap();
+    }
+    if let Some(index) = args.iter().position(|arg| arg == "--serial") {
+        args.remove(index);
+        parameters.parallel = false;
+    }
+    if let Err(error) = parameters.check() {
+        panic!("{}", error);
+    }
+    parameters
 }
 
-fn handle_args() -> Mode {
-    let args: Vec<String> = env::args().collect();
+fn handle_args() -> (Mode, sph::Parameters) {
+    let mut args: Vec<String> = env::args().collect();
+    let mut parameters = handle_parameters(&mut args);
     let mut mode = Mode::Terminal;
     if args.len() >= 2 && args[1] == "dump" {
         mode = Mode::DtoDump;
     }
-    if args.len() == 3 {
+    if args.len() >= 2 && args[1] == "bench" {
+        mode = Mode::Bench {
+            name: args.get(2).cloned(),
+        };
+    }
+    if args.len() == 3 || args.len() == 4 {
         if args[1] == "image" {
+            let vorticity = args.get(3).map(String::as_str) == Some("vorticity");
+            // Colouring by vorticity needs it computed on every step
+            parameters.vorticity |= vorticity;
             mode = Mode::Image {
                 size: args[2].parse().unwrap(),
+                vorticity,
             };
         }
     }
-    return mode;
+    return (mode, parameters);
 }
 
 fn main() {
     let mut stdout = stdout(); //.into_raw_mode().unwrap();
                                //write!(stdout, "{}", termion::clear::All);
-    let mut state = sph::create_initial_state();
+    let (mode, parameters) = handle_args();
+    if let Mode::Bench { name } = &mode {
+        bench::run(name.as_deref(), &parameters);
+        return;
+    }
+    let mut state = sph::create_initial_state(parameters);
+    if let (Some(report), Some(relaxation)) = (&state.relaxation, &state.parameters.relaxation) {
+        println!(
+            "Relaxed for {} iterations to a residual of {:.3e}",
+            report.iterations, report.residual
+        );
+        if !report.converged(relaxation) {
+            println!(
+                "Warning: relaxation stopped above the tolerance of {:.3e}",
+                relaxation.tolerance
+            );
+        }
+    }
     let mut frame = 0;
-    let mode = handle_args();
     loop {
         let t1 = time::Instant::now();
-        let (grid, debug) = sph::update_state(&mut state, DT, sph::SPHDebug::new());
+        let debug = sph::update_state(&mut state, DT, sph::SPHDebug::new());
         let frame_time = t1.elapsed().as_micros();
         match mode {
             Mode::Terminal => render_state(
                 &mut stdout,
                 &state,
-                &grid,
                 sph::SPHDebug {
                     frame_time,
                     ..debug
                 },
             ),
-            Mode::Image { size } => render_png(
+            Mode::Image {
+                size,
+                vorticity: true,
+            } => render_vorticity_png(&state, frame, size),
+            Mode::Image { size, .. } => render_png(
                 &state,
-                &grid,
                 sph::SPHDebug {
                     frame_time,
                     ..debug
@@ -166,6 +467,7 @@ fn main() {
                 size,
             ),
             Mode::DtoDump => dump_dto(&state, frame),
+            Mode::Bench { .. } => unreachable!(),
         }
         frame += 1;
     }
@@ -0,0 +1,250 @@
+//! Scenes from bitmap masks drawn in a paint program. The image is stretched
+//! over the domain with its top edge at `min_y`, so down the image is down
+//! the tank, and each pixel is fluid, solid, duck or empty by its colour.
+
+use crate::scene::{Body, Domain, Fluid, Obstacle, SceneDescription, Solver};
+use crate::sph::GRAVITY;
+
+/// Colours of the materials. Pixels within `COLOUR_TOLERANCE` of none of
+/// them on every channel are empty.
+pub const FLUID_COLOUR: [u8; 3] = [0, 0, 255];
+pub const SOLID_COLOUR: [u8; 3] = [0, 0, 0];
+pub const DUCK_COLOUR: [u8; 3] = [255, 255, 0];
+const COLOUR_TOLERANCE: u8 = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Material {
+    Empty,
+    Fluid,
+    Solid,
+    Duck,
+}
+
+fn material(pixel: &image::Rgb<u8>) -> Material {
+    let matches = |colour: [u8; 3]| {
+        pixel
+            .0
+            .iter()
+            .zip(colour.iter())
+            .all(|(a, b)| a.max(b) - a.min(b) <= COLOUR_TOLERANCE)
+    };
+    if matches(FLUID_COLOUR) {
+        Material::Fluid
+    } else if matches(SOLID_COLOUR) {
+        Material::Solid
+    } else if matches(DUCK_COLOUR) {
+        Material::Duck
+    } else {
+        Material::Empty
+    }
+}
+
+/// Runs of solid pixels merged into as few rectangles as rows allow, as
+/// (first column, first row, last column + 1, last row + 1)
+fn solid_rectangles(solid: &[bool], columns: u32) -> Vec<(u32, u32, u32, u32)> {
+    let rows = solid.len() as u32 / columns;
+    let mut rectangles = Vec::new();
+    // Rectangles still growing downwards, as (first column, end column, first row)
+    let mut open: Vec<(u32, u32, u32)> = Vec::new();
+    for row in 0..rows {
+        let line = &solid[(row * columns) as usize..((row + 1) * columns) as usize];
+        let mut growing = Vec::new();
+        let mut column = 0;
+        while column < columns {
+            if !line[column as usize] {
+                column += 1;
+                continue;
+            }
+            let start = column;
+            while column < columns && line[column as usize] {
+                column += 1;
+            }
+            match open
+                .iter()
+                .position(|&(first, end, _)| (first, end) == (start, column))
+            {
+                Some(index) => growing.push(open.remove(index)),
+                None => growing.push((start, column, row)),
+            }
+        }
+        for (first, end, first_row) in open.drain(..) {
+            rectangles.push((first, first_row, end, row));
+        }
+        open = growing;
+    }
+    for (first, end, first_row) in open {
+        rectangles.push((first, first_row, end, rows));
+    }
+    rectangles
+}
+
+/// Scene over `domain` with the fluid pixels filled on the lattice of the
+/// parameters, the solid pixels as rectangular obstacles and the duck at the
+/// centre of its pixels, or where it starts by default without any
+pub fn scene_from_mask(mask: &image::RgbImage, domain: Domain) -> Result<SceneDescription, String> {
+    let (columns, rows) = mask.dimensions();
+    let materials: Vec<Material> = mask.pixels().map(material).collect();
+    let fluid: Vec<bool> = materials.iter().map(|&m| m == Material::Fluid).collect();
+    if !fluid.contains(&true) {
+        return Err("Mask has no fluid pixels".to_string());
+    }
+    let pixel_width = (domain.max_x - domain.min_x) / columns as f64;
+    let pixel_height = (domain.max_y - domain.min_y) / rows as f64;
+    let x = |column: u32| domain.min_x + column as f64 * pixel_width;
+    let y = |row: u32| domain.min_y + row as f64 * pixel_height;
+
+    // Solids on the border extend past the walls, so that particles are only
+    // ever pushed out of them into the tank
+    let margin = (domain.max_x - domain.min_x).max(domain.max_y - domain.min_y);
+    let extend = |value: u32, limit: u32, sign: f64| {
+        if value == limit {
+            sign * margin
+        } else {
+            0.0
+        }
+    };
+    let solid: Vec<bool> = materials.iter().map(|&m| m == Material::Solid).collect();
+    let obstacles = solid_rectangles(&solid, columns)
+        .into_iter()
+        .map(|(first, first_row, end, end_row)| Obstacle::Rectangle {
+            min_x: x(first) + extend(first, 0, -1.0),
+            min_y: y(first_row) + extend(first_row, 0, -1.0),
+            max_x: x(end) + extend(end, columns, 1.0),
+            max_y: y(end_row) + extend(end_row, rows, 1.0),
+        })
+        .collect();
+
+    let (mut duck_x, mut duck_y, mut duck_pixels) = (0.0, 0.0, 0);
+    for (index, &m) in materials.iter().enumerate() {
+        if m == Material::Duck {
+            let (column, row) = (index as u32 % columns, index as u32 / columns);
+            duck_x += x(column) + 0.5 * pixel_width;
+            duck_y += y(row) + 0.5 * pixel_height;
+            duck_pixels += 1;
+        }
+    }
+    let duck = if duck_pixels > 0 {
+        Body {
+            x: duck_x / duck_pixels as f64,
+            y: duck_y / duck_pixels as f64,
+            vx: 0.0,
+            vy: 0.0,
+        }
+    } else {
+        Body::duck()
+    };
+
+    Ok(SceneDescription {
+        domain,
+        fluid: vec![Fluid::Bitmap {
+            min_x: domain.min_x,
+            min_y: domain.min_y,
+            max_x: domain.max_x,
+            max_y: domain.max_y,
+            columns,
+            pixels: fluid,
+            spacing: None,
+        }],
+        obstacles,
+        duck,
+        gravity: GRAVITY,
+        solver: Solver::default(),
+    })
+}
+
+/// Scene from the image file at `path`, see `scene_from_mask`
+pub fn load_mask(path: &str, domain: Domain) -> Result<SceneDescription, String> {
+    let mask =
+        image::open(path).map_err(|error| format!("Cannot read mask {}: {}", path, error))?;
+    scene_from_mask(&mask.to_rgb8(), domain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::sph::{self, Parameters};
+
+    /// 10 x 10 mask of empty space over two rows of fluid, with a solid step
+    /// in the bottom right corner and a 2 x 2 duck
+    fn mask() -> image::RgbImage {
+        image::RgbImage::from_fn(10, 10, |column, row| {
+            let colour = if row >= 8 && column >= 7 {
+                SOLID_COLOUR
+            } else if row >= 8 {
+                FLUID_COLOUR
+            } else if (4..6).contains(&column) && (2..4).contains(&row) {
+                DUCK_COLOUR
+            } else {
+                [250, 250, 250]
+            };
+            image::Rgb(colour)
+        })
+    }
+
+    fn domain() -> Domain {
+        Domain {
+            min_x: 0.0,
+            min_y: 0.0,
+            max_x: 5.0,
+            max_y: 5.0,
+        }
+    }
+
+    #[test]
+    fn test_scene_from_mask() {
+        let scene = scene_from_mask(&mask(), domain()).unwrap();
+        assert_eq!((scene.duck.x, scene.duck.y), (2.5, 1.5));
+        // The step is one rectangle, extended past the right wall and floor
+        assert_eq!(
+            scene.obstacles,
+            vec![Obstacle::Rectangle {
+                min_x: 3.5,
+                min_y: 4.0,
+                max_x: 10.0,
+                max_y: 10.0,
+            }]
+        );
+
+        let mut parameters = Parameters {
+            parallel: false,
+            ..Parameters::new()
+        };
+        scene.apply(&mut parameters).unwrap();
+        let state = sph::create_initial_state(parameters);
+        assert!(!state.particles.is_empty());
+        for particle in state.particles.iter() {
+            assert!(particle.x < 3.5 && particle.y > 4.0);
+        }
+        // The fluid pixels are filled at the particle spacing
+        let area = 3.5 * 1.0;
+        let expected = area / state.parameters.particle_spacing().powi(2);
+        let count = state.particles.len() as f64;
+        assert!(
+            (count - expected).abs() < 0.2 * expected,
+            "{} {}",
+            count,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_solid_runs_merge() {
+        // Two rows of an L: the shared run merges, the foot stays separate
+        let solid = [
+            true, true, false, false, //
+            true, true, false, false, //
+            true, true, true, true,
+        ];
+        assert_eq!(
+            solid_rectangles(&solid, 4),
+            vec![(0, 0, 2, 2), (0, 2, 4, 3)]
+        );
+    }
+
+    #[test]
+    fn test_mask_without_fluid() {
+        let empty = image::RgbImage::from_pixel(4, 4, image::Rgb([255, 255, 255]));
+        assert!(scene_from_mask(&empty, domain()).is_err());
+    }
+}
@@ -1,3 +1,25 @@
 pub fn length(x: f64, y: f64) -> f64 {
     return f64::sqrt(x.powi(2) + y.powi(2));
 }
+
+/// Golden angle in radians, pi (3 - sqrt 5). Multiples of it spread
+/// directions evenly without repeating, for deterministic jitter.
+pub const GOLDEN_ANGLE: f64 = 2.399963229728653;
+
+/// Xorshift generator of uniform numbers in [0, 1), deterministic without an
+/// extra dependency
+pub struct Random(u64);
+
+impl Random {
+    pub fn new(seed: u64) -> Random {
+        // Xorshift is stuck at zero
+        Random(seed.max(1))
+    }
+
+    pub fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
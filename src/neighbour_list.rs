@@ -0,0 +1,159 @@
+use crate::neighbours::NeighbourSearch;
+use crate::parallel;
+use crate::sph::Particle;
+
+/// Verlet neighbour list: for every particle, all particles within the
+/// interaction radius plus a skin. The list stays valid until some particle
+/// has moved more than half the skin since it was built.
+pub struct NeighbourList {
+    skin: f64,
+    /// Positions at the last rebuild
+    reference: Vec<(f64, f64)>,
+    /// Neighbours of particle i are `indices[offsets[i]..offsets[i + 1]]`
+    offsets: Vec<usize>,
+    indices: Vec<u32>,
+    pub rebuilds: u64,
+    pub evaluations: u64,
+}
+
+impl NeighbourList {
+    pub fn new(skin: f64) -> NeighbourList {
+        NeighbourList {
+            skin,
+            reference: Vec::new(),
+            offsets: vec![0],
+            indices: Vec::new(),
+            rebuilds: 0,
+            evaluations: 0,
+        }
+    }
+
+    pub fn skin(&self) -> f64 {
+        self.skin
+    }
+
+    /// Fraction of evaluations that had to rebuild the list
+    pub fn rebuild_rate(&self) -> f64 {
+        if self.evaluations == 0 {
+            return 0.0;
+        }
+        self.rebuilds as f64 / self.evaluations as f64
+    }
+
+    /// Force a rebuild at the next evaluation, e.g. after the particles
+    /// have been reordered
+    pub fn invalidate(&mut self) {
+        self.reference.clear();
+    }
+
+    pub fn needs_rebuild(&self, particles: &[Particle]) -> bool {
+        if particles.len() != self.reference.len() {
+            return true;
+        }
+        let limit = (0.5 * self.skin).powi(2);
+        particles
+            .iter()
+            .zip(self.reference.iter())
+            .any(|(particle, (x, y))| (particle.x - x).powi(2) + (particle.y - y).powi(2) > limit)
+    }
+
+    /// Rebuild from a search that finds everything within `radius + skin`
+    pub fn build(
+        &mut self,
+        particles: &[Particle],
+        search: &impl NeighbourSearch,
+        radius: f64,
+        parallel: bool,
+    ) {
+        let cutoff = (radius + self.skin).powi(2);
+        let lists = parallel::map(particles.len(), parallel, |i| {
+            let particle1 = &particles[i];
+            let mut list = Vec::new();
+            search.for_each_neighbour(particle1.x, particle1.y, |j| {
+                let particle2 = &particles[j as usize];
+                if (particle1.x - particle2.x).powi(2) + (particle1.y - particle2.y).powi(2)
+                    <= cutoff
+                {
+                    list.push(j);
+                }
+            });
+            list
+        });
+        self.offsets.clear();
+        self.offsets.push(0);
+        self.indices.clear();
+        for list in lists {
+            self.indices.extend(list);
+            self.offsets.push(self.indices.len());
+        }
+        self.reference.clear();
+        self.reference
+            .extend(particles.iter().map(|particle| (particle.x, particle.y)));
+        self.rebuilds += 1;
+    }
+
+    /// Call `f` with every particle in the list of particle `i`, including `i`
+    pub fn for_each_neighbour(&self, i: usize, mut f: impl FnMut(u32)) {
+        for &j in &self.indices[self.offsets[i]..self.offsets[i + 1]] {
+            f(j);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::grid;
+    use crate::sph::Parameters;
+
+    fn particles() -> Vec<Particle> {
+        let parameters = Parameters::new();
+        let mut particles = Vec::new();
+        for i in 0..20 {
+            for j in 0..20 {
+                particles
+                    .push(parameters.particle(0.1 * i as f64 + 0.01 * j as f64, 0.1 * j as f64));
+            }
+        }
+        particles
+    }
+
+    fn build(particles: &[Particle], radius: f64, skin: f64) -> NeighbourList {
+        let mut grid = grid::create_grid(radius + skin, 0.0, 2.5, 0.0, 2.5);
+        for (index, particle) in particles.iter().enumerate() {
+            grid.add_particle(index as u32, particle.x, particle.y);
+        }
+        grid.finish();
+        let mut list = NeighbourList::new(skin);
+        list.build(particles, &grid, radius, false);
+        list
+    }
+
+    #[test]
+    fn test_contains_all_pairs_within_cutoff() {
+        let particles = particles();
+        let list = build(&particles, 0.25, 0.05);
+        for (i, particle1) in particles.iter().enumerate() {
+            let mut neighbours = Vec::new();
+            list.for_each_neighbour(i, |j| neighbours.push(j));
+            for (j, particle2) in particles.iter().enumerate() {
+                let r2 = (particle1.x - particle2.x).powi(2) + (particle1.y - particle2.y).powi(2);
+                assert_eq!(neighbours.contains(&(j as u32)), r2 <= 0.3f64.powi(2));
+            }
+        }
+    }
+
+    #[test]
+    fn test_needs_rebuild_after_half_skin() {
+        let mut particles = particles();
+        let list = build(&particles, 0.25, 0.05);
+        assert!(!list.needs_rebuild(&particles));
+        particles[7].x += 0.02;
+        assert!(!list.needs_rebuild(&particles));
+        particles[7].x += 0.01;
+        assert!(list.needs_rebuild(&particles));
+        particles.pop();
+        assert!(list.needs_rebuild(&particles));
+    }
+}
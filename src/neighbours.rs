@@ -0,0 +1,312 @@
+use crate::grid;
+use crate::kdtree::KdTree;
+use crate::neighbour_list::NeighbourList;
+use crate::sph::Particle;
+
+use std::str::FromStr;
+
+/// Spatial search for the particles that can interact with a point. Every
+/// particle within the interaction radius of (x, y) must be visited, others
+/// may be visited too.
+pub trait NeighbourSearch: Sync {
+    fn for_each_neighbour<F: FnMut(u32)>(&self, x: f64, y: f64, f: F);
+
+    /// Call `f` once with every unordered pair of distinct particles that
+    /// may interact in block `block` of `blocks`, which between them visit
+    /// every pair once. By default a run of particles with each one's
+    /// neighbours of higher index.
+    fn for_each_pair_in<F: FnMut(u32, u32)>(
+        &self,
+        particles: &[Particle],
+        block: usize,
+        blocks: usize,
+        mut f: F,
+    ) {
+        let (n, start) = (particles.len(), block * particles.len() / blocks);
+        let run = &particles[start..(block + 1) * n / blocks];
+        for (i, particle) in (start..).zip(run) {
+            self.for_each_neighbour(particle.x, particle.y, |j| {
+                if j > i as u32 {
+                    f(i as u32, j);
+                }
+            });
+        }
+    }
+}
+
+/// Neighbour queries by particle index, which cached lists can answer
+/// without looking at positions
+pub trait ParticleNeighbours: Sync {
+    fn for_each_neighbour_of<F: FnMut(u32)>(&self, particles: &[Particle], i: usize, f: F);
+
+    /// See `NeighbourSearch::for_each_pair_in`
+    fn for_each_pair_in<F: FnMut(u32, u32)>(
+        &self,
+        particles: &[Particle],
+        block: usize,
+        blocks: usize,
+        f: F,
+    );
+}
+
+impl<T: NeighbourSearch> ParticleNeighbours for T {
+    fn for_each_neighbour_of<F: FnMut(u32)>(&self, particles: &[Particle], i: usize, f: F) {
+        self.for_each_neighbour(particles[i].x, particles[i].y, f)
+    }
+
+    fn for_each_pair_in<F: FnMut(u32, u32)>(
+        &self,
+        particles: &[Particle],
+        block: usize,
+        blocks: usize,
+        f: F,
+    ) {
+        NeighbourSearch::for_each_pair_in(self, particles, block, blocks, f)
+    }
+}
+
+impl ParticleNeighbours for NeighbourList {
+    fn for_each_neighbour_of<F: FnMut(u32)>(&self, _particles: &[Particle], i: usize, f: F) {
+        self.for_each_neighbour(i, f)
+    }
+
+    fn for_each_pair_in<F: FnMut(u32, u32)>(
+        &self,
+        particles: &[Particle],
+        block: usize,
+        blocks: usize,
+        mut f: F,
+    ) {
+        let n = particles.len();
+        for i in block * n / blocks..(block + 1) * n / blocks {
+            self.for_each_neighbour(i, |j| {
+                if j > i as u32 {
+                    f(i as u32, j);
+                }
+            });
+        }
+    }
+}
+
+impl NeighbourSearch for grid::Grid {
+    fn for_each_neighbour<F: FnMut(u32)>(&self, x: f64, y: f64, f: F) {
+        grid::Grid::for_each_neighbour(self, x, y, f)
+    }
+
+    /// Half stencil, so no pair is looked at twice
+    fn for_each_pair_in<F: FnMut(u32, u32)>(
+        &self,
+        _particles: &[Particle],
+        block: usize,
+        blocks: usize,
+        f: F,
+    ) {
+        grid::Grid::for_each_pair_in(self, block, blocks, f)
+    }
+}
+
+impl NeighbourSearch for KdTree {
+    fn for_each_neighbour<F: FnMut(u32)>(&self, x: f64, y: f64, f: F) {
+        KdTree::for_each_neighbour(self, x, y, f)
+    }
+}
+
+/// O(n) per query reference search, visits exactly the particles within `radius`
+pub struct BruteForce {
+    positions: Vec<(f64, f64)>,
+    radius: f64,
+}
+
+impl BruteForce {
+    pub fn new(particles: &[Particle], radius: f64) -> BruteForce {
+        BruteForce {
+            positions: particles
+                .iter()
+                .map(|particle| (particle.x, particle.y))
+                .collect(),
+            radius,
+        }
+    }
+}
+
+impl NeighbourSearch for BruteForce {
+    fn for_each_neighbour<F: FnMut(u32)>(&self, x: f64, y: f64, mut f: F) {
+        let r2 = self.radius.powi(2);
+        for (index, (px, py)) in self.positions.iter().enumerate() {
+            if (px - x).powi(2) + (py - y).powi(2) <= r2 {
+                f(index as u32);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SearchBackend {
+    Grid,
+    KdTree,
+    BruteForce,
+}
+
+impl FromStr for SearchBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "grid" => Ok(SearchBackend::Grid),
+            "kdtree" => Ok(SearchBackend::KdTree),
+            "bruteforce" => Ok(SearchBackend::BruteForce),
+            _ => Err(format!("Unknown neighbour search: {}", s)),
+        }
+    }
+}
+
+/// The search structure built for the current particle positions
+pub enum Search {
+    Grid(grid::Grid),
+    KdTree(KdTree),
+    BruteForce(BruteForce),
+}
+
+impl Search {
+    pub fn grid(&self) -> Option<&grid::Grid> {
+        match self {
+            Search::Grid(grid) => Some(grid),
+            _ => None,
+        }
+    }
+}
+
+impl NeighbourSearch for Search {
+    fn for_each_neighbour<F: FnMut(u32)>(&self, x: f64, y: f64, f: F) {
+        match self {
+            Search::Grid(grid) => grid.for_each_neighbour(x, y, f),
+            Search::KdTree(tree) => tree.for_each_neighbour(x, y, f),
+            Search::BruteForce(brute_force) => brute_force.for_each_neighbour(x, y, f),
+        }
+    }
+
+    fn for_each_pair_in<F: FnMut(u32, u32)>(
+        &self,
+        particles: &[Particle],
+        block: usize,
+        blocks: usize,
+        f: F,
+    ) {
+        match self {
+            Search::Grid(grid) => {
+                NeighbourSearch::for_each_pair_in(grid, particles, block, blocks, f)
+            }
+            Search::KdTree(tree) => {
+                NeighbourSearch::for_each_pair_in(tree, particles, block, blocks, f)
+            }
+            Search::BruteForce(brute_force) => {
+                NeighbourSearch::for_each_pair_in(brute_force, particles, block, blocks, f)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::math::Random;
+    use crate::sph::Parameters;
+
+    const H: f64 = 0.25;
+
+    /// Random cloud in [0, 4] x [0, 3] plus particles on cell edges and the
+    /// domain corners
+    fn cloud(seed: u64, n: usize) -> Vec<Particle> {
+        let parameters = Parameters::new();
+        let mut random = Random::new(seed);
+        let mut particles: Vec<_> = (0..n)
+            .map(|_| parameters.particle(4.0 * random.next(), 3.0 * random.next()))
+            .collect();
+        for i in 0..=8 {
+            for j in 0..=6 {
+                particles.push(parameters.particle(2.0 * H * i as f64, 2.0 * H * j as f64));
+            }
+        }
+        for &(x, y) in [(0.0, 0.0), (4.0, 0.0), (0.0, 3.0), (4.0, 3.0)].iter() {
+            particles.push(parameters.particle(x, y));
+        }
+        particles
+    }
+
+    fn sorted(search: &impl NeighbourSearch, x: f64, y: f64) -> Vec<u32> {
+        let mut neighbours = Vec::new();
+        search.for_each_neighbour(x, y, |j| neighbours.push(j));
+        neighbours.sort();
+        neighbours
+    }
+
+    fn is_superset(search: &impl NeighbourSearch, reference: &BruteForce, x: f64, y: f64) -> bool {
+        let found = sorted(search, x, y);
+        sorted(reference, x, y)
+            .iter()
+            .all(|j| found.binary_search(j).is_ok())
+    }
+
+    #[test]
+    fn test_grid_backends_are_supersets() {
+        for seed in 1..6 {
+            let particles = cloud(seed, 400);
+            let reference = BruteForce::new(&particles, 2.0 * H);
+            for &backend in [
+                grid::GridBackend::Cells,
+                grid::GridBackend::Compact,
+                grid::GridBackend::Hash,
+            ]
+            .iter()
+            {
+                let mut grid = grid::create_grid_with_backend(backend, 2.0 * H, 0.0, 4.0, 0.0, 3.0);
+                for (index, particle) in particles.iter().enumerate() {
+                    grid.add_particle(index as u32, particle.x, particle.y);
+                }
+                grid.finish();
+                for particle in particles.iter() {
+                    assert!(is_superset(&grid, &reference, particle.x, particle.y));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_kdtree_matches_brute_force() {
+        for seed in 1..6 {
+            let particles = cloud(seed, 400);
+            let reference = BruteForce::new(&particles, 2.0 * H);
+            let tree = KdTree::new(&particles, 2.0 * H);
+            let mut random = Random::new(seed + 100);
+            for particle in particles.iter() {
+                assert_eq!(
+                    sorted(&tree, particle.x, particle.y),
+                    sorted(&reference, particle.x, particle.y)
+                );
+            }
+            for _ in 0..100 {
+                let (x, y) = (5.0 * random.next() - 0.5, 4.0 * random.next() - 0.5);
+                assert_eq!(sorted(&tree, x, y), sorted(&reference, x, y));
+            }
+        }
+    }
+
+    #[test]
+    fn test_neighbour_list_is_superset() {
+        let particles = cloud(7, 400);
+        let reference = BruteForce::new(&particles, 2.0 * H);
+        let skin = 0.05;
+        let search = KdTree::new(&particles, 2.0 * H + skin);
+        let mut list = NeighbourList::new(skin);
+        list.build(&particles, &search, 2.0 * H, false);
+        for (i, particle) in particles.iter().enumerate() {
+            let mut found = Vec::new();
+            list.for_each_neighbour_of(&particles, i, |j| found.push(j));
+            found.sort();
+            assert!(sorted(&reference, particle.x, particle.y)
+                .iter()
+                .all(|j| found.binary_search(j).is_ok()));
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Initial packings of the particles in a region, and the settings of the
+//! relaxation that settles them before the run, see `sph::relax`
+
+use crate::math::Random;
+
+use std::str::FromStr;
+
+/// Largest offset of a jittered particle from its lattice site, relative to
+/// the spacing
+const JITTER: f64 = 0.2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Packing {
+    /// Particles at the centres of square cells of the spacing
+    Square,
+    /// Rows offset by half a particle, with the same area per particle as the
+    /// square lattice, so every neighbour shell is isotropic
+    Hexagonal,
+    /// Square lattice with every particle moved by up to `JITTER` of the
+    /// spacing along each axis, reproducibly for a given seed
+    Jittered { seed: u64 },
+}
+
+impl FromStr for Packing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "square" => Ok(Packing::Square),
+            "hexagonal" => Ok(Packing::Hexagonal),
+            "jittered" => Ok(Packing::Jittered { seed: 1 }),
+            _ => Err(format!("Unknown packing: {}", s)),
+        }
+    }
+}
+
+/// Positions of `packing` with an area of spacing^2 per particle over the
+/// bounds (min_x, min_y, max_x, max_y), keeping the lattice sites for which
+/// `contains` holds
+pub fn fill(
+    packing: Packing,
+    spacing: f64,
+    bounds: (f64, f64, f64, f64),
+    contains: impl Fn(f64, f64) -> bool,
+) -> Vec<(f64, f64)> {
+    let (min_x, min_y, max_x, max_y) = bounds;
+    let (dx, dy) = match packing {
+        Packing::Hexagonal => {
+            let distance = spacing * (2.0 / 3f64.sqrt()).sqrt();
+            (distance, 0.5 * 3f64.sqrt() * distance)
+        }
+        _ => (spacing, spacing),
+    };
+    let columns = ((max_x - min_x) / dx).ceil() as u32;
+    let rows = ((max_y - min_y) / dy).ceil() as u32;
+    let mut random = match packing {
+        Packing::Jittered { seed } => Some(Random::new(seed)),
+        _ => None,
+    };
+    let mut positions = Vec::new();
+    for i in 0..columns {
+        for j in 0..rows {
+            let shift = if packing == Packing::Hexagonal && j % 2 == 1 {
+                0.5 * dx
+            } else {
+                0.0
+            };
+            let x = min_x + (i as f64 + 0.5) * dx - shift;
+            let y = min_y + (j as f64 + 0.5) * dy;
+            if !contains(x, y) {
+                continue;
+            }
+            match &mut random {
+                Some(random) => positions.push((
+                    x + JITTER * spacing * (2.0 * random.next() - 1.0),
+                    y + JITTER * spacing * (2.0 * random.next() - 1.0),
+                )),
+                None => positions.push((x, y)),
+            }
+        }
+    }
+    positions
+}
+
+/// Damped pseudo-time stepping of the initial particles until the root mean
+/// square acceleration, relative to gravity, drops below `tolerance`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Relaxation {
+    pub tolerance: f64,
+    pub max_iterations: u32,
+    pub timestep: f64,
+    /// Fraction of the velocity removed every iteration
+    pub damping: f64,
+    /// Relax under gravity towards hydrostatic pressure. Only for fluid that
+    /// rests on the floor or an obstacle, anything else falls. With the summed
+    /// density the free surface contracts into a dense top row. Without it
+    /// the particles only settle towards uniform density.
+    pub hydrostatic: bool,
+}
+
+impl Relaxation {
+    pub fn new(tolerance: f64) -> Relaxation {
+        Relaxation {
+            tolerance,
+            max_iterations: 2000,
+            timestep: 0.002,
+            damping: 0.05,
+            hydrostatic: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RelaxationReport {
+    pub iterations: u32,
+    /// Residual after the last iteration, below the tolerance unless the
+    /// relaxation ran out of iterations
+    pub residual: f64,
+}
+
+impl RelaxationReport {
+    pub fn converged(&self, relaxation: &Relaxation) -> bool {
+        self.residual < relaxation.tolerance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square(packing: Packing) -> Vec<(f64, f64)> {
+        fill(packing, 0.1, (0.0, 0.0, 2.0, 2.0), |x, y| {
+            (0.0..2.0).contains(&x) && (0.0..2.0).contains(&y)
+        })
+    }
+
+    #[test]
+    fn test_packings_keep_area_per_particle() {
+        for &packing in [
+            Packing::Square,
+            Packing::Hexagonal,
+            Packing::Jittered { seed: 7 },
+        ]
+        .iter()
+        {
+            let count = square(packing).len() as f64;
+            assert!(
+                (count - 400.0).abs() < 0.05 * 400.0,
+                "{:?} {}",
+                packing,
+                count
+            );
+        }
+    }
+
+    #[test]
+    fn test_hexagonal_neighbours_equidistant() {
+        let positions = square(Packing::Hexagonal);
+        let distance = 0.1 * (2.0 / 3f64.sqrt()).sqrt();
+        let (x, y) = positions[positions.len() / 2];
+        let mut neighbours: Vec<f64> = positions
+            .iter()
+            .map(|&(x2, y2)| (x2 - x).hypot(y2 - y))
+            .filter(|&r| r > 0.0)
+            .collect();
+        neighbours.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        for r in neighbours[..6].iter() {
+            assert!((r - distance).abs() < 1e-9);
+        }
+        assert!(neighbours[6] > 1.5 * distance);
+    }
+
+    #[test]
+    fn test_jitter_reproducible() {
+        let jittered = square(Packing::Jittered { seed: 3 });
+        assert_eq!(jittered, square(Packing::Jittered { seed: 3 }));
+        assert_ne!(jittered, square(Packing::Jittered { seed: 4 }));
+        for (&(x, y), &(x0, y0)) in jittered.iter().zip(square(Packing::Square).iter()) {
+            assert!((x - x0).abs() <= JITTER * 0.1 && (y - y0).abs() <= JITTER * 0.1);
+        }
+    }
+}
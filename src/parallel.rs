@@ -0,0 +1,137 @@
+//! Loops that run on the rayon thread pool on native builds and serially
+//! everywhere else. Every helper produces the same result either way.
+
+#[cfg(not(target_arch = "wasm32"))]
+use rayon::prelude::*;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn map<T, F>(n: usize, parallel: bool, f: F) -> Vec<T>
+where
+    T: Send,
+    F: Fn(usize) -> T + Sync + Send,
+{
+    if parallel {
+        (0..n).into_par_iter().map(f).collect()
+    } else {
+        (0..n).map(f).collect()
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn map<T, F>(n: usize, _parallel: bool, f: F) -> Vec<T>
+where
+    T: Send,
+    F: Fn(usize) -> T + Sync + Send,
+{
+    (0..n).map(f).collect()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn for_each<A, F>(a: &mut [A], parallel: bool, f: F)
+where
+    A: Send,
+    F: Fn(&mut A) + Sync + Send,
+{
+    if parallel {
+        a.par_iter_mut().for_each(f);
+    } else {
+        a.iter_mut().for_each(f);
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn for_each<A, F>(a: &mut [A], _parallel: bool, f: F)
+where
+    A: Send,
+    F: Fn(&mut A) + Sync + Send,
+{
+    a.iter_mut().for_each(f);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn for_each_zip<A, B, F>(a: &mut [A], b: &mut [B], parallel: bool, f: F)
+where
+    A: Send,
+    B: Send,
+    F: Fn(&mut A, &mut B) + Sync + Send,
+{
+    if parallel {
+        a.par_iter_mut()
+            .zip(b.par_iter_mut())
+            .for_each(|(a, b)| f(a, b));
+    } else {
+        a.iter_mut().zip(b.iter_mut()).for_each(|(a, b)| f(a, b));
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn for_each_zip<A, B, F>(a: &mut [A], b: &mut [B], _parallel: bool, f: F)
+where
+    A: Send,
+    B: Send,
+    F: Fn(&mut A, &mut B) + Sync + Send,
+{
+    a.iter_mut().zip(b.iter_mut()).for_each(|(a, b)| f(a, b));
+}
+
+/// Dedicated thread pool, or `None` to use rayon's global pool
+#[cfg(not(target_arch = "wasm32"))]
+pub struct Pool(Option<rayon::ThreadPool>);
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Pool {
+    /// `threads == 0` uses rayon's default of one thread per core
+    pub fn new(threads: usize) -> Pool {
+        if threads == 0 {
+            return Pool(None);
+        }
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        Pool(Some(pool))
+    }
+
+    pub fn install<R: Send>(&self, f: impl FnOnce() -> R + Send) -> R {
+        match &self.0 {
+            Some(pool) => pool.install(f),
+            None => f(),
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+pub struct Pool;
+
+#[cfg(target_arch = "wasm32")]
+impl Pool {
+    pub fn new(_threads: usize) -> Pool {
+        Pool
+    }
+
+    pub fn install<R: Send>(&self, f: impl FnOnce() -> R + Send) -> R {
+        f()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parallel_matches_serial() {
+        let serial = map(1000, false, |i| (i as f64).sqrt());
+        let parallel = Pool::new(3).install(|| map(1000, true, |i| (i as f64).sqrt()));
+        assert_eq!(serial, parallel);
+
+        let mut a = vec![1.0; 100];
+        let mut b: Vec<f64> = (0..100).map(|i| i as f64).collect();
+        for_each_zip(&mut a, &mut b, true, |a, b| {
+            *a += *b;
+            *b = 0.0;
+        });
+        for_each(&mut a, true, |a| *a *= 2.0);
+        assert_eq!(a[10], 22.0);
+        assert_eq!(b[10], 0.0);
+    }
+}
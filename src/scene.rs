@@ -0,0 +1,465 @@
+//! Scene descriptions read from JSON: the domain, regions of fluid each on
+//! its own lattice, static obstacles, the initial state of the duck, gravity
+//! and solver settings. The presets in `scenes/` are built into the crate.
+
+use crate::density::DensityMethod;
+use crate::packing;
+use crate::sph::{Duck, Parameters, Particle, Scene, GRAVITY, MAX_X, MAX_Y, MIN_X, MIN_Y};
+
+use serde::Deserialize;
+
+use std::fs;
+
+/// Preset scene files by name
+pub const PRESETS: [(&str, &str); 4] = [
+    ("dam-break", include_str!("../scenes/dam-break.json")),
+    (
+        "drop-into-pool",
+        include_str!("../scenes/drop-into-pool.json"),
+    ),
+    (
+        "double-dam-break",
+        include_str!("../scenes/double-dam-break.json"),
+    ),
+    (
+        "duck-on-calm-water",
+        include_str!("../scenes/duck-on-calm-water.json"),
+    ),
+];
+
+/// Walls of the tank
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Domain {
+    pub min_x: f64,
+    pub min_y: f64,
+    pub max_x: f64,
+    pub max_y: f64,
+}
+
+impl Domain {
+    pub fn tank() -> Domain {
+        Domain {
+            min_x: MIN_X,
+            min_y: MIN_Y,
+            max_x: MAX_X,
+            max_y: MAX_Y,
+        }
+    }
+}
+
+/// Region filled with particles on a square lattice of `spacing`, or the
+/// spacing of the parameters if not given. Particles on a different lattice
+/// have their mass and smoothing length scaled to keep the rest density, and
+/// a coarser one widens the neighbour search to match.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "shape", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Fluid {
+    Rectangle {
+        min_x: f64,
+        min_y: f64,
+        max_x: f64,
+        max_y: f64,
+        spacing: Option<f64>,
+    },
+    Circle {
+        x: f64,
+        y: f64,
+        radius: f64,
+        spacing: Option<f64>,
+    },
+    /// Pixels of a mask stretched over the rectangle, row major from
+    /// `min_y`, see `mask`
+    #[serde(skip)]
+    Bitmap {
+        min_x: f64,
+        min_y: f64,
+        max_x: f64,
+        max_y: f64,
+        columns: u32,
+        pixels: Vec<bool>,
+        spacing: Option<f64>,
+    },
+}
+
+impl Fluid {
+    fn bounds(&self) -> (f64, f64, f64, f64) {
+        match *self {
+            Fluid::Rectangle {
+                min_x,
+                min_y,
+                max_x,
+                max_y,
+                ..
+            }
+            | Fluid::Bitmap {
+                min_x,
+                min_y,
+                max_x,
+                max_y,
+                ..
+            } => (min_x, min_y, max_x, max_y),
+            Fluid::Circle { x, y, radius, .. } => (x - radius, y - radius, x + radius, y + radius),
+        }
+    }
+
+    fn contains(&self, x: f64, y: f64) -> bool {
+        let (min_x, min_y, max_x, max_y) = self.bounds();
+        let inside = (min_x..max_x).contains(&x) && (min_y..max_y).contains(&y);
+        match self {
+            Fluid::Rectangle { .. } => inside,
+            Fluid::Circle {
+                x: centre_x,
+                y: centre_y,
+                radius,
+                ..
+            } => (x - centre_x).hypot(y - centre_y) < *radius,
+            Fluid::Bitmap {
+                columns, pixels, ..
+            } => {
+                let rows = pixels.len() as u32 / columns;
+                let column = ((x - min_x) / (max_x - min_x) * *columns as f64) as u32;
+                let row = ((y - min_y) / (max_y - min_y) * rows as f64) as u32;
+                inside && pixels[(row.min(rows - 1) * columns + column.min(columns - 1)) as usize]
+            }
+        }
+    }
+
+    /// Spacing of the lattice of the region, if not that of the parameters
+    pub fn spacing(&self) -> Option<f64> {
+        match *self {
+            Fluid::Rectangle { spacing, .. }
+            | Fluid::Circle { spacing, .. }
+            | Fluid::Bitmap { spacing, .. } => spacing,
+        }
+    }
+
+    /// Particles of the packing of the parameters inside the region
+    pub fn particles(&self, parameters: &Parameters) -> Vec<Particle> {
+        let spacing = self
+            .spacing()
+            .unwrap_or_else(|| parameters.particle_spacing());
+        let scale = spacing / parameters.particle_spacing();
+        packing::fill(parameters.packing, spacing, self.bounds(), |x, y| {
+            self.contains(x, y)
+        })
+        .into_iter()
+        .map(|(x, y)| Particle {
+            mass: parameters.mass * scale * scale,
+            h: parameters.h * scale,
+            ..parameters.particle(x, y)
+        })
+        .collect()
+    }
+}
+
+/// Solid that particles bounce off like the walls
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "shape", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Obstacle {
+    Rectangle {
+        min_x: f64,
+        min_y: f64,
+        max_x: f64,
+        max_y: f64,
+    },
+    Circle {
+        x: f64,
+        y: f64,
+        radius: f64,
+    },
+}
+
+impl Obstacle {
+    /// Outward unit normal of the nearest part of the surface, and the depth
+    /// below it, of a point inside the obstacle
+    pub fn penetration(&self, x: f64, y: f64) -> Option<(f64, f64, f64)> {
+        match *self {
+            Obstacle::Rectangle {
+                min_x,
+                min_y,
+                max_x,
+                max_y,
+            } => {
+                if !(min_x < x && x < max_x && min_y < y && y < max_y) {
+                    return None;
+                }
+                let sides = [
+                    (-1.0, 0.0, x - min_x),
+                    (1.0, 0.0, max_x - x),
+                    (0.0, -1.0, y - min_y),
+                    (0.0, 1.0, max_y - y),
+                ];
+                sides
+                    .iter()
+                    .copied()
+                    .min_by(|a, b| a.2.partial_cmp(&b.2).unwrap())
+            }
+            Obstacle::Circle {
+                x: centre_x,
+                y: centre_y,
+                radius,
+            } => {
+                let distance = (x - centre_x).hypot(y - centre_y);
+                if distance >= radius {
+                    None
+                } else if distance == 0.0 {
+                    // Straight up, against gravity
+                    Some((0.0, -1.0, radius))
+                } else {
+                    let (nx, ny) = ((x - centre_x) / distance, (y - centre_y) / distance);
+                    Some((nx, ny, radius - distance))
+                }
+            }
+        }
+    }
+
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        self.penetration(x, y).is_some()
+    }
+}
+
+/// Position and velocity of a body at the start
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Body {
+    pub x: f64,
+    pub y: f64,
+    #[serde(default)]
+    pub vx: f64,
+    #[serde(default)]
+    pub vy: f64,
+}
+
+impl Body {
+    /// Where `Duck::new` starts
+    pub fn duck() -> Body {
+        let duck = Duck::new();
+        Body {
+            x: duck.x,
+            y: duck.y,
+            vx: duck.vx,
+            vy: duck.vy,
+        }
+    }
+}
+
+/// Settings of the run, named after the command line options they match.
+/// Options given on the command line after `--scene` take precedence.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Solver {
+    /// Ratio of the smoothing length to the particle spacing
+    pub smoothing_length: Option<f64>,
+    pub kernel: Option<String>,
+    pub integrator: Option<String>,
+    pub density: Option<String>,
+    pub symmetric: Option<bool>,
+    pub shifting: Option<f64>,
+    pub hydrostatic: Option<bool>,
+    pub wall_images: Option<bool>,
+}
+
+fn default_gravity() -> f64 {
+    GRAVITY
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneDescription {
+    #[serde(default = "Domain::tank")]
+    pub domain: Domain,
+    pub fluid: Vec<Fluid>,
+    #[serde(default)]
+    pub obstacles: Vec<Obstacle>,
+    #[serde(default = "Body::duck")]
+    pub duck: Body,
+    /// Acceleration along +y, down the screen
+    #[serde(default = "default_gravity")]
+    pub gravity: f64,
+    #[serde(default)]
+    pub solver: Solver,
+}
+
+impl SceneDescription {
+    pub fn from_json(text: &str) -> Result<SceneDescription, String> {
+        serde_json::from_str(text).map_err(|error| format!("Invalid scene: {}", error))
+    }
+
+    /// The preset called `name`, or else the scene file at path `name`
+    pub fn load(name: &str) -> Result<SceneDescription, String> {
+        match PRESETS.iter().find(|(preset, _)| *preset == name) {
+            Some((_, text)) => SceneDescription::from_json(text),
+            None => {
+                let text = fs::read_to_string(name)
+                    .map_err(|error| format!("Cannot read scene {}: {}", name, error))?;
+                SceneDescription::from_json(&text)
+            }
+        }
+    }
+
+    /// Set up `parameters` to build and run this scene
+    pub fn apply(&self, parameters: &mut Parameters) -> Result<(), String> {
+        parameters.scene = Scene::Fluid(self.fluid.clone());
+        parameters.domain = self.domain;
+        parameters.obstacles = self.obstacles.clone();
+        parameters.duck = Duck {
+            x: self.duck.x,
+            y: self.duck.y,
+            vx: self.duck.vx,
+            vy: self.duck.vy,
+        };
+        parameters.gravity = self.gravity;
+        let solver = &self.solver;
+        if let Some(ratio) = solver.smoothing_length {
+            parameters.h = ratio * parameters.particle_spacing();
+        }
+        if let Some(kernel) = &solver.kernel {
+            parameters.kernel = kernel.parse()?;
+        }
+        if let Some(integrator) = &solver.integrator {
+            parameters.integrator = integrator.parse()?;
+        }
+        if let Some(method) = &solver.density {
+            parameters.density_method = method.parse()?;
+        }
+        if let Some(symmetric) = solver.symmetric {
+            parameters.symmetric_forces = symmetric;
+        }
+        if let Some(coefficient) = solver.shifting {
+            parameters.shifting = Some(coefficient);
+        }
+        if let Some(hydrostatic) = solver.hydrostatic {
+            parameters.hydrostatic = hydrostatic;
+            if hydrostatic && solver.density.is_none() {
+                parameters.density_method = DensityMethod::Continuity;
+            }
+        }
+        if let Some(wall_images) = solver.wall_images {
+            parameters.wall_images = wall_images;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::sph;
+
+    #[test]
+    fn test_presets_build() {
+        for (name, _) in PRESETS.iter() {
+            // --scene would take a built-in scene of the same name instead
+            assert!(name.parse::<Scene>().is_err(), "{}", name);
+            let scene = SceneDescription::load(name).unwrap();
+            let mut parameters = Parameters {
+                parallel: false,
+                ..Parameters::new()
+            };
+            scene.apply(&mut parameters).unwrap();
+            let state = sph::create_initial_state(parameters);
+            assert!(state.particles.len() > 100, "{}", name);
+            let domain = &state.parameters.domain;
+            for particle in state.particles.iter() {
+                assert!(
+                    (domain.min_x..domain.max_x).contains(&particle.x),
+                    "{}",
+                    name
+                );
+                assert!(
+                    (domain.min_y..domain.max_y).contains(&particle.y),
+                    "{}",
+                    name
+                );
+                for obstacle in state.parameters.obstacles.iter() {
+                    assert!(!obstacle.contains(particle.x, particle.y), "{}", name);
+                }
+            }
+            assert_eq!(state.duck.x, scene.duck.x);
+        }
+    }
+
+    #[test]
+    fn test_region_spacing_scales_particles() {
+        let parameters = Parameters::new();
+        let fine = parameters.particle_spacing() / 2.0;
+        let scene = SceneDescription::from_json(&format!(
+            r#"{{"fluid": [
+                {{"shape": "rectangle", "min_x": 0, "min_y": 0, "max_x": 1, "max_y": 1}},
+                {{"shape": "circle", "x": 3, "y": 3, "radius": 0.5, "spacing": {}}}
+            ]}}"#,
+            fine
+        ))
+        .unwrap();
+        let coarse = scene.fluid[0].particles(&parameters);
+        let n = (1.0 / parameters.particle_spacing()).ceil() as usize;
+        assert_eq!(coarse.len(), n * n);
+        assert!(coarse
+            .iter()
+            .all(|particle| particle.mass == parameters.mass));
+        let drop = scene.fluid[1].particles(&parameters);
+        // Close to the area of the circle over that of a particle
+        let expected = std::f64::consts::PI * 0.25 / (fine * fine);
+        assert!((drop.len() as f64 - expected).abs() < 0.05 * expected);
+        for particle in drop.iter() {
+            assert!((particle.mass - 0.25 * parameters.mass).abs() < 1e-9 * parameters.mass);
+            assert!((particle.h - 0.5 * parameters.h).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_coarse_region_widens_the_search() {
+        let mut parameters = Parameters::new();
+        let coarse = 3.0 * parameters.particle_spacing();
+        let scene = SceneDescription::from_json(&format!(
+            r#"{{"fluid": [
+                {{"shape": "rectangle", "min_x": 1, "min_y": 1, "max_x": 2, "max_y": 2}},
+                {{"shape": "rectangle", "min_x": 3, "min_y": 3, "max_x": 4, "max_y": 4,
+                  "spacing": {}}}
+            ]}}"#,
+            coarse
+        ))
+        .unwrap();
+        scene.apply(&mut parameters).unwrap();
+        let h = scene.fluid[1].particles(&parameters)[0].h;
+        assert!((parameters.max_smoothing_length() - h).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_obstacle_penetration() {
+        let block = Obstacle::Rectangle {
+            min_x: 1.0,
+            min_y: 1.0,
+            max_x: 3.0,
+            max_y: 2.0,
+        };
+        let (nx, ny, depth) = block.penetration(1.2, 1.5).unwrap();
+        assert_eq!((nx, ny), (-1.0, 0.0));
+        assert!((depth - 0.2).abs() < 1e-12);
+        assert_eq!(
+            block.penetration(2.0, 1.9).map(|(nx, ny, _)| (nx, ny)),
+            Some((0.0, 1.0))
+        );
+        assert_eq!(block.penetration(0.9, 1.5), None);
+        let post = Obstacle::Circle {
+            x: 0.0,
+            y: 0.0,
+            radius: 1.0,
+        };
+        let (nx, ny, depth) = post.penetration(0.0, -0.25).unwrap();
+        assert_eq!((nx, ny), (0.0, -1.0));
+        assert!((depth - 0.75).abs() < 1e-12);
+        assert!(!post.contains(1.0, 0.5));
+    }
+
+    #[test]
+    fn test_invalid_scenes() {
+        assert!(SceneDescription::from_json(r#"{"fluid": [], "gravity": 9.8}"#).is_ok());
+        assert!(SceneDescription::from_json(r#"{"fluid": [], "gravitee": 9.8}"#).is_err());
+        let scene = SceneDescription::from_json(r#"{"fluid": [], "solver": {"kernel": "x"}}"#);
+        assert!(scene.unwrap().apply(&mut Parameters::new()).is_err());
+        assert!(SceneDescription::load("no-such-scene.json").is_err());
+    }
+}
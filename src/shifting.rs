@@ -0,0 +1,156 @@
+//! Particle shifting after Lind et al. (2012): particles are moved down the
+//! gradient of the particle concentration, which keeps their spacing uniform
+//! and so keeps the pressure field smooth
+
+use crate::density::DensityMethod;
+use crate::neighbours::ParticleNeighbours;
+use crate::parallel;
+use crate::sph::{self, Parameters, Particle};
+use crate::walls;
+
+/// Strength and exponent of the anti-clustering term
+/// 1 + R (W_ij / W(dp))^n, which stops particles from pairing up
+const R: f64 = 0.2;
+const N: i32 = 4;
+
+/// Largest shift in one step, as a fraction of the particle spacing
+const MAX_SHIFT: f64 = 0.1;
+
+/// Shift every particle not flagged at the free surface by
+/// -coefficient (2h)^2 sum_j V_j (1 + R (W_ij / W(dp))^n) grad W_ij. The
+/// flags are those of the last `surface::detect_surface`, which classifies
+/// the particles whenever shifting is on. With the continuity equation the
+/// density is carried to the new position to first order, with summation it
+/// is recomputed at the next evaluation anyway.
+pub fn shift_particles<N: ParticleNeighbours>(
+    particles: &mut [Particle],
+    neighbours: &N,
+    parameters: &Parameters,
+    coefficient: f64,
+) {
+    let kernel = parameters.kernel_evaluator();
+    let particles_ref = &*particles;
+    let shifts = parallel::map(particles.len(), parameters.parallel, |i| {
+        let particle1 = &particles_ref[i];
+        if particle1.asleep || particle1.surface {
+            return (0.0, 0.0, 0.0);
+        }
+        let spacing = particle1.spacing(parameters.rest_density);
+        let walls = walls::Walls::near(particle1, parameters);
+        let (mut concentration_x, mut concentration_y) = (0.0, 0.0);
+        let (mut density_x, mut density_y) = (0.0, 0.0);
+        neighbours.for_each_neighbour_of(particles_ref, i, |j| {
+            // The images keep particles from being shifted into the walls
+            let mut add = |particle2: &Particle| {
+                let rx = particle1.x - particle2.x;
+                let ry = particle1.y - particle2.y;
+                let h = sph::pair_h(particle1, particle2);
+                let volume = particle2.mass / particle2.density;
+                let (grad_x, grad_y) = kernel.gradient(rx, ry, h);
+                let w = kernel.value_r2(rx.powi(2) + ry.powi(2), h);
+                // The spacing of the pair goes with its smoothing length
+                let reference = kernel.value(spacing * h / particle1.h, h);
+                let clustering = 1.0 + R * (w / reference).powi(N);
+                concentration_x += volume * clustering * grad_x;
+                concentration_y += volume * clustering * grad_y;
+                let difference = volume * (particle2.density - particle1.density);
+                density_x += difference * grad_x;
+                density_y += difference * grad_y;
+            };
+            let particle2 = &particles_ref[j as usize];
+            add(particle2);
+            walls.for_each_image(particle2, add);
+        });
+        let scale = -coefficient * (2.0 * particle1.h).powi(2);
+        let (mut dx, mut dy) = (scale * concentration_x, scale * concentration_y);
+        let length = (dx * dx + dy * dy).sqrt();
+        if length > MAX_SHIFT * spacing {
+            dx *= MAX_SHIFT * spacing / length;
+            dy *= MAX_SHIFT * spacing / length;
+        }
+        (dx, dy, density_x * dx + density_y * dy)
+    });
+    let continuity = parameters.density_method == DensityMethod::Continuity;
+    for (particle, (dx, dy, density)) in particles.iter_mut().zip(shifts) {
+        particle.x += dx;
+        particle.y += dy;
+        if continuity {
+            particle.density += density;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fixtures::{self, lattice};
+    use crate::math::GOLDEN_ANGLE;
+    use crate::neighbours::BruteForce;
+    use crate::surface::detect_surface;
+
+    /// Square lattice of `n * n` particles with every particle displaced by
+    /// up to `jitter` along a fixed pseudo-random direction
+    fn jittered(n: i32, jitter: f64, parameters: &Parameters) -> Vec<Particle> {
+        let mut particles = lattice(0..n, parameters, |_, _| (0.0, 0.0));
+        for (k, particle) in particles.iter_mut().enumerate() {
+            let (i, j) = (k as i32 / n, k as i32 % n);
+            let angle = k as f64 * GOLDEN_ANGLE;
+            let amount = jitter * (((i * 7 + j * 13) % 10) as f64 / 10.0);
+            particle.x += amount * angle.cos();
+            particle.y += amount * angle.sin();
+        }
+        particles
+    }
+
+    /// Smallest distance from an interior particle to any other
+    fn closest_interior_pair(particles: &[Particle], n: i32) -> f64 {
+        let mut closest = f64::INFINITY;
+        for (k, particle1) in particles.iter().enumerate() {
+            let (i, j) = (k as i32 / n, k as i32 % n);
+            if i < 4 || i >= n - 4 || j < 4 || j >= n - 4 {
+                continue;
+            }
+            for (l, particle2) in particles.iter().enumerate() {
+                if l != k {
+                    let r = ((particle1.x - particle2.x).powi(2)
+                        + (particle1.y - particle2.y).powi(2))
+                    .sqrt();
+                    closest = closest.min(r);
+                }
+            }
+        }
+        closest
+    }
+
+    #[test]
+    fn test_shifting_evens_out_spacing() {
+        let parameters = Parameters {
+            h: 0.13,
+            shifting: Some(0.05),
+            ..fixtures::parameters()
+        };
+        let n = 16;
+        let mut particles = jittered(n, 0.02, &parameters);
+        let start = particles.clone();
+        let closest = closest_interior_pair(&particles, n);
+        let search = BruteForce::new(&particles, parameters.support_radius());
+        detect_surface(&mut particles, &search, &parameters);
+        shift_particles(&mut particles, &search, &parameters, 0.05);
+        // The outermost particles are at the free surface and stay put
+        for (k, (particle, original)) in particles.iter().zip(start.iter()).enumerate() {
+            let (i, j) = (k as i32 / n, k as i32 % n);
+            if i == 0 || i == n - 1 || j == 0 || j == n - 1 {
+                assert_eq!((particle.x, particle.y), (original.x, original.y));
+            } else if (2..n - 2).contains(&i) && (2..n - 2).contains(&j) {
+                assert_ne!((particle.x, particle.y), (original.x, original.y));
+            }
+        }
+        for _ in 0..50 {
+            let search = BruteForce::new(&particles, parameters.support_radius());
+            detect_surface(&mut particles, &search, &parameters);
+            shift_particles(&mut particles, &search, &parameters, 0.05);
+        }
+        assert!(closest_interior_pair(&particles, n) > closest + 0.01);
+    }
+}
@@ -0,0 +1,130 @@
+//! Sleeping particles: where the fluid has come to rest its particles stop
+//! being integrated, and keep their density, pressure and forces for their
+//! awake neighbours, until something disturbs them
+
+use crate::neighbours::ParticleNeighbours;
+use crate::parallel;
+use crate::sph::{Duck, Parameters, Particle, DUCK_RADIUS};
+
+/// Thresholds below which a particle counts as calm
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sleeping {
+    pub velocity: f64,
+    pub acceleration: f64,
+    /// Steps a particle and all its neighbours must have been calm for before
+    /// it goes to sleep
+    pub steps: u32,
+}
+
+impl Sleeping {
+    pub fn new() -> Sleeping {
+        Sleeping {
+            velocity: 0.05,
+            acceleration: 5.0,
+            steps: 50,
+        }
+    }
+}
+
+/// Count the calm steps of every particle after a step, then send to sleep
+/// those that have been calm for `steps` steps with only calm neighbours, and
+/// wake the rest. Particles the duck is moving through, or about to reach,
+/// are never calm. Returns the number of particles awake.
+pub fn update_activity<N: ParticleNeighbours>(
+    particles: &mut [Particle],
+    neighbours: &N,
+    parameters: &Parameters,
+    sleeping: &Sleeping,
+    duck: &Duck,
+) -> usize {
+    let duck_moving = duck.vx.hypot(duck.vy) > sleeping.velocity;
+    let duck_reach = DUCK_RADIUS + parameters.support_radius();
+    for particle in particles.iter_mut() {
+        let speed = particle.vx.hypot(particle.vy);
+        // Forces are stored multiplied by the density
+        let acceleration = particle.fx.hypot(particle.fy) / particle.density;
+        let near_duck = (particle.x - duck.x).hypot(particle.y - duck.y) < duck_reach;
+        if speed < sleeping.velocity
+            && acceleration < sleeping.acceleration
+            && !(duck_moving && near_duck)
+        {
+            particle.calm_steps = particle.calm_steps.saturating_add(1);
+        } else {
+            particle.calm_steps = 0;
+        }
+    }
+    let particles_ref = &*particles;
+    let asleep = parallel::map(particles.len(), parameters.parallel, |i| {
+        let mut calm = particles_ref[i].calm_steps >= sleeping.steps;
+        if calm {
+            neighbours.for_each_neighbour_of(particles_ref, i, |j| {
+                calm &= particles_ref[j as usize].calm_steps >= sleeping.steps;
+            });
+        }
+        calm
+    });
+    let mut active = 0;
+    for (particle, asleep) in particles.iter_mut().zip(asleep) {
+        if asleep {
+            // At rest, as seen by the viscosity and continuity of neighbours
+            particle.vx = 0.0;
+            particle.vy = 0.0;
+        } else {
+            active += 1;
+        }
+        particle.asleep = asleep;
+    }
+    active
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::neighbours::BruteForce;
+
+    /// Row of particles at rest at the rest density, far from the duck
+    fn row(parameters: &Parameters) -> Vec<Particle> {
+        (0..20)
+            .map(|i| {
+                let mut particle = parameters.particle(0.1 * i as f64, 10.0);
+                particle.density = parameters.rest_density;
+                particle
+            })
+            .collect()
+    }
+
+    fn update(particles: &mut [Particle], parameters: &Parameters) -> usize {
+        let search = BruteForce::new(particles, parameters.support_radius());
+        update_activity(
+            particles,
+            &search,
+            parameters,
+            &Sleeping::new(),
+            &Duck::new(),
+        )
+    }
+
+    #[test]
+    fn test_sleep_and_wake() {
+        let parameters = Parameters {
+            h: 0.1,
+            parallel: false,
+            ..Parameters::new()
+        };
+        let mut particles = row(&parameters);
+        for _ in 0..Sleeping::new().steps - 1 {
+            assert_eq!(update(&mut particles, &parameters), particles.len());
+        }
+        assert_eq!(update(&mut particles, &parameters), 0);
+        assert!(particles.iter().all(|particle| particle.asleep));
+
+        // A disturbed particle wakes itself and its neighbours, but not the
+        // far end of the row
+        particles[0].vx = 1.0;
+        let active = update(&mut particles, &parameters);
+        assert!(active > 1 && active < 6, "{}", active);
+        assert!(!particles[0].asleep && !particles[1].asleep);
+        assert!(particles[19].asleep);
+    }
+}
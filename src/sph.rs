@@ -1,9 +1,30 @@
+use crate::adaptive::AdaptiveResolution;
+use crate::correction::{self, GradientCorrection};
+use crate::density::{self, DensityDiffusion, DensityMethod};
+use crate::diagnostics::{self, Diagnostics};
 use crate::grid;
-use crate::kernels;
+use crate::hydrostatic;
+use crate::integrators::{self, Integrator, IntegratorType, System};
+use crate::kdtree::KdTree;
+use crate::kernel_table::{Interpolation, Tabulated};
+use crate::kernels::{Kernel, KernelType};
 use crate::math;
+use crate::neighbour_list::NeighbourList;
+use crate::neighbours::{BruteForce, NeighbourSearch, ParticleNeighbours, Search, SearchBackend};
+use crate::packing::{self, Packing, Relaxation, RelaxationReport};
+use crate::parallel;
+use crate::scene::{Domain, Fluid, Obstacle};
+use crate::shifting;
+use crate::sleeping::{self, Sleeping};
+use crate::surface::{self, SurfaceDetection};
+use crate::turbulence;
+use crate::vorticity;
+use crate::walls;
+
+use std::str::FromStr;
+use std::sync::Arc;
 
 const N: u32 = 30;
-pub const N_PARTICLES: u32 = N * N;
 pub const MAX_X: f64 = 4.95;
 pub const MIN_X: f64 = 0.05;
 pub const MAX_Y: f64 = 4.95;
@@ -12,30 +33,327 @@ const START_MIN_X: f64 = 0.1;
 const START_MAX_X: f64 = 4.9;
 const START_MIN_Y: f64 = 1.5;
 const START_MAX_Y: f64 = 3.9;
-const GAS_CONST: f64 = 1000.0;
-pub const H: f64 = 4.0 * (START_MAX_X - START_MIN_X) / N as f64;
-const M: f64 = 65.0;
+pub const GAS_CONST: f64 = 1000.0;
+/// Mass of the initial block, split among the particles at any resolution
+const BLOCK_MASS: f64 = 65.0 * (N * N) as f64;
 const MU: f64 = 0.1;
 const DAMPING: f64 = 0.9;
-const REST_DENS: f64 =
-    M * (N * N) as f64 / ((START_MAX_X - START_MIN_X) * (START_MAX_Y - START_MIN_Y));
-const GRAVITY: f64 = 100.0; // Acceleration * Area ?
+pub const GRAVITY: f64 = 100.0; // Acceleration * Area ?
 
 const DUCK_X: f64 = 2.5;
 const DUCK_Y: f64 = 1.0;
-const DUCK_RADIUS: f64 = 0.4;
-const DUCK_MASS: f64 = 10. * M;
+pub const DUCK_RADIUS: f64 = 0.4;
+/// A fixed fraction of the block, so that the duck floats the same at any
+/// resolution
+pub const DUCK_MASS: f64 = BLOCK_MASS / 90.0;
+
+/// Initial arrangement of the particles
+#[derive(Clone, Debug, PartialEq)]
+pub enum Scene {
+    /// A block of water dropped into the tank, `default` on the command line
+    /// to leave the `dam-break` preset its name
+    DamBreak,
+    /// A square patch in the middle of the tank spaced 10% wider than at
+    /// rest, so that it starts under tension. Best run with h close to the
+    /// particle spacing, where without artificial pressure the particles
+    /// clump in pairs and short strings. Run without gravity, so that only
+    /// the tension moves the patch.
+    Stretching,
+    /// Regions of fluid from a scene description, see `scene`
+    Fluid(Vec<Fluid>),
+}
+
+impl FromStr for Scene {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(Scene::DamBreak),
+            "stretching" => Ok(Scene::Stretching),
+            _ => Err(format!("Unknown scene: {}", s)),
+        }
+    }
+}
+
+/// Monaghan's artificial pressure against the tensile instability: pairs
+/// under tension get an extra repulsion weighted by (W_ij / W(dp))^n. It
+/// acts over the whole kernel support, but the weight is small beyond the
+/// initial spacing dp and grows quickly for pairs closer than it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ArtificialPressure {
+    /// Fraction of the negative pressure p / rho^2 turned into repulsion,
+    /// typically 0.2
+    pub epsilon: f64,
+    /// Typically 4
+    pub exponent: i32,
+}
+
+impl ArtificialPressure {
+    /// Addition to p_i / rho_i^2 + p_j / rho_j^2 for a pair whose kernel
+    /// value is `w`, with `reference` the kernel value at dp
+    fn term(&self, particle1: &Particle, particle2: &Particle, w: f64, reference: f64) -> f64 {
+        let tension = |particle: &Particle| {
+            if particle.pressure < 0.0 {
+                -self.epsilon * particle.pressure / particle.density.powi(2)
+            } else {
+                0.0
+            }
+        };
+        (tension(particle1) + tension(particle2)) * (w / reference).powi(self.exponent)
+    }
+}
+
+/// Settings that can be chosen per run
+#[derive(Clone, Debug)]
+pub struct Parameters {
+    pub scene: Scene,
+    /// Walls of the tank
+    pub domain: Domain,
+    /// Solids inside the tank, which no particles are placed in
+    pub obstacles: Vec<Obstacle>,
+    /// Initial state of the duck
+    pub duck: Duck,
+    /// Acceleration along +y
+    pub gravity: f64,
+    pub integrator: IntegratorType,
+    /// Run the density, force and integration loops on the rayon thread pool
+    pub parallel: bool,
+    /// Size of the thread pool, 0 for one thread per core
+    pub threads: usize,
+    /// The initial block is `resolution * resolution` particles
+    pub resolution: u32,
+    /// Arrangement of the particles in the fluid regions of the scene. The
+    /// square packing of the dam break has the block's aspect ratio.
+    pub packing: Packing,
+    /// Settle the particles before the first step
+    pub relaxation: Option<Relaxation>,
+    /// Start the fluid at rest under gravity, with the densities and
+    /// pressures of its depth and its columns compressed to match, see
+    /// `hydrostatic::initialise`. After any relaxation. Needs the continuity
+    /// density, see `check`.
+    pub hydrostatic: bool,
+    /// Hold up the fluid at the walls of the tank with mirror images of the
+    /// particles near them, see `walls`. Always on with `hydrostatic`, whose
+    /// columns rest on the floor.
+    pub wall_images: bool,
+    pub h: f64,
+    pub kernel: KernelType,
+    /// Lookup table of `kernel` used in its place, see `tabulate`
+    pub table: Option<Arc<Tabulated>>,
+    /// Normalise the density sum by the Shepard sum of neighbour volumes, so
+    /// that particles with few neighbours are not underestimated
+    pub shepard_density: bool,
+    pub density_method: DensityMethod,
+    /// With the continuity equation, reset the densities to their Shepard
+    /// filtered sums every this many steps
+    pub shepard_interval: Option<u64>,
+    /// Diffusive term in the continuity equation, unused with summation
+    pub density_diffusion: DensityDiffusion,
+    /// The delta of delta-SPH, typically 0.1
+    pub diffusion_coefficient: f64,
+    /// Shift particles towards uniform spacing after every step, with this
+    /// coefficient, see `shifting`. Implies divergence surface detection if
+    /// no other criterion is set.
+    pub shifting: Option<f64>,
+    pub gradient_correction: GradientCorrection,
+    /// Visit each pair of neighbours once and apply equal and opposite
+    /// forces, so momentum is conserved to round-off. Not allowed with a
+    /// gradient correction, whose per particle matrices make the pair terms
+    /// asymmetric, see `check`.
+    pub symmetric_forces: bool,
+    pub artificial_pressure: Option<ArtificialPressure>,
+    /// Classify the particles at the free surface on every evaluation
+    pub surface_detection: SurfaceDetection,
+    /// Compute the vorticity of every particle on every evaluation
+    pub vorticity: bool,
+    /// Strength of the vorticity confinement force, which implies `vorticity`
+    pub vorticity_confinement: Option<f64>,
+    /// Smagorinsky constant of the sub-particle-scale eddy viscosity added
+    /// to `MU`, typically 0.12
+    pub smagorinsky: Option<f64>,
+    /// Let calm particles sleep, skipping their density and forces
+    pub sleeping: Option<Sleeping>,
+    /// Split and merge particles in refinement and coarsening regions. Every
+    /// pass uses the mass and smoothing length of each particle, and the
+    /// neighbour search reaches as far as the heaviest merged particle needs.
+    pub adaptive: Option<AdaptiveResolution>,
+    pub mass: f64,
+    pub rest_density: f64,
+    /// Cache neighbour lists with this skin distance, see `NeighbourList`
+    pub skin: Option<f64>,
+    pub search: SearchBackend,
+    /// Storage used when `search` is `SearchBackend::Grid`
+    pub grid_backend: grid::GridBackend,
+    /// Sort the particles in memory along this order every `reorder_interval` steps
+    pub reorder: Option<grid::Ordering>,
+    pub reorder_interval: u64,
+}
+
+impl Parameters {
+    pub fn new() -> Parameters {
+        Parameters::with_resolution(N)
+    }
+
+    /// Smoothing length and particle mass follow the resolution so that the
+    /// total mass and rest density of the block stay the same.
+    pub fn with_resolution(n: u32) -> Parameters {
+        let width = START_MAX_X - START_MIN_X;
+        let height = START_MAX_Y - START_MIN_Y;
+        let mass = BLOCK_MASS / (n * n) as f64;
+        Parameters {
+            scene: Scene::DamBreak,
+            domain: Domain::tank(),
+            obstacles: Vec::new(),
+            duck: Duck::new(),
+            gravity: GRAVITY,
+            integrator: IntegratorType::VelocityVerlet,
+            parallel: cfg!(not(target_arch = "wasm32")),
+            threads: 0,
+            resolution: n,
+            packing: Packing::Square,
+            relaxation: None,
+            hydrostatic: false,
+            wall_images: false,
+            h: 4.0 * width / n as f64,
+            kernel: KernelType::WendlandC2,
+            table: None,
+            shepard_density: false,
+            density_method: DensityMethod::Summation,
+            shepard_interval: None,
+            density_diffusion: DensityDiffusion::None,
+            diffusion_coefficient: 0.1,
+            shifting: None,
+            gradient_correction: GradientCorrection::None,
+            symmetric_forces: false,
+            artificial_pressure: None,
+            surface_detection: SurfaceDetection::None,
+            vorticity: false,
+            vorticity_confinement: None,
+            smagorinsky: None,
+            sleeping: None,
+            adaptive: None,
+            mass,
+            rest_density: mass * (n * n) as f64 / (width * height),
+            skin: None,
+            search: SearchBackend::Grid,
+            grid_backend: grid::GridBackend::Cells,
+            reorder: None,
+            reorder_interval: 100,
+        }
+    }
+
+    /// Distance within which particles interact
+    pub fn support_radius(&self) -> f64 {
+        self.kernel.support() * self.max_smoothing_length()
+    }
+
+    /// Largest smoothing length of any particle, `h` unless a region of the
+    /// scene has a coarser lattice or merging may produce heavier particles
+    pub fn max_smoothing_length(&self) -> f64 {
+        let coarsest = match &self.scene {
+            Scene::Fluid(fluid) => fluid
+                .iter()
+                .filter_map(Fluid::spacing)
+                .fold(1.0_f64, |ratio, spacing| {
+                    ratio.max(spacing / self.particle_spacing())
+                }),
+            _ => 1.0,
+        };
+        let heaviest = match &self.adaptive {
+            Some(adaptive) => adaptive.max_mass_ratio.max(1.0).sqrt(),
+            None => 1.0,
+        };
+        self.h * coarsest.max(heaviest)
+    }
+
+    /// Combinations of options that cannot run together
+    pub fn check(&self) -> Result<(), String> {
+        if self.symmetric_forces && self.gradient_correction != GradientCorrection::None {
+            return Err(format!(
+                "Symmetric forces cannot be combined with the {:?} gradient correction",
+                self.gradient_correction
+            ));
+        }
+        // Summed densities come from the positions alone, and would replace
+        // the hydrostatic ones at the first evaluation
+        if self.hydrostatic && self.density_method == DensityMethod::Summation {
+            return Err(
+                "Hydrostatic initialisation needs the continuity density, not summation"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Particle at rest at (x, y) with the mass, smoothing length and rest
+    /// density of these parameters
+    pub fn particle(&self, x: f64, y: f64) -> Particle {
+        Particle {
+            x,
+            y,
+            vx: 0.,
+            vy: 0.,
+            fx: 0.,
+            fy: 0.,
+            mass: self.mass,
+            h: self.h,
+            density: self.rest_density,
+            density_rate: 0.,
+            pressure: 0.,
+            surface: false,
+            nx: 0.,
+            ny: 0.,
+            vorticity: 0.,
+            eddy_viscosity: 0.,
+            asleep: false,
+            calm_steps: 0,
+        }
+    }
+
+    /// Spacing dp of a square lattice at the rest density
+    pub fn particle_spacing(&self) -> f64 {
+        (self.mass / self.rest_density).sqrt()
+    }
+
+    /// Evaluate the current kernel from a table of `size` samples
+    pub fn tabulate(&mut self, size: usize, interpolation: Interpolation) {
+        self.table = Some(Arc::new(Tabulated::new(&self.kernel, size, interpolation)));
+    }
+
+    /// The table if one was built, otherwise the analytic kernel
+    pub fn kernel_evaluator(&self) -> &dyn Kernel {
+        match &self.table {
+            Some(table) => &**table,
+            None => &self.kernel,
+        }
+    }
+}
 
 pub struct State {
     pub particles: Vec<Particle>,
     pub duck: Duck,
+    pub parameters: Parameters,
+    /// Structure of the last neighbour search. With a neighbour list its
+    /// radius is widened by the skin so it stays valid for density queries
+    /// between rebuilds.
+    pub search: Search,
+    neighbour_list: Option<NeighbourList>,
+    integrator: Box<dyn Integrator>,
+    /// Number of completed calls to `update_state`
+    pub step: u64,
+    pool: parallel::Pool,
+    /// Diagnostics after the first step, used as the reference for drift
+    pub initial_diagnostics: Option<Diagnostics>,
+    /// Outcome of the relaxation of the initial particles, if any
+    pub relaxation: Option<RelaxationReport>,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Duck {
     pub x: f64,
     pub y: f64,
-    vx: f64,
-    vy: f64,
+    pub vx: f64,
+    pub vy: f64,
 }
 
 impl Duck {
@@ -57,26 +375,37 @@ pub struct Particle {
     pub vy: f64,
     pub fx: f64,
     pub fy: f64,
-    pub ofx: f64,
-    pub ofy: f64,
+    /// Mass and smoothing length, those of `Parameters` unless adaptive
+    /// resolution has split or merged the particle or its region of the
+    /// scene has its own spacing
+    pub mass: f64,
+    pub h: f64,
     pub density: f64,
+    /// d rho / dt from the continuity equation, zero with density summation
+    pub density_rate: f64,
     pub pressure: f64,
+    /// At the free surface, see `surface::detect_surface`
+    pub surface: bool,
+    /// Outward unit normal at the free surface, zero elsewhere
+    pub nx: f64,
+    pub ny: f64,
+    /// Signed vorticity, counter-clockwise positive, see
+    /// `vorticity::compute_vorticity`
+    pub vorticity: f64,
+    /// Kinematic sub-particle-scale eddy viscosity, see
+    /// `turbulence::compute_eddy_viscosity`
+    pub eddy_viscosity: f64,
+    /// Not integrated, see `sleeping::update_activity`
+    pub asleep: bool,
+    /// Consecutive steps below the sleeping thresholds
+    pub calm_steps: u32,
 }
 
 impl Particle {
-    pub fn new(x: f64, y: f64) -> Particle {
-        Particle {
-            x: x,
-            y: y,
-            vx: 0.,
-            vy: 0.,
-            fx: 0.,
-            fy: 0.,
-            ofx: 0.,
-            ofy: 0.,
-            density: 1.,
-            pressure: 0.,
-        }
+    /// Spacing of a square lattice of particles of this mass at
+    /// `rest_density`, which its smoothing length follows
+    pub fn spacing(&self, rest_density: f64) -> f64 {
+        (self.mass / rest_density).sqrt()
     }
 }
 
@@ -86,6 +415,15 @@ pub struct SPHDebug {
     pub frame_time: u128,
     pub h: f64,
     pub grid_width: u64,
+    pub diagnostics: Diagnostics,
+    pub drift: Diagnostics,
+    pub energy_drift: f64,
+    pub neighbour_list_rebuilds: u64,
+    pub neighbour_list_rebuild_rate: f64,
+    /// Particles outside the domain at the last grid rebuild, if using a grid
+    pub escaped_particles: usize,
+    /// Particles not asleep after the step, all of them without sleeping
+    pub active_particles: usize,
 }
 
 impl SPHDebug {
@@ -96,65 +434,334 @@ impl SPHDebug {
             frame_time: 0,
             h: 0.0,
             grid_width: 0,
+            diagnostics: Diagnostics::new(),
+            drift: Diagnostics::new(),
+            energy_drift: 0.0,
+            neighbour_list_rebuilds: 0,
+            neighbour_list_rebuild_rate: 0.0,
+            escaped_particles: 0,
+            active_particles: 0,
         }
     }
 }
 
-pub fn create_initial_state() -> State {
+pub fn create_initial_state(parameters: Parameters) -> State {
     let mut particles = Vec::new();
-    let width = START_MAX_X - START_MIN_X;
-    let height = START_MAX_Y - START_MIN_Y;
-    let dx = width / N as f64;
-    let dy = height / N as f64;
-    for x in 0..N {
-        for y in 0..N {
-            let x = START_MIN_X + (x as f64) * dx;
-            let y = START_MIN_Y + (y as f64) * dy;
-            let particle = Particle::new(x, y);
-            particles.push(particle);
+    let n = parameters.resolution;
+    match &parameters.scene {
+        Scene::DamBreak if parameters.packing != Packing::Square => {
+            let bounds = (START_MIN_X, START_MIN_Y, START_MAX_X, START_MAX_Y);
+            let spacing = parameters.particle_spacing();
+            let inside = |x: f64, y: f64| {
+                (START_MIN_X..START_MAX_X).contains(&x) && (START_MIN_Y..START_MAX_Y).contains(&y)
+            };
+            for (x, y) in packing::fill(parameters.packing, spacing, bounds, inside) {
+                particles.push(parameters.particle(x, y));
+            }
+        }
+        Scene::DamBreak => {
+            let width = START_MAX_X - START_MIN_X;
+            let height = START_MAX_Y - START_MIN_Y;
+            let dx = width / n as f64;
+            let dy = height / n as f64;
+            for x in 0..n {
+                for y in 0..n {
+                    let x = START_MIN_X + (x as f64) * dx;
+                    let y = START_MIN_Y + (y as f64) * dy;
+                    let particle = parameters.particle(x, y);
+                    particles.push(particle);
+                }
+            }
+        }
+        Scene::Stretching => {
+            let spacing = 1.1 * parameters.particle_spacing();
+            let domain = &parameters.domain;
+            let centre_x = 0.5 * (domain.min_x + domain.max_x);
+            let centre_y = 0.5 * (domain.min_y + domain.max_y);
+            let start = -0.5 * (n - 1) as f64 * spacing;
+            for i in 0..n {
+                for j in 0..n {
+                    let angle = (i * n + j) as f64 * math::GOLDEN_ANGLE;
+                    let x = centre_x + start + i as f64 * spacing + 0.01 * spacing * angle.cos();
+                    let y = centre_y + start + j as f64 * spacing + 0.01 * spacing * angle.sin();
+                    let particle = parameters.particle(x, y);
+                    particles.push(particle);
+                }
+            }
+        }
+        Scene::Fluid(regions) => {
+            for region in regions.iter() {
+                particles.extend(region.particles(&parameters));
+            }
         }
     }
+    let obstacles = &parameters.obstacles;
+    particles.retain(|particle| {
+        !obstacles
+            .iter()
+            .any(|obstacle| obstacle.contains(particle.x, particle.y))
+    });
 
-    State {
+    let search = build_search(&particles, &parameters, 0.0);
+    let relaxation = parameters.relaxation;
+    let mut state = State {
         particles: particles,
-        duck: Duck::new(),
+        duck: parameters.duck,
+        search,
+        neighbour_list: parameters.skin.map(NeighbourList::new),
+        step: 0,
+        integrator: integrators::create_integrator(parameters.integrator, parameters.parallel),
+        pool: parallel::Pool::new(parameters.threads),
+        parameters,
+        initial_diagnostics: None,
+        relaxation: None,
+    };
+    if let Some(relaxation) = relaxation {
+        state.relaxation = Some(relax(&mut state, &relaxation));
     }
+    if state.parameters.hydrostatic {
+        hydrostatic::initialise(&mut state.particles, &state.parameters);
+        state.search = build_search(&state.particles, &state.parameters, 0.0);
+        if let Some(list) = &mut state.neighbour_list {
+            list.invalidate();
+        }
+    }
+    state
 }
 
-pub fn update_density(
-    particles: &mut Vec<Particle>,
-    grid: &grid::Grid,
-    debug: SPHDebug,
-) -> SPHDebug {
-    for particle in particles.iter_mut() {
-        particle.density = 0.0;
+/// Acceleration of a particle, without the part into a wall it touches,
+/// which the wall takes up
+fn unsupported_acceleration(particle: &Particle, domain: &Domain) -> (f64, f64) {
+    let (mut ax, mut ay) = (
+        particle.fx / particle.density,
+        particle.fy / particle.density,
+    );
+    if particle.x <= domain.min_x {
+        ax = ax.max(0.0);
     }
-    let mut max_density = 0.0;
-    let mut n_neighbours = 0;
-    // Wat?!
-    for i in 0..particles.len() {
-        let mut density = 0.;
+    if particle.x >= domain.max_x {
+        ax = ax.min(0.0);
+    }
+    if particle.y <= domain.min_y {
+        ay = ay.max(0.0);
+    }
+    if particle.y >= domain.max_y {
+        ay = ay.min(0.0);
+    }
+    (ax, ay)
+}
+
+/// Root mean square of the unsupported particle accelerations, relative to
+/// `gravity`
+fn acceleration_residual(particles: &[Particle], domain: &Domain, gravity: f64) -> f64 {
+    let sum: f64 = particles
+        .iter()
+        .map(|particle| {
+            let (ax, ay) = unsupported_acceleration(particle, domain);
+            ax * ax + ay * ay
+        })
+        .sum();
+    let scale = if gravity != 0.0 { gravity.abs() } else { 1.0 };
+    (sum / particles.len().max(1) as f64).sqrt() / scale
+}
+
+/// Settle the particles of a new state by damped pseudo-time stepping, with
+/// the walls and obstacles but a duck that stays put, and leave them at rest
+pub fn relax(state: &mut State, relaxation: &Relaxation) -> RelaxationReport {
+    let mut parameters = state.parameters.clone();
+    if !relaxation.hydrostatic {
+        parameters.gravity = 0.0;
+    }
+    // The relaxation evens out the spacing itself
+    parameters.shifting = None;
+    let density_reset = match parameters.density_method {
+        DensityMethod::Summation => None,
+        DensityMethod::Continuity => Some(DensityReset::Summation),
+    };
+    let mut duck = state.duck;
+    let mut physics = Physics {
+        parameters: &parameters,
+        duck: &mut duck,
+        search: &mut state.search,
+        neighbour_list: state.neighbour_list.as_mut(),
+        density_reset: None,
+        shift: false,
+        debug: SPHDebug::new(),
+    };
+    let particles = &mut state.particles;
+    let (dt, damping) = (relaxation.timestep, relaxation.damping);
+    let (domain, gravity) = (state.parameters.domain, state.parameters.gravity);
+    let mut report = RelaxationReport {
+        iterations: 0,
+        residual: f64::INFINITY,
+    };
+    state.pool.install(|| loop {
+        physics.density_reset = density_reset;
+        physics.evaluate(particles);
+        report.residual = acceleration_residual(particles, &domain, gravity);
+        if report.residual < relaxation.tolerance || report.iterations >= relaxation.max_iterations
         {
+            break;
+        }
+        for particle in particles.iter_mut() {
+            particle.vx = (1.0 - damping) * (particle.vx + dt * particle.fx / particle.density);
+            particle.vy = (1.0 - damping) * (particle.vy + dt * particle.fy / particle.density);
+            particle.x += dt * particle.vx;
+            particle.y += dt * particle.vy;
+        }
+        physics.constrain(particles);
+        report.iterations += 1;
+    });
+    for particle in state.particles.iter_mut() {
+        particle.vx = 0.0;
+        particle.vy = 0.0;
+    }
+    report
+}
+
+fn build_grid(particles: &[Particle], parameters: &Parameters, cell_size: f64) -> grid::Grid {
+    let Domain {
+        min_x,
+        min_y,
+        max_x,
+        max_y,
+    } = parameters.domain;
+    let backend = parameters.grid_backend;
+    let mut grid = grid::create_grid_with_backend(backend, cell_size, min_x, max_x, min_y, max_y);
+    for (index, particle) in particles.iter().enumerate() {
+        grid.add_particle(index as u32, particle.x, particle.y);
+    }
+    grid.finish();
+    grid
+}
+
+/// Search structure for the current positions that finds every particle
+/// within the kernel support plus `skin`
+fn build_search(particles: &[Particle], parameters: &Parameters, skin: f64) -> Search {
+    let radius = parameters.support_radius() + skin;
+    match parameters.search {
+        SearchBackend::Grid => Search::Grid(build_grid(particles, parameters, radius)),
+        SearchBackend::KdTree => Search::KdTree(KdTree::new(particles, radius)),
+        SearchBackend::BruteForce => Search::BruteForce(BruteForce::new(particles, radius)),
+    }
+}
+
+/// Sort the particles in memory along `ordering` so that particles close in
+/// space are also close in memory. Invalidates any cached neighbour list.
+pub fn reorder_particles(state: &mut State, ordering: grid::Ordering) {
+    let cell_size = state.parameters.support_radius();
+    let domain = &state.parameters.domain;
+    let grid = grid::create_grid(
+        cell_size,
+        domain.min_x,
+        domain.max_x,
+        domain.min_y,
+        domain.max_y,
+    );
+    state
+        .particles
+        .sort_by_cached_key(|particle| grid.order_key(ordering, particle.x, particle.y));
+    if let Some(list) = &mut state.neighbour_list {
+        list.invalidate();
+    }
+}
+
+/// Smoothing length of a pair, the mean of the two so that W_ij = W_ji
+pub fn pair_h(particle1: &Particle, particle2: &Particle) -> f64 {
+    0.5 * (particle1.h + particle2.h)
+}
+
+/// Density by direct summation, sum_j m_j W_ij, and the number of neighbours
+/// visited
+fn summed_densities<N: ParticleNeighbours>(
+    particles: &[Particle],
+    neighbours: &N,
+    parameters: &Parameters,
+) -> Vec<(f64, usize)> {
+    let kernel = parameters.kernel_evaluator();
+    // Volumes 1 / sum_j W_ij from the particle distribution alone
+    let volumes = if parameters.shepard_density {
+        parallel::map(particles.len(), parameters.parallel, |i| {
+            let mut number_density = 0.;
             let particle1 = &particles[i];
-            let neighbours = grid.get_neighbours(particle1.x, particle1.y);
-            if neighbours.len() > n_neighbours {
-                n_neighbours = neighbours.len()
-            }
-            for j in neighbours {
+            let walls = walls::Walls::near(particle1, parameters);
+            neighbours.for_each_neighbour_of(particles, i, |j| {
+                let mut add = |particle2: &Particle| {
+                    let r2 =
+                        (particle1.x - particle2.x).powi(2) + (particle1.y - particle2.y).powi(2);
+                    number_density += kernel.value_r2(r2, pair_h(particle1, particle2));
+                };
                 let particle2 = &particles[j as usize];
-                let r = math::length(particle1.x - particle2.x, particle1.y - particle2.y);
-                density += M * kernels::kernel_2d(r, H);
-            }
+                add(particle2);
+                walls.for_each_image(particle2, add);
+            });
+            1.0 / number_density
+        })
+    } else {
+        Vec::new()
+    };
+    parallel::map(particles.len(), parameters.parallel, |i| {
+        let mut density = 0.;
+        let mut shepard = 0.;
+        let mut n_neighbours = 0;
+        let particle1 = &particles[i];
+        if particle1.asleep {
+            return (particle1.density, 0);
         }
-        {
-            let particle = &mut particles[i];
-            particle.density = density;
-            if density > max_density {
-                max_density = density;
-            }
-            // particle.pressure = GAS_CONST * f64::max(particle.density - REST_DENS, 0.0);
-            particle.pressure = GAS_CONST * (particle.density - REST_DENS);
+        let walls = walls::Walls::near(particle1, parameters);
+        neighbours.for_each_neighbour_of(particles, i, |j| {
+            n_neighbours += 1;
+            let mut add = |particle2: &Particle| {
+                let r2 = (particle1.x - particle2.x).powi(2) + (particle1.y - particle2.y).powi(2);
+                let w = kernel.value_r2(r2, pair_h(particle1, particle2));
+                density += particle2.mass * w;
+                if parameters.shepard_density {
+                    shepard += volumes[j as usize] * w;
+                }
+            };
+            let particle2 = &particles[j as usize];
+            add(particle2);
+            walls.for_each_image(particle2, add);
+        });
+        if parameters.shepard_density {
+            density /= shepard;
+        }
+        (density, n_neighbours)
+    })
+}
+
+pub fn update_density<N: ParticleNeighbours>(
+    particles: &mut [Particle],
+    neighbours: &N,
+    parameters: &Parameters,
+    debug: SPHDebug,
+) -> SPHDebug {
+    let densities = match parameters.density_method {
+        DensityMethod::Summation => summed_densities(particles, neighbours, parameters),
+        DensityMethod::Continuity => {
+            let rates = density::continuity_rates(particles, neighbours, parameters);
+            particles
+                .iter_mut()
+                .zip(rates)
+                .map(|(particle, (rate, n_neighbours))| {
+                    particle.density_rate = rate;
+                    (particle.density, n_neighbours)
+                })
+                .collect()
         }
+    };
+    let mut max_density = 0.0;
+    let mut n_neighbours = 0;
+    for (particle, (density, neighbours)) in particles.iter_mut().zip(densities) {
+        if neighbours > n_neighbours {
+            n_neighbours = neighbours
+        }
+        particle.density = density;
+        if density > max_density {
+            max_density = density;
+        }
+        // particle.pressure = GAS_CONST * f64::max(particle.density - parameters.rest_density, 0.0);
+        particle.pressure = GAS_CONST * (particle.density - parameters.rest_density);
     }
     return SPHDebug {
         max_density,
@@ -163,131 +770,1096 @@ pub fn update_density(
     };
 }
 
-pub fn calculate_forces(
-    particles: &mut Vec<Particle>,
-    grid: &grid::Grid,
+pub fn calculate_forces<N: ParticleNeighbours>(
+    particles: &mut [Particle],
+    neighbours: &N,
+    parameters: &Parameters,
     debug: SPHDebug,
 ) -> SPHDebug {
-    let new_forces: Vec<_> = (0..particles.len())
-        .into_iter()
-        .map(|i| {
-            let mut fx = 0.;
-            let mut fy: f64;
-            {
-                let particle1 = &particles[i];
-                fy = GRAVITY * particle1.density;
-                let neighbours = grid.get_neighbours(particle1.x, particle1.y);
-                for j in neighbours {
-                    if i as u32 != j {
-                        let particle2 = &particles[j as usize];
-                        let rx = particle1.x - particle2.x;
-                        let ry = particle1.y - particle2.y;
-                        let p_over_rho_1 = particle1.pressure / particle1.density.powi(2);
-                        let p_over_rho_2 = particle2.pressure / particle2.density.powi(2);
-                        let (grad_x, grad_y) = kernels::grad_kernel_2d(rx, ry, H);
-                        let laplacian = kernels::laplace_kernel_2d(math::length(rx, ry), H);
-                        let advection = -M * particle1.density * (p_over_rho_1 + p_over_rho_2);
-                        let diffusion = -laplacian * MU * M / particle2.density;
-                        fx += grad_x * advection + diffusion * (particle2.vx - particle1.vx);
-                        fy += grad_y * advection + diffusion * (particle2.vy - particle1.vy);
+    if parameters.symmetric_forces {
+        calculate_pair_forces(particles, neighbours, parameters);
+        return debug;
+    }
+    let kernel = parameters.kernel_evaluator();
+    let corrections = correction::compute_corrections(particles, neighbours, parameters);
+    let new_forces = parallel::map(particles.len(), parameters.parallel, |i| {
+        let mut fx = 0.;
+        let mut fy: f64;
+        {
+            let particle1 = &particles[i];
+            if particle1.asleep {
+                return (particle1.fx, particle1.fy);
+            }
+            let walls = walls::Walls::near(particle1, parameters);
+            fy = parameters.gravity * particle1.density;
+            neighbours.for_each_neighbour_of(particles, i, |j| {
+                let mut add = |particle2: &Particle| {
+                    let rx = particle1.x - particle2.x;
+                    let ry = particle1.y - particle2.y;
+                    let p_over_rho_1 = particle1.pressure / particle1.density.powi(2);
+                    let p_over_rho_2 = particle2.pressure / particle2.density.powi(2);
+                    let (h, m) = (pair_h(particle1, particle2), particle2.mass);
+                    let r2 = rx.powi(2) + ry.powi(2);
+                    let w = kernel.value_r2(r2, h);
+                    let (grad_x, grad_y) = corrections[i].gradient(kernel.gradient(rx, ry, h), w);
+                    let laplacian = kernel.laplacian_r2(r2, h);
+                    let mut pressure = p_over_rho_1 + p_over_rho_2;
+                    if let Some(artificial) = &parameters.artificial_pressure {
+                        let reference = artificial_reference(parameters, h);
+                        pressure += artificial.term(particle1, particle2, w, reference);
                     }
+                    let advection = -m * particle1.density * pressure;
+                    let mu = MU + eddy_viscosity(particle1, particle2);
+                    let diffusion = -laplacian * mu * m / particle2.density;
+                    fx += grad_x * advection + diffusion * (particle2.vx - particle1.vx);
+                    fy += grad_y * advection + diffusion * (particle2.vy - particle1.vy);
+                };
+                let particle2 = &particles[j as usize];
+                if i as u32 != j {
+                    add(particle2);
                 }
-            }
-            (fx, fy)
-        })
-        .collect();
+                walls.for_each_image(particle2, add);
+            });
+        }
+        (fx, fy)
+    });
     for (i, (fx, fy)) in new_forces.into_iter().enumerate() {
         let particle = &mut particles[i];
-        particle.ofx = particle.fx;
-        particle.ofy = particle.fy;
         particle.fx = fx;
         particle.fy = fy;
     }
     debug
 }
 
-pub fn update_state(state: &mut State, dt: f64, debug: SPHDebug) -> (grid::Grid, SPHDebug) {
-    let mut grid = grid::create_grid(H, MIN_X, MAX_X, MIN_Y, MAX_Y);
+/// Kernel value at the particle spacing for smoothing length `h`, which
+/// scales the artificial pressure. The spacing scales with h.
+fn artificial_reference(parameters: &Parameters, h: f64) -> f64 {
+    let spacing = parameters.particle_spacing() * h / parameters.h;
+    parameters.kernel_evaluator().value(spacing, h)
+}
+
+/// Dynamic eddy viscosity of a pair, the mean of rho nu_t of the two
+/// particles, so that the viscous term stays antisymmetric
+fn eddy_viscosity(particle1: &Particle, particle2: &Particle) -> f64 {
+    0.5 * (particle1.density * particle1.eddy_viscosity
+        + particle2.density * particle2.eddy_viscosity)
+}
+
+/// Blocks of pairs that `calculate_pair_forces` sums separately, fixed so
+/// that the result does not depend on the thread count
+const PAIR_BLOCKS: usize = 16;
+
+/// The same forces as `calculate_forces`, summed over each pair once. The
+/// pressure and viscosity accelerations of a pair are antisymmetric, so they
+/// are computed once and added to both particles. Each block of pairs adds
+/// up its own accelerations, in parallel, and the blocks are then summed in
+/// order, which keeps the serial and parallel results identical.
+fn calculate_pair_forces<N: ParticleNeighbours>(
+    particles: &mut [Particle],
+    neighbours: &N,
+    parameters: &Parameters,
+) {
+    let support2 = parameters.support_radius().powi(2);
+    let walls = parallel::map(particles.len(), parameters.parallel, |i| {
+        walls::Walls::near(&particles[i], parameters)
+    });
+    let blocks = parallel::map(PAIR_BLOCKS, parameters.parallel, |block| {
+        let mut accelerations = vec![(0.0, 0.0); particles.len()];
+        neighbours.for_each_pair_in(particles, block, PAIR_BLOCKS, |i, j| {
+            let (i, j) = (i as usize, j as usize);
+            let (particle1, particle2) = (&particles[i], &particles[j]);
+            let r2 = (particle1.x - particle2.x).powi(2) + (particle1.y - particle2.y).powi(2);
+            if r2 >= support2 || (particle1.asleep && particle2.asleep) {
+                return;
+            }
+            let (ax, ay) = pair_acceleration(particle1, particle2, parameters);
+            let (mi, mj) = (particle1.mass, particle2.mass);
+            accelerations[i].0 += mj * ax;
+            accelerations[i].1 += mj * ay;
+            accelerations[j].0 -= mi * ax;
+            accelerations[j].1 -= mi * ay;
+            // Images act on one particle only, the walls taking up the
+            // reaction. Both particles are near the same walls when either
+            // has images of the other.
+            walls[i].for_each_image(particle2, |image| {
+                let (ax, ay) = pair_acceleration(particle1, image, parameters);
+                accelerations[i].0 += image.mass * ax;
+                accelerations[i].1 += image.mass * ay;
+            });
+            walls[j].for_each_image(particle1, |image| {
+                let (ax, ay) = pair_acceleration(particle2, image, parameters);
+                accelerations[j].0 += image.mass * ax;
+                accelerations[j].1 += image.mass * ay;
+            });
+        });
+        accelerations
+    });
+    for (i, particle) in particles.iter_mut().enumerate() {
+        if particle.asleep {
+            continue;
+        }
+        let (mut ax, mut ay) = (0.0, parameters.gravity);
+        for accelerations in blocks.iter() {
+            ax += accelerations[i].0;
+            ay += accelerations[i].1;
+        }
+        walls[i].for_each_image(particle, |image| {
+            let (image_ax, image_ay) = pair_acceleration(particle, image, parameters);
+            ax += image.mass * image_ax;
+            ay += image.mass * image_ay;
+        });
+        particle.fx = ax * particle.density;
+        particle.fy = ay * particle.density;
+    }
+}
+
+/// Pressure and viscous acceleration of `particle1` from `particle2`, per
+/// unit mass of `particle2`, which is the opposite of the acceleration of
+/// `particle2` per unit mass of `particle1`
+fn pair_acceleration(
+    particle1: &Particle,
+    particle2: &Particle,
+    parameters: &Parameters,
+) -> (f64, f64) {
+    let kernel = parameters.kernel_evaluator();
+    let rx = particle1.x - particle2.x;
+    let ry = particle1.y - particle2.y;
+    let r2 = rx.powi(2) + ry.powi(2);
+    let p_over_rho_1 = particle1.pressure / particle1.density.powi(2);
+    let p_over_rho_2 = particle2.pressure / particle2.density.powi(2);
+    let h = pair_h(particle1, particle2);
+    let (grad_x, grad_y) = kernel.gradient(rx, ry, h);
+    let laplacian = kernel.laplacian_r2(r2, h);
+    let mut pressure = p_over_rho_1 + p_over_rho_2;
+    if let Some(artificial) = &parameters.artificial_pressure {
+        let reference = artificial_reference(parameters, h);
+        pressure += artificial.term(particle1, particle2, kernel.value_r2(r2, h), reference);
+    }
+    let advection = -pressure;
+    let mu = MU + eddy_viscosity(particle1, particle2);
+    let diffusion = -laplacian * mu / (particle1.density * particle2.density);
+    (
+        grad_x * advection + diffusion * (particle2.vx - particle1.vx),
+        grad_y * advection + diffusion * (particle2.vy - particle1.vy),
+    )
+}
+
+/// Walls, the duck and the SPH forces as seen by the integrator
+struct Physics<'a> {
+    parameters: &'a Parameters,
+    duck: &'a mut Duck,
+    search: &'a mut Search,
+    neighbour_list: Option<&'a mut NeighbourList>,
+    /// Reset the integrated densities at the next evaluation
+    density_reset: Option<DensityReset>,
+    /// Shift the particles before the next evaluation, which ends the step
+    shift: bool,
+    debug: SPHDebug,
+}
+
+/// Ways of resetting densities integrated with the continuity equation
+#[derive(Clone, Copy)]
+enum DensityReset {
+    /// Direct summation, which gives the initial densities
+    Summation,
+    Shepard,
+}
+
+impl<'a> Physics<'a> {
+    /// Search for the current positions, rebuilding the neighbour list only
+    /// once a particle has moved too far
+    fn update_search(&mut self, particles: &[Particle]) {
+        let parameters = self.parameters;
+        match &mut self.neighbour_list {
+            Some(list) => {
+                if list.needs_rebuild(particles) {
+                    *self.search = build_search(particles, parameters, list.skin());
+                    list.build(
+                        particles,
+                        self.search,
+                        parameters.support_radius(),
+                        parameters.parallel,
+                    );
+                }
+            }
+            None => *self.search = build_search(particles, parameters, 0.0),
+        }
+    }
+
+    /// Push particles out of the walls, obstacles and the duck, reflecting
+    /// their velocity, and with `exchange` give the duck the momentum
+    fn collide(&mut self, particles: &mut [Particle], exchange: bool) {
+        let duck = &mut self.duck;
+        let domain = &self.parameters.domain;
+        for particle in particles.iter_mut() {
+            if particle.x > domain.max_x {
+                particle.x = domain.max_x;
+                particle.vx = -DAMPING * particle.vx;
+            }
+            if particle.x < domain.min_x {
+                particle.x = domain.min_x;
+                particle.vx = -DAMPING * particle.vx;
+            }
+            if particle.y > domain.max_y {
+                particle.y = domain.max_y;
+                particle.vy = -DAMPING * particle.vy;
+            }
+            if particle.y < domain.min_y {
+                particle.y = domain.min_y;
+                particle.vy = -DAMPING * particle.vy;
+            }
+            for obstacle in self.parameters.obstacles.iter() {
+                if let Some((normal_x, normal_y, depth)) =
+                    obstacle.penetration(particle.x, particle.y)
+                {
+                    // Only the velocity into the obstacle is reflected
+                    let dot = (normal_x * particle.vx + normal_y * particle.vy).min(0.0);
+                    particle.vx -= (1.0 + DAMPING) * dot * normal_x;
+                    particle.vy -= (1.0 + DAMPING) * dot * normal_y;
+                    particle.x += normal_x * depth;
+                    particle.y += normal_y * depth;
+                }
+            }
+            if (particle.x - duck.x).powi(2) + (particle.y - duck.y).powi(2) < DUCK_RADIUS.powi(2) {
+                let distance_x = particle.x - duck.x;
+                let distance_y = particle.y - duck.y;
+                let distance = f64::sqrt(distance_x.powi(2) + distance_y.powi(2));
+                let normal_x = distance_x / distance;
+                let normal_y = distance_y / distance;
+                let dot = normal_x * particle.vx + normal_y * particle.vy;
+                particle.vx -= (1.0 + DAMPING) * dot * normal_x;
+                particle.vy -= (1.0 + DAMPING) * dot * normal_y;
+                particle.x += normal_x * (DUCK_RADIUS - distance);
+                particle.y += normal_y * (DUCK_RADIUS - distance);
+
+                if exchange {
+                    duck.vx += particle.mass / DUCK_MASS * (1.0 + DAMPING) * dot * normal_x;
+                    duck.vy += particle.mass / DUCK_MASS * (1.0 + DAMPING) * dot * normal_y;
+                }
+            }
+        }
+    }
+}
+
+impl<'a> System for Physics<'a> {
+    fn constrain(&mut self, particles: &mut [Particle]) {
+        self.collide(particles, true);
+        self.shift = self.parameters.shifting.is_some();
+    }
+
+    fn constrain_stage(&mut self, particles: &mut [Particle]) {
+        self.collide(particles, false);
+    }
+
+    fn evaluate(&mut self, particles: &mut [Particle]) {
+        let parameters = self.parameters;
+        if let Some(list) = &mut self.neighbour_list {
+            list.evaluations += 1;
+        }
+        self.update_search(particles);
+        if let (true, Some(coefficient)) = (std::mem::take(&mut self.shift), parameters.shifting) {
+            // Shifted and constrained before the forces are computed, so
+            // that they belong to the final positions of the step
+            match &self.neighbour_list {
+                Some(list) => {
+                    shifting::shift_particles(particles, &**list, parameters, coefficient)
+                }
+                None => shifting::shift_particles(particles, self.search, parameters, coefficient),
+            }
+            self.collide(particles, false);
+            self.update_search(particles);
+        }
+        let debug = std::mem::replace(&mut self.debug, SPHDebug::new());
+        let reset = self.density_reset.take();
+        self.debug = match &self.neighbour_list {
+            Some(list) => density_and_forces(particles, &**list, parameters, reset, debug),
+            None => density_and_forces(particles, self.search, parameters, reset, debug),
+        };
+    }
+}
+
+fn density_and_forces<N: ParticleNeighbours>(
+    particles: &mut [Particle],
+    neighbours: &N,
+    parameters: &Parameters,
+    reset: Option<DensityReset>,
+    debug: SPHDebug,
+) -> SPHDebug {
+    match reset {
+        Some(DensityReset::Summation) => {
+            let densities = summed_densities(particles, neighbours, parameters);
+            for (particle, (density, _)) in particles.iter_mut().zip(densities) {
+                particle.density = density;
+            }
+        }
+        Some(DensityReset::Shepard) => density::shepard_filter(particles, neighbours, parameters),
+        None => {}
+    }
+    let debug = update_density(particles, neighbours, parameters, debug);
+    surface::detect_surface(particles, neighbours, parameters);
+    if parameters.vorticity || parameters.vorticity_confinement.is_some() {
+        vorticity::compute_vorticity(particles, neighbours, parameters);
+    }
+    if let Some(smagorinsky) = parameters.smagorinsky {
+        turbulence::compute_eddy_viscosity(particles, neighbours, parameters, smagorinsky);
+    }
+    let debug = calculate_forces(particles, neighbours, parameters, debug);
+    if let Some(strength) = parameters.vorticity_confinement {
+        vorticity::add_confinement(particles, neighbours, parameters, strength);
+    }
+    debug
+}
+
+pub fn update_state(state: &mut State, dt: f64, debug: SPHDebug) -> SPHDebug {
+    // Before reordering, while the last search still indexes the particles
+    if let Some(adaptive) = &state.parameters.adaptive {
+        if state.step.is_multiple_of(adaptive.interval) {
+            let (particles, parameters, duck) =
+                (&mut state.particles, &state.parameters, &state.duck);
+            let (search, neighbour_list) = (&state.search, &state.neighbour_list);
+            let changed = state.pool.install(|| match neighbour_list {
+                Some(list) => adaptive.adapt(particles, list, parameters, duck),
+                None => adaptive.adapt(particles, search, parameters, duck),
+            });
+            if let (true, Some(list)) = (changed, &mut state.neighbour_list) {
+                list.invalidate();
+            }
+        }
+    }
+    if let Some(ordering) = state.parameters.reorder {
+        if state.step.is_multiple_of(state.parameters.reorder_interval) {
+            reorder_particles(state, ordering);
+        }
+    }
+    let density_reset = match state.parameters.density_method {
+        DensityMethod::Summation => None,
+        // The particles start without densities to integrate, unless
+        // hydrostatic ones
+        DensityMethod::Continuity if state.step == 0 && !state.parameters.hydrostatic => {
+            Some(DensityReset::Summation)
+        }
+        DensityMethod::Continuity => state
+            .parameters
+            .shepard_interval
+            .filter(|&interval| state.step.is_multiple_of(interval))
+            .map(|_| DensityReset::Shepard),
+    };
+    state.step += 1;
 
     let duck = &mut state.duck;
+    let domain = &state.parameters.domain;
 
     duck.x += duck.vx * dt;
     duck.y += duck.vy * dt;
 
-    if duck.y > MAX_Y - DUCK_RADIUS {
+    if duck.y > domain.max_y - DUCK_RADIUS {
         duck.vy = -duck.vy;
-        duck.y = MAX_Y - DUCK_RADIUS;
+        duck.y = domain.max_y - DUCK_RADIUS;
     }
-    if duck.x > MAX_X - DUCK_RADIUS {
+    if duck.x > domain.max_x - DUCK_RADIUS {
         duck.vx = -duck.vx;
-        duck.x = MAX_X - DUCK_RADIUS;
+        duck.x = domain.max_x - DUCK_RADIUS;
     }
-    if duck.x < MIN_X + DUCK_RADIUS {
+    if duck.x < domain.min_x + DUCK_RADIUS {
         duck.vx = -duck.vx;
-        duck.x = MIN_X + DUCK_RADIUS;
+        duck.x = domain.min_x + DUCK_RADIUS;
+    }
+
+    duck.vy += state.parameters.gravity * dt;
+
+    let mut physics = Physics {
+        parameters: &state.parameters,
+        duck,
+        search: &mut state.search,
+        neighbour_list: state.neighbour_list.as_mut(),
+        density_reset,
+        shift: false,
+        debug,
+    };
+    let integrator = &mut state.integrator;
+    let particles = &mut state.particles;
+    state
+        .pool
+        .install(|| integrator.step(particles, dt, &mut physics));
+    let debug = physics.debug;
+    let active_particles = match &state.parameters.sleeping {
+        Some(sleeping) => {
+            let (particles, parameters, duck) =
+                (&mut state.particles, &state.parameters, &state.duck);
+            let (search, neighbour_list) = (&state.search, &state.neighbour_list);
+            state.pool.install(|| match neighbour_list {
+                Some(list) => {
+                    sleeping::update_activity(particles, list, parameters, sleeping, duck)
+                }
+                None => sleeping::update_activity(particles, search, parameters, sleeping, duck),
+            })
+        }
+        None => state.particles.len(),
+    };
+
+    let diagnostics = diagnostics::measure(&state.particles, &state.duck, &state.parameters);
+    let initial = *state.initial_diagnostics.get_or_insert(diagnostics);
+    let (neighbour_list_rebuilds, neighbour_list_rebuild_rate) = match &state.neighbour_list {
+        Some(list) => (list.rebuilds, list.rebuild_rate()),
+        None => (0, 0.0),
+    };
+    return SPHDebug {
+        h: state.parameters.h,
+        grid_width: state.search.grid().map_or(0, |grid| grid.grid_width()),
+        diagnostics,
+        drift: diagnostics.drift(&initial),
+        energy_drift: diagnostics.relative_energy_drift(&initial),
+        neighbour_list_rebuilds,
+        neighbour_list_rebuild_rate,
+        escaped_particles: state.search.grid().map_or(0, |grid| grid.escaped().len()),
+        active_particles,
+        ..debug
+    };
+}
+
+#[allow(dead_code)]
+pub fn density(
+    particles: &Vec<Particle>,
+    search: &impl NeighbourSearch,
+    parameters: &Parameters,
+    x: f64,
+    y: f64,
+) -> f64 {
+    let mut density = 0.0;
+    search.for_each_neighbour(x, y, |i| {
+        let particle = &particles[i as usize];
+        let r = math::length(x - particle.x, y - particle.y);
+        density += particle.mass * parameters.kernel_evaluator().value(r, particle.h);
+    });
+    return density;
+}
+
+#[allow(dead_code)]
+/// Vorticity at (x, y), interpolated from the particles with Shepard
+/// normalisation, or zero away from the fluid
+pub fn vorticity(
+    particles: &[Particle],
+    search: &impl NeighbourSearch,
+    parameters: &Parameters,
+    x: f64,
+    y: f64,
+) -> f64 {
+    let kernel = parameters.kernel_evaluator();
+    let (mut vorticity, mut shepard) = (0.0, 0.0);
+    search.for_each_neighbour(x, y, |i| {
+        let particle = &particles[i as usize];
+        let r = math::length(x - particle.x, y - particle.y);
+        let weight = particle.mass / particle.density * kernel.value(r, particle.h);
+        vorticity += weight * particle.vorticity;
+        shepard += weight;
+    });
+    if shepard > 0.0 {
+        vorticity / shepard
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(parameters: Parameters, steps: u32) -> State {
+        let mut state = create_initial_state(parameters);
+        for _ in 0..steps {
+            update_state(&mut state, 0.0005, SPHDebug::new());
+        }
+        state
     }
 
-    duck.vy += GRAVITY * dt;
+    #[test]
+    fn test_duck_to_fluid_mass_independent_of_resolution() {
+        let ratio = |n: u32| {
+            let state = create_initial_state(Parameters::with_resolution(n));
+            let fluid: f64 = state.particles.iter().map(|particle| particle.mass).sum();
+            DUCK_MASS / fluid
+        };
+        assert!((ratio(20) - ratio(N)).abs() < 1e-12);
+        assert!((ratio(45) - ratio(N)).abs() < 1e-12);
+    }
 
-    for (index, particle) in state.particles.iter_mut().enumerate() {
-        // Velocity Verlet (position update)
-        particle.x =
-            particle.x + particle.vx * dt + 0.5 * (particle.fx / particle.density) * dt * dt;
-        particle.y =
-            particle.y + particle.vy * dt + 0.5 * (particle.fy / particle.density) * dt * dt;
+    #[test]
+    fn test_parallel_matches_serial() {
+        let serial = run(
+            Parameters {
+                parallel: false,
+                ..Parameters::new()
+            },
+            20,
+        );
+        let parallel = run(
+            Parameters {
+                parallel: true,
+                threads: 4,
+                ..Parameters::new()
+            },
+            20,
+        );
+        assert_eq!(serial.particles, parallel.particles);
+    }
 
-        if particle.x > MAX_X {
-            particle.x = MAX_X;
-            particle.vx = -DAMPING * particle.vx;
+    #[test]
+    fn test_neighbour_list_matches_grid() {
+        let grid = run(Parameters::new(), 20);
+        let list = run(
+            Parameters {
+                skin: Some(0.1),
+                ..Parameters::new()
+            },
+            20,
+        );
+        for (particle1, particle2) in grid.particles.iter().zip(list.particles.iter()) {
+            assert!((particle1.x - particle2.x).abs() < 1e-9);
+            assert!((particle1.y - particle2.y).abs() < 1e-9);
+            assert!((particle1.density - particle2.density).abs() < 1e-6);
         }
-        if particle.x < MIN_X {
-            particle.x = MIN_X;
-            particle.vx = -DAMPING * particle.vx;
+        let rebuilds = list.neighbour_list.unwrap().rebuilds;
+        assert!((1..20).contains(&rebuilds));
+    }
+
+    #[test]
+    fn test_all_kernels_run() {
+        for kernel in [
+            "cubic",
+            "wendland2",
+            "wendland4",
+            "wendland6",
+            "poly6",
+            "spiky",
+            "muller",
+        ]
+        .iter()
+        {
+            let state = run(
+                Parameters {
+                    kernel: kernel.parse().unwrap(),
+                    ..Parameters::new()
+                },
+                5,
+            );
+            assert!(state
+                .particles
+                .iter()
+                .all(|particle| particle.x.is_finite() && particle.y.is_finite()));
         }
-        if particle.y > MAX_Y {
-            particle.y = MAX_Y;
-            particle.vy = -DAMPING * particle.vy;
+    }
+
+    #[test]
+    fn test_singular_kernels_at_the_walls() {
+        // Particles clamped onto the walls meet the images of their
+        // neighbours, but not of themselves, where these kernels are singular
+        for kernel in ["spiky", "muller"].iter() {
+            let state = run(
+                Parameters {
+                    kernel: kernel.parse().unwrap(),
+                    wall_images: true,
+                    ..Parameters::with_resolution(10)
+                },
+                400,
+            );
+            assert!(state.particles.iter().all(|particle| {
+                particle.x.is_finite() && particle.vx.is_finite() && particle.fx.is_finite()
+            }));
         }
-        if particle.y < MIN_Y {
-            particle.y = MIN_Y;
-            particle.vy = -DAMPING * particle.vy;
+    }
+
+    #[test]
+    fn test_corrections_run() {
+        for &gradient_correction in [
+            GradientCorrection::RandlesLibersky,
+            GradientCorrection::BonetLok,
+        ]
+        .iter()
+        {
+            let state = run(
+                Parameters {
+                    shepard_density: true,
+                    gradient_correction,
+                    ..Parameters::new()
+                },
+                5,
+            );
+            assert!(state
+                .particles
+                .iter()
+                .all(|particle| particle.x.is_finite() && particle.density > 0.0));
         }
-        if (particle.x - duck.x).powi(2) + (particle.y - duck.y).powi(2) < DUCK_RADIUS.powi(2) {
-            let distance_x = particle.x - duck.x;
-            let distance_y = particle.y - duck.y;
-            let distance = f64::sqrt(distance_x.powi(2) + distance_y.powi(2));
-            let normal_x = distance_x / distance;
-            let normal_y = distance_y / distance;
-            let dot = normal_x * particle.vx + normal_y * particle.vy;
-            particle.vx -= (1.0 + DAMPING) * dot * normal_x;
-            particle.vy -= (1.0 + DAMPING) * dot * normal_y;
-            particle.x += normal_x * (DUCK_RADIUS - distance);
-            particle.y += normal_y * (DUCK_RADIUS - distance);
+    }
 
-            duck.vx += M / DUCK_MASS * (1.0 + DAMPING) * dot * normal_x;
-            duck.vy += M / DUCK_MASS * (1.0 + DAMPING) * dot * normal_y;
+    #[test]
+    fn test_symmetric_forces_match_particle_loop() {
+        let symmetric = Parameters {
+            symmetric_forces: true,
+            ..Parameters::new()
+        };
+        let reference = run(Parameters::new(), 20);
+        let serial = run(
+            Parameters {
+                parallel: false,
+                ..symmetric.clone()
+            },
+            20,
+        );
+        for (particle1, particle2) in reference.particles.iter().zip(serial.particles.iter()) {
+            assert!((particle1.x - particle2.x).abs() < 1e-9);
+            assert!((particle1.y - particle2.y).abs() < 1e-9);
+        }
+        let parallel = run(
+            Parameters {
+                parallel: true,
+                threads: 4,
+                ..symmetric.clone()
+            },
+            20,
+        );
+        assert_eq!(serial.particles, parallel.particles);
+        let hash = run(
+            Parameters {
+                grid_backend: grid::GridBackend::Hash,
+                ..symmetric.clone()
+            },
+            20,
+        );
+        assert_eq!(serial.particles, hash.particles);
+        let list = run(
+            Parameters {
+                skin: Some(0.1),
+                ..symmetric
+            },
+            20,
+        );
+        for (particle1, particle2) in serial.particles.iter().zip(list.particles.iter()) {
+            assert!((particle1.x - particle2.x).abs() < 1e-9);
+            assert!((particle1.y - particle2.y).abs() < 1e-9);
+        }
+        // The wall images, added as the pairs are walked
+        let walled = Parameters {
+            wall_images: true,
+            ..Parameters::new()
+        };
+        let reference = run(walled.clone(), 20);
+        let symmetric = run(
+            Parameters {
+                symmetric_forces: true,
+                ..walled
+            },
+            20,
+        );
+        for (particle1, particle2) in reference.particles.iter().zip(symmetric.particles.iter()) {
+            assert!((particle1.x - particle2.x).abs() < 1e-9);
+            assert!((particle1.y - particle2.y).abs() < 1e-9);
         }
-        grid.add_particle(index as u32, particle.x, particle.y);
     }
-    let debug1 = update_density(&mut state.particles, &grid, debug);
-    let debug2 = calculate_forces(&mut state.particles, &grid, debug1);
 
-    for particle in state.particles.iter_mut() {
-        // Velocity Verlet (velocity update)
-        particle.vx = particle.vx + (particle.ofx + particle.fx) / particle.density / 2.0 * dt;
-        particle.vy = particle.vy + (particle.ofy + particle.fy) / particle.density / 2.0 * dt;
+    #[test]
+    fn test_symmetric_forces_conserve_momentum() {
+        let state = run(
+            Parameters {
+                symmetric_forces: true,
+                ..Parameters::new()
+            },
+            20,
+        );
+        let (mut total_x, mut total_y, mut magnitude) = (0.0, 0.0, 0.0);
+        for particle in state.particles.iter() {
+            let ax = particle.fx / particle.density;
+            let ay = particle.fy / particle.density - GRAVITY;
+            total_x += ax;
+            total_y += ay;
+            magnitude += ax.abs() + ay.abs();
+        }
+        assert!(total_x.abs() < 1e-12 * magnitude);
+        assert!(total_y.abs() < 1e-12 * magnitude);
     }
-    return (grid, SPHDebug { h: H, ..debug2 });
-}
 
-#[allow(dead_code)]
-pub fn density(particles: &Vec<Particle>, grid: &grid::Grid, x: f64, y: f64) -> f64 {
-    let mut density = 0.0;
-    let neighbours = grid.get_neighbours(x, y);
-    for i in neighbours {
-        let particle = &particles[i as usize];
-        let r = math::length(x - particle.x, y - particle.y);
-        density += M * kernels::kernel_2d(r, H);
+    #[test]
+    fn test_symmetric_forces_reject_gradient_correction() {
+        let mut parameters = Parameters {
+            symmetric_forces: true,
+            ..Parameters::new()
+        };
+        assert!(parameters.check().is_ok());
+        parameters.gradient_correction = GradientCorrection::BonetLok;
+        assert!(parameters.check().is_err());
+    }
+
+    #[test]
+    fn test_continuity_density_follows_summation() {
+        let summation = run(Parameters::new(), 20);
+        let continuity = run(
+            Parameters {
+                density_method: DensityMethod::Continuity,
+                ..Parameters::new()
+            },
+            20,
+        );
+        let mut difference = 0.0;
+        for (particle1, particle2) in summation.particles.iter().zip(continuity.particles.iter()) {
+            difference += (particle1.density - particle2.density).abs() / particle1.density;
+        }
+        assert!(difference / (summation.particles.len() as f64) < 0.02);
+        assert!(continuity
+            .particles
+            .iter()
+            .any(|particle| particle.density_rate != 0.0));
+        assert!(summation
+            .particles
+            .iter()
+            .all(|particle| particle.density_rate == 0.0));
+
+        // The filter fills in the density deficit at the free surface, so
+        // only the mean stays close to the summed densities
+        let reinitialised = run(
+            Parameters {
+                density_method: DensityMethod::Continuity,
+                shepard_interval: Some(5),
+                ..Parameters::new()
+            },
+            20,
+        );
+        let mean = |state: &State| {
+            state
+                .particles
+                .iter()
+                .map(|particle| particle.density)
+                .sum::<f64>()
+                / state.particles.len() as f64
+        };
+        assert!((mean(&reinitialised) - mean(&summation)).abs() < 0.05 * mean(&summation));
+    }
+
+    #[test]
+    fn test_stabilisation_runs() {
+        for &density_diffusion in [
+            DensityDiffusion::MolteniColagrossi,
+            DensityDiffusion::Antuono,
+        ]
+        .iter()
+        {
+            let state = run(
+                Parameters {
+                    density_method: DensityMethod::Continuity,
+                    density_diffusion,
+                    shifting: Some(0.01),
+                    ..Parameters::new()
+                },
+                20,
+            );
+            assert!(state
+                .particles
+                .iter()
+                .all(|particle| particle.x.is_finite() && particle.density > 0.0));
+        }
+    }
+
+    #[test]
+    fn test_shifting_ends_step_with_current_forces() {
+        let state = run(
+            Parameters {
+                shifting: Some(0.05),
+                // Evaluates last, so the forces are those of the final velocities too
+                integrator: IntegratorType::SymplecticEuler,
+                ..Parameters::new()
+            },
+            20,
+        );
+        let (parameters, domain) = (&state.parameters, &state.parameters.domain);
+        assert!(state.particles.iter().all(|particle| {
+            (domain.min_x..=domain.max_x).contains(&particle.x)
+                && (domain.min_y..=domain.max_y).contains(&particle.y)
+        }));
+        let mut particles = state.particles.clone();
+        let search = build_search(&particles, parameters, 0.0);
+        density_and_forces(&mut particles, &search, parameters, None, SPHDebug::new());
+        assert_eq!(particles, state.particles);
+    }
+
+    /// Particles of the stretching scene summed over 8 snapshots that are
+    /// closer than half the particle spacing to another
+    fn clumped_particles(artificial_pressure: Option<ArtificialPressure>) -> usize {
+        let mut parameters = Parameters {
+            scene: Scene::Stretching,
+            gravity: 0.0,
+            kernel: "wendland2".parse().unwrap(),
+            artificial_pressure,
+            ..Parameters::with_resolution(20)
+        };
+        let spacing = parameters.particle_spacing();
+        parameters.h = 1.3 * spacing;
+        let mut state = create_initial_state(parameters);
+        let mut clumped = 0;
+        for step in 0..200 {
+            update_state(&mut state, 0.0005, SPHDebug::new());
+            if step % 25 != 24 {
+                continue;
+            }
+            let particles = &state.particles;
+            clumped += particles
+                .iter()
+                .enumerate()
+                .filter(|&(k, particle1)| {
+                    particles.iter().enumerate().any(|(l, particle2)| {
+                        l != k
+                            && (particle1.x - particle2.x).powi(2)
+                                + (particle1.y - particle2.y).powi(2)
+                                < (0.5 * spacing).powi(2)
+                    })
+                })
+                .count();
+        }
+        clumped
+    }
+
+    #[test]
+    fn test_artificial_pressure_reduces_clumping() {
+        let plain = clumped_particles(None);
+        let corrected = clumped_particles(Some(ArtificialPressure {
+            epsilon: 0.2,
+            exponent: 4,
+        }));
+        assert!(4 * corrected < 3 * plain, "{} {}", corrected, plain);
+    }
+
+    #[test]
+    fn test_sleeping_particles_stay_until_disturbed() {
+        // A tank at hydrostatic rest, wider than a few neighbour search cells
+        // on either side of the duck falling into it from its usual start.
+        // Calm is what the resting tank keeps to, see
+        // `test_hydrostatic_tank_stays_at_rest`.
+        let mut parameters = Parameters {
+            density_method: DensityMethod::Continuity,
+            hydrostatic: true,
+            ..Parameters::new()
+        };
+        parameters.domain.max_x = parameters.domain.min_x + 10.0;
+        let domain = parameters.domain;
+        let width = domain.max_x - domain.min_x;
+        let spacing = width / (width / parameters.particle_spacing()).round();
+        let depth = 11.0 * spacing;
+        parameters.scene = Scene::Fluid(vec![Fluid::Rectangle {
+            min_x: domain.min_x,
+            min_y: domain.max_y - depth,
+            max_x: domain.max_x,
+            max_y: domain.max_y,
+            spacing: Some(spacing),
+        }]);
+        parameters.sleeping = Some(Sleeping {
+            velocity: 0.1 * (parameters.gravity * depth).sqrt(),
+            acceleration: 0.5 * parameters.gravity,
+            ..Sleeping::new()
+        });
+        let mut state = create_initial_state(parameters);
+        let all_asleep = |state: &State| state.particles.iter().all(|particle| particle.asleep);
+        while !all_asleep(&state) {
+            assert!(state.step < 200);
+            update_state(&mut state, 0.0005, SPHDebug::new());
+        }
+
+        // They stay put, keeping their density and forces
+        let key = |particle: &Particle| (particle.x, particle.y, particle.density, particle.fy);
+        let start: Vec<_> = state.particles.iter().map(key).collect();
+        for _ in 0..20 {
+            update_state(&mut state, 0.0005, SPHDebug::new());
+        }
+        assert!(all_asleep(&state));
+        for (particle, start) in state.particles.iter().zip(start.iter()) {
+            assert_eq!(key(particle), *start);
+            assert!(particle.fy != 0.0);
+        }
+
+        // Until the duck comes down into the water beneath it, while the
+        // fluid at the far wall sleeps on
+        let surface = domain.max_y - depth;
+        while state.duck.y + DUCK_RADIUS < surface {
+            update_state(&mut state, 0.0005, SPHDebug::new());
+        }
+        for (particle, start) in state.particles.iter().zip(start.iter()) {
+            if (particle.x - state.duck.x).abs() < DUCK_RADIUS {
+                assert!(!particle.asleep);
+            }
+            if particle.x > domain.max_x - spacing {
+                assert!(particle.asleep);
+                assert_eq!(key(particle), *start);
+            }
+        }
+        assert!(state.particles.iter().any(|particle| particle.vy != 0.0));
+    }
+
+    #[test]
+    fn test_grid_backends_match_cells() {
+        let cells = run(Parameters::new(), 20);
+        let compact = run(
+            Parameters {
+                grid_backend: grid::GridBackend::Compact,
+                ..Parameters::new()
+            },
+            20,
+        );
+        assert_eq!(cells.particles, compact.particles);
+        let hash = run(
+            Parameters {
+                grid_backend: grid::GridBackend::Hash,
+                ..Parameters::new()
+            },
+            20,
+        );
+        assert_eq!(cells.particles, hash.particles);
+    }
+
+    #[test]
+    fn test_search_backends_match_grid() {
+        let grid = run(Parameters::new(), 10);
+        for &search in [SearchBackend::KdTree, SearchBackend::BruteForce].iter() {
+            let state = run(
+                Parameters {
+                    search,
+                    ..Parameters::new()
+                },
+                10,
+            );
+            for (particle1, particle2) in grid.particles.iter().zip(state.particles.iter()) {
+                assert!((particle1.x - particle2.x).abs() < 1e-9);
+                assert!((particle1.y - particle2.y).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn test_reordering_keeps_particles() {
+        let mut state = run(Parameters::new(), 5);
+        let key = |particle: &Particle| (particle.x.to_bits(), particle.y.to_bits());
+        let mut before = state.particles.clone();
+        reorder_particles(&mut state, grid::Ordering::Morton);
+        let mut after = state.particles.clone();
+        before.sort_by_key(key);
+        after.sort_by_key(key);
+        assert_eq!(before, after);
+
+        let mut reordered = run(
+            Parameters {
+                reorder: Some(grid::Ordering::Morton),
+                reorder_interval: 3,
+                skin: Some(0.1),
+                ..Parameters::new()
+            },
+            20,
+        );
+        let mut reference = run(Parameters::new(), 20);
+        reordered.particles.sort_by_key(key);
+        reference.particles.sort_by_key(key);
+        for (particle1, particle2) in reference.particles.iter().zip(reordered.particles.iter()) {
+            assert!((particle1.x - particle2.x).abs() < 1e-9);
+            assert!((particle1.y - particle2.y).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_relaxation_settles_hexagonal_block() {
+        let relaxation = Relaxation::new(0.05);
+        let parameters = Parameters {
+            packing: Packing::Hexagonal,
+            relaxation: Some(relaxation),
+            ..Parameters::new()
+        };
+        let unrelaxed = create_initial_state(Parameters {
+            relaxation: None,
+            ..parameters.clone()
+        });
+        let state = create_initial_state(parameters);
+        let report = state.relaxation.unwrap();
+        assert!(report.converged(&relaxation), "{:?}", report);
+        assert!(report.iterations > 0 && report.iterations < relaxation.max_iterations);
+        assert_eq!(state.particles.len(), unrelaxed.particles.len());
+        assert!(state
+            .particles
+            .iter()
+            .all(|particle| particle.vx == 0.0 && particle.vy == 0.0));
+        // Without gravity the block settles in place
+        let mean_y = |particles: &[Particle]| {
+            particles.iter().map(|particle| particle.y).sum::<f64>() / particles.len() as f64
+        };
+        assert!((mean_y(&state.particles) - mean_y(&unrelaxed.particles)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_hydrostatic_tank_stays_at_rest() {
+        // The tank filled wall to wall, on a lattice of whole spacings across
+        // it, until the duck falling from its usual start reaches the water
+        let mut parameters = Parameters {
+            density_method: DensityMethod::Continuity,
+            ..Parameters::new()
+        };
+        let domain = parameters.domain;
+        let width = domain.max_x - domain.min_x;
+        let spacing = width / (width / parameters.particle_spacing()).round();
+        let depth = 11.0 * spacing;
+        parameters.scene = Scene::Fluid(vec![Fluid::Rectangle {
+            min_x: domain.min_x,
+            min_y: domain.max_y - depth,
+            max_x: domain.max_x,
+            max_y: domain.max_y,
+            spacing: Some(spacing),
+        }]);
+        let wave_speed = (parameters.gravity * depth).sqrt();
+        let max_speed = |state: &State| {
+            state
+                .particles
+                .iter()
+                .map(|particle| particle.vx.hypot(particle.vy))
+                .fold(0.0, f64::max)
+        };
+
+        let mut state = create_initial_state(Parameters {
+            hydrostatic: true,
+            ..parameters.clone()
+        });
+        let surface = domain.max_y - depth;
+        while state.duck.y + DUCK_RADIUS < surface - spacing {
+            update_state(&mut state, 0.0005, SPHDebug::new());
+            assert!(
+                max_speed(&state) < 0.1 * wave_speed,
+                "{}",
+                max_speed(&state)
+            );
+        }
+        let mean_speed = state
+            .particles
+            .iter()
+            .map(|particle| particle.vx.hypot(particle.vy))
+            .sum::<f64>()
+            / state.particles.len() as f64;
+        assert!(mean_speed < 0.05 * wave_speed, "{}", mean_speed);
+        assert!(state.step > 300);
+
+        // Starting at the rest density the column collapses, walls or not
+        let mut state = create_initial_state(Parameters {
+            wall_images: true,
+            ..parameters
+        });
+        let mut worst: f64 = 0.0;
+        for _ in 0..200 {
+            update_state(&mut state, 0.0005, SPHDebug::new());
+            worst = worst.max(max_speed(&state));
+        }
+        assert!(worst > wave_speed, "{}", worst);
+    }
+
+    #[test]
+    fn test_hydrostatic_needs_continuity() {
+        let parameters = Parameters {
+            hydrostatic: true,
+            ..Parameters::new()
+        };
+        assert!(parameters.check().is_err());
+        let parameters = Parameters {
+            density_method: DensityMethod::Continuity,
+            ..parameters
+        };
+        assert!(parameters.check().is_ok());
     }
-    return density;
 }
@@ -0,0 +1,187 @@
+//! Classification of particles at the free surface, with the outward normal
+//! of the fluid taken from the gradient of the colour field
+//! C_i = sum_j V_j W_ij
+
+use crate::neighbours::ParticleNeighbours;
+use crate::parallel;
+use crate::sph::{self, Parameters, Particle};
+use crate::walls;
+
+use std::str::FromStr;
+
+/// The divergence of position, sum_j V_j r_ji . grad W_ij, is the dimension
+/// (2) inside the fluid and drops towards the free surface. Below this the
+/// particle is taken to be at the surface.
+pub const FREE_SURFACE_DIVERGENCE: f64 = 1.5;
+
+/// The colour gradient vanishes inside the fluid and is about 1.5 / (2h) at
+/// a flat surface. Above this, relative to the support radius, the particle
+/// is taken to be at the surface.
+pub const FREE_SURFACE_COLOUR_GRADIENT: f64 = 0.75;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SurfaceDetection {
+    None,
+    /// Divergence of position below `FREE_SURFACE_DIVERGENCE`, also used
+    /// when shifting is on without a criterion chosen
+    Divergence,
+    /// Length of the colour gradient above `FREE_SURFACE_COLOUR_GRADIENT`,
+    /// which also marks thin sheets and drops
+    ColourGradient,
+}
+
+impl FromStr for SurfaceDetection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(SurfaceDetection::None),
+            "divergence" => Ok(SurfaceDetection::Divergence),
+            "colour" => Ok(SurfaceDetection::ColourGradient),
+            _ => Err(format!("Unknown surface detection: {}", s)),
+        }
+    }
+}
+
+/// The configured criterion, or the divergence if there is none but
+/// shifting needs to know the free surface
+fn criterion(parameters: &Parameters) -> SurfaceDetection {
+    match parameters.surface_detection {
+        SurfaceDetection::None if parameters.shifting.is_some() => SurfaceDetection::Divergence,
+        detection => detection,
+    }
+}
+
+/// Set `surface` of every particle by the configured criterion, and the unit
+/// normal `nx`, `ny` pointing out of the fluid for those at the surface. The
+/// normal of particles inside the fluid is zero.
+pub fn detect_surface<N: ParticleNeighbours>(
+    particles: &mut [Particle],
+    neighbours: &N,
+    parameters: &Parameters,
+) {
+    let detection = criterion(parameters);
+    if detection == SurfaceDetection::None {
+        return;
+    }
+    let kernel = parameters.kernel_evaluator();
+    let particles_ref = &*particles;
+    let classified = parallel::map(particles.len(), parameters.parallel, |i| {
+        let particle1 = &particles_ref[i];
+        let walls = walls::Walls::near(particle1, parameters);
+        let mut divergence = 0.0;
+        let (mut colour_x, mut colour_y) = (0.0, 0.0);
+        neighbours.for_each_neighbour_of(particles_ref, i, |j| {
+            // The images fill in the neighbourhood beyond the walls
+            let mut add = |particle2: &Particle| {
+                let rx = particle1.x - particle2.x;
+                let ry = particle1.y - particle2.y;
+                let volume = particle2.mass / particle2.density;
+                let (grad_x, grad_y) = kernel.gradient(rx, ry, sph::pair_h(particle1, particle2));
+                divergence -= volume * (rx * grad_x + ry * grad_y);
+                colour_x += volume * grad_x;
+                colour_y += volume * grad_y;
+            };
+            let particle2 = &particles_ref[j as usize];
+            add(particle2);
+            walls.for_each_image(particle2, add);
+        });
+        let length = (colour_x * colour_x + colour_y * colour_y).sqrt();
+        let surface = match detection {
+            SurfaceDetection::Divergence => divergence < FREE_SURFACE_DIVERGENCE,
+            _ => length * kernel.support() * particle1.h > FREE_SURFACE_COLOUR_GRADIENT,
+        };
+        // The kernel gradient points towards the neighbours, so into the fluid
+        if surface && length > 0.0 {
+            (true, -colour_x / length, -colour_y / length)
+        } else {
+            (surface, 0.0, 0.0)
+        }
+    });
+    for (particle, (surface, nx, ny)) in particles.iter_mut().zip(classified) {
+        particle.surface = surface;
+        particle.nx = nx;
+        particle.ny = ny;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fixtures::{self, lattice};
+    use crate::neighbours::BruteForce;
+    use crate::scene::Domain;
+
+    fn classify(detection: SurfaceDetection) -> Vec<Particle> {
+        let parameters = Parameters {
+            h: 0.13,
+            surface_detection: detection,
+            ..fixtures::parameters()
+        };
+        let mut particles = lattice(0..12, &parameters, |_, _| (0.0, 0.0));
+        let search = BruteForce::new(&particles, parameters.support_radius());
+        detect_surface(&mut particles, &search, &parameters);
+        particles
+    }
+
+    #[test]
+    fn test_block_surface_and_normals() {
+        for &detection in [
+            SurfaceDetection::Divergence,
+            SurfaceDetection::ColourGradient,
+        ]
+        .iter()
+        {
+            let particles = classify(detection);
+            for (k, particle) in particles.iter().enumerate() {
+                let (i, j) = (k as i32 / 12, k as i32 % 12);
+                if i == 0 || i == 11 || j == 0 || j == 11 {
+                    assert!(particle.surface, "{:?} {} {}", detection, i, j);
+                } else if (3..9).contains(&i) && (3..9).contains(&j) {
+                    assert!(!particle.surface, "{:?} {} {}", detection, i, j);
+                    assert_eq!((particle.nx, particle.ny), (0.0, 0.0));
+                }
+            }
+            // Middle of the left edge faces -x, the corners face diagonally out
+            let left = &particles[6];
+            assert!(left.nx < -0.99 && left.ny.abs() < 1e-9);
+            let corner = &particles[12 * 12 - 1];
+            assert!((corner.nx - corner.ny).abs() < 1e-9 && corner.nx > 0.7);
+        }
+    }
+
+    #[test]
+    fn test_block_against_a_wall() {
+        // The left wall half a spacing from the block, the others far away
+        let parameters = Parameters {
+            h: 0.13,
+            surface_detection: SurfaceDetection::Divergence,
+            wall_images: true,
+            domain: Domain {
+                min_x: -0.5 * fixtures::SPACING,
+                min_y: -10.0,
+                max_x: 10.0,
+                max_y: 10.0,
+            },
+            ..fixtures::parameters()
+        };
+        let mut particles = lattice(0..12, &parameters, |_, _| (0.0, 0.0));
+        let search = BruteForce::new(&particles, parameters.support_radius());
+        detect_surface(&mut particles, &search, &parameters);
+        // Along the wall, away from the top and bottom, there is no surface
+        for particle in particles[3..9].iter() {
+            assert!(!particle.surface);
+            assert_eq!((particle.nx, particle.ny), (0.0, 0.0));
+        }
+        // The opposite edge still is one, facing away from the wall
+        let right = &particles[11 * 12 + 6];
+        assert!(right.surface && right.nx > 0.99);
+    }
+
+    #[test]
+    fn test_no_detection_leaves_particles() {
+        let particles = classify(SurfaceDetection::None);
+        assert!(particles.iter().all(|particle| !particle.surface));
+    }
+}
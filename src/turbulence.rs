@@ -0,0 +1,88 @@
+//! Sub-particle-scale turbulence: a Smagorinsky eddy viscosity
+//! nu_t = (C_s dp)^2 |S| from the local strain rate, which adds the
+//! dissipation of the eddies smaller than the particle spacing dp
+
+use crate::neighbours::ParticleNeighbours;
+use crate::parallel;
+use crate::sph::{self, Parameters, Particle};
+
+/// Set the kinematic eddy viscosity of every particle from the Smagorinsky
+/// constant `smagorinsky`, typically 0.12, and the strain rate magnitude
+/// |S| = sqrt(2 S_ab S_ab) of the velocity gradient
+/// sum_j V_j (v_j - v_i) grad W_ij
+pub fn compute_eddy_viscosity<N: ParticleNeighbours>(
+    particles: &mut [Particle],
+    neighbours: &N,
+    parameters: &Parameters,
+    smagorinsky: f64,
+) {
+    let kernel = parameters.kernel_evaluator();
+    let particles_ref = &*particles;
+    let viscosities = parallel::map(particles.len(), parameters.parallel, |i| {
+        let particle1 = &particles_ref[i];
+        let length2 = (smagorinsky * particle1.spacing(parameters.rest_density)).powi(2);
+        // Row major d v_a / d x_b
+        let mut gradient = [0.0; 4];
+        neighbours.for_each_neighbour_of(particles_ref, i, |j| {
+            let particle2 = &particles_ref[j as usize];
+            let h = sph::pair_h(particle1, particle2);
+            let (grad_x, grad_y) =
+                kernel.gradient(particle1.x - particle2.x, particle1.y - particle2.y, h);
+            let volume = particle2.mass / particle2.density;
+            let (vx, vy) = (particle2.vx - particle1.vx, particle2.vy - particle1.vy);
+            gradient[0] += volume * vx * grad_x;
+            gradient[1] += volume * vx * grad_y;
+            gradient[2] += volume * vy * grad_x;
+            gradient[3] += volume * vy * grad_y;
+        });
+        let shear = 0.5 * (gradient[1] + gradient[2]);
+        let strain2 = gradient[0].powi(2) + 2.0 * shear.powi(2) + gradient[3].powi(2);
+        length2 * (2.0 * strain2).sqrt()
+    });
+    for (particle, viscosity) in particles.iter_mut().zip(viscosities) {
+        particle.eddy_viscosity = viscosity;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fixtures::{lattice, parameters};
+    use crate::neighbours::BruteForce;
+
+    /// Eddy viscosities of a square lattice around the origin with velocity
+    /// field `velocity`, and the particle positions
+    fn eddy_viscosities(velocity: impl Fn(f64, f64) -> (f64, f64)) -> Vec<Particle> {
+        let parameters = parameters();
+        let mut particles = lattice(-10..=10, &parameters, velocity);
+        let search = BruteForce::new(&particles, parameters.support_radius());
+        compute_eddy_viscosity(&mut particles, &search, &parameters, 0.12);
+        particles
+    }
+
+    fn interior(particles: &[Particle]) -> impl Iterator<Item = &Particle> {
+        particles
+            .iter()
+            .filter(|particle| particle.x.abs() < 0.55 && particle.y.abs() < 0.55)
+    }
+
+    #[test]
+    fn test_simple_shear() {
+        // Shear rate 5, so |S| = 5 and nu_t = (0.12 * 0.1)^2 * 5
+        let particles = eddy_viscosities(|_, y| (5.0 * y, 0.0));
+        let expected = (0.12f64 * 0.1).powi(2) * 5.0;
+        for particle in interior(&particles) {
+            assert!((particle.eddy_viscosity - expected).abs() < 1e-2 * expected);
+        }
+    }
+
+    #[test]
+    fn test_no_strain_without_deformation() {
+        // Rigid rotation and translation do not deform the fluid
+        let particles = eddy_viscosities(|x, y| (1.0 - 3.0 * y, 2.0 + 3.0 * x));
+        for particle in interior(&particles) {
+            assert!(particle.eddy_viscosity.abs() < 1e-12);
+        }
+    }
+}
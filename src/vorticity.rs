@@ -0,0 +1,131 @@
+//! Vorticity of the particle velocities and the vorticity confinement force
+//! of Fedkiw et al. (2001), which puts back the small swirls that the
+//! Laplacian viscosity damps out
+
+use crate::neighbours::ParticleNeighbours;
+use crate::parallel;
+use crate::sph::{self, Parameters, Particle};
+
+/// Set the signed vorticity omega_i = sum_j V_j (v_i - v_j) x grad W_ij of
+/// every particle, positive for counter-clockwise rotation
+pub fn compute_vorticity<N: ParticleNeighbours>(
+    particles: &mut [Particle],
+    neighbours: &N,
+    parameters: &Parameters,
+) {
+    let kernel = parameters.kernel_evaluator();
+    let particles_ref = &*particles;
+    let vorticities = parallel::map(particles.len(), parameters.parallel, |i| {
+        let particle1 = &particles_ref[i];
+        let mut vorticity = 0.0;
+        neighbours.for_each_neighbour_of(particles_ref, i, |j| {
+            let particle2 = &particles_ref[j as usize];
+            let h = sph::pair_h(particle1, particle2);
+            let (grad_x, grad_y) =
+                kernel.gradient(particle1.x - particle2.x, particle1.y - particle2.y, h);
+            let (vx, vy) = (particle1.vx - particle2.vx, particle1.vy - particle2.vy);
+            vorticity += particle2.mass / particle2.density * (vx * grad_y - vy * grad_x);
+        });
+        vorticity
+    });
+    for (particle, vorticity) in particles.iter_mut().zip(vorticities) {
+        particle.vorticity = vorticity;
+    }
+}
+
+/// Add the confinement force strength h (N x omega) to the forces, with N the
+/// unit vector up the gradient of |omega|, which pushes the flow round each
+/// vortex. Needs the vorticities of the current velocities.
+pub fn add_confinement<N: ParticleNeighbours>(
+    particles: &mut [Particle],
+    neighbours: &N,
+    parameters: &Parameters,
+    strength: f64,
+) {
+    let kernel = parameters.kernel_evaluator();
+    let particles_ref = &*particles;
+    let accelerations = parallel::map(particles.len(), parameters.parallel, |i| {
+        let particle1 = &particles_ref[i];
+        // Their stored forces already include it
+        if particle1.asleep {
+            return (0.0, 0.0);
+        }
+        let (mut gradient_x, mut gradient_y) = (0.0, 0.0);
+        neighbours.for_each_neighbour_of(particles_ref, i, |j| {
+            let particle2 = &particles_ref[j as usize];
+            let h = sph::pair_h(particle1, particle2);
+            let (grad_x, grad_y) =
+                kernel.gradient(particle1.x - particle2.x, particle1.y - particle2.y, h);
+            let difference = particle2.vorticity.abs() - particle1.vorticity.abs();
+            let volume = particle2.mass / particle2.density;
+            gradient_x += volume * difference * grad_x;
+            gradient_y += volume * difference * grad_y;
+        });
+        let length = (gradient_x * gradient_x + gradient_y * gradient_y).sqrt();
+        if length == 0.0 {
+            return (0.0, 0.0);
+        }
+        let scale = strength * particle1.h * particle1.vorticity / length;
+        (scale * gradient_y, -scale * gradient_x)
+    });
+    for (particle, (ax, ay)) in particles.iter_mut().zip(accelerations) {
+        particle.fx += particle.density * ax;
+        particle.fy += particle.density * ay;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fixtures::{lattice, parameters};
+    use crate::neighbours::BruteForce;
+
+    fn centre(particles: &[Particle], x: f64, y: f64) -> usize {
+        particles
+            .iter()
+            .position(|particle| (particle.x - x).abs() < 1e-9 && (particle.y - y).abs() < 1e-9)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_rigid_rotation() {
+        // Counter-clockwise at 3 rad / s, whose vorticity is 6
+        let parameters = parameters();
+        let mut particles = lattice(-10..=10, &parameters, |x, y| (-3.0 * y, 3.0 * x));
+        let search = BruteForce::new(&particles, parameters.support_radius());
+        compute_vorticity(&mut particles, &search, &parameters);
+        for particle in particles.iter() {
+            if particle.x.abs() < 0.55 && particle.y.abs() < 0.55 {
+                assert!(
+                    (particle.vorticity - 6.0).abs() < 1e-2,
+                    "{}",
+                    particle.vorticity
+                );
+            }
+        }
+
+        let mut translating = lattice(-10..=10, &parameters, |_, _| (1.0, -2.0));
+        compute_vorticity(&mut translating, &search, &parameters);
+        assert!(translating.iter().all(|particle| particle.vorticity == 0.0));
+    }
+
+    #[test]
+    fn test_confinement_pushes_flow_round_vortex() {
+        // Counter-clockwise vortex whose vorticity peaks at the centre
+        let parameters = parameters();
+        let mut particles = lattice(-10..=10, &parameters, |x, y| {
+            let speed = (-(x * x + y * y) / 0.1).exp();
+            (-speed * y, speed * x)
+        });
+        let search = BruteForce::new(&particles, parameters.support_radius());
+        compute_vorticity(&mut particles, &search, &parameters);
+        add_confinement(&mut particles, &search, &parameters, 1.0);
+        let right = &particles[centre(&particles, 0.3, 0.0)];
+        assert!(right.fy > 0.0 && right.fx.abs() < 1e-9 * right.fy);
+        let left = &particles[centre(&particles, -0.3, 0.0)];
+        assert!(left.fy < 0.0 && left.fx.abs() < 1e-9 * -left.fy);
+        let top = &particles[centre(&particles, 0.0, 0.3)];
+        assert!(top.fx < 0.0 && top.fy.abs() < 1e-9 * -top.fx);
+    }
+}
@@ -0,0 +1,159 @@
+//! Mirror images of the fluid across the walls of the tank, which stand in
+//! for the fluid beyond each wall like layers of boundary particles. Without
+//! them a particle near a wall sees only half a kernel support: its summed
+//! density falls short and the missing neighbours draw it into the wall.
+//! Images reflect the velocity normal to the wall, a free slip wall, and
+//! carry the pressure of the hydrostatic field continued through the wall,
+//! after Adami et al. (2012), so that the wall holds up the fluid above it.
+
+use crate::sph::{Parameters, Particle};
+
+/// A wall at a coordinate, facing the fluid on its positive or negative
+/// side, and the distance to it
+type Wall = (f64, f64, f64);
+
+/// The walls within reach of a particle
+pub struct Walls {
+    x: [Option<Wall>; 2],
+    y: [Option<Wall>; 2],
+    reach: f64,
+    gravity: f64,
+}
+
+impl Walls {
+    /// Walls that `particle` interacts with through the images of its
+    /// neighbours, none unless the parameters mirror the fluid
+    pub fn near(particle: &Particle, parameters: &Parameters) -> Walls {
+        let domain = &parameters.domain;
+        let reach = parameters.support_radius();
+        let mirrored = parameters.wall_images || parameters.hydrostatic;
+        let wall = |wall: f64, side: f64, coordinate: f64| {
+            let distance = side * (coordinate - wall);
+            (mirrored && (0.0..reach).contains(&distance)).then_some((wall, side, distance))
+        };
+        Walls {
+            x: [
+                wall(domain.min_x, 1.0, particle.x),
+                wall(domain.max_x, -1.0, particle.x),
+            ],
+            y: [
+                wall(domain.min_y, 1.0, particle.y),
+                wall(domain.max_y, -1.0, particle.y),
+            ],
+            reach,
+            gravity: parameters.gravity,
+        }
+    }
+
+    /// Call `f` with every image of `particle2` within reach of the particle
+    /// these walls are near: its reflections in each wall that both are near,
+    /// and in both walls of a corner
+    pub fn for_each_image(&self, particle2: &Particle, mut f: impl FnMut(&Particle)) {
+        if self.x == [None, None] && self.y == [None, None] {
+            return;
+        }
+        let xs = self.x.map(|wall| reflection(wall, particle2.x, self.reach));
+        let ys = self.y.map(|wall| reflection(wall, particle2.y, self.reach));
+        for x in xs.iter().flatten() {
+            f(&self.image(particle2, Some(*x), None));
+        }
+        for y in ys.iter().flatten() {
+            f(&self.image(particle2, None, Some(*y)));
+            for x in xs.iter().flatten() {
+                f(&self.image(particle2, Some(*x), Some(*y)));
+            }
+        }
+    }
+
+    /// `particle` reflected to `x` and `y`, where given, with the hydrostatic
+    /// pressure at its new depth. It keeps the density of `particle`, whose
+    /// volume goes with the spacing it mirrors.
+    fn image(&self, particle: &Particle, x: Option<f64>, y: Option<f64>) -> Particle {
+        let mut image = particle.clone();
+        if let Some(x) = x {
+            image.x = x;
+            image.vx = -particle.vx;
+        }
+        if let Some(y) = y {
+            image.y = y;
+            image.vy = -particle.vy;
+            image.pressure += particle.density * self.gravity * (y - particle.y);
+        }
+        image
+    }
+}
+
+/// Reflection of `coordinate` in `wall`, if it is on the fluid side and the
+/// reflection is within `reach` of the particle near the wall. A particle
+/// clamped onto the wall is its own reflection and has none, which would
+/// otherwise sit on top of it.
+fn reflection(wall: Option<Wall>, coordinate: f64, reach: f64) -> Option<f64> {
+    let (wall, side, distance) = wall?;
+    let other = side * (coordinate - wall);
+    (other > 0.0 && distance + other < reach).then_some(2.0 * wall - coordinate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_corner_images() {
+        let parameters = Parameters {
+            wall_images: true,
+            ..Parameters::new()
+        };
+        let domain = parameters.domain;
+        let particle1 = parameters.particle(domain.min_x + 0.1, domain.max_y - 0.1);
+        let mut particle2 = parameters.particle(domain.min_x + 0.2, domain.max_y - 0.3);
+        particle2.vx = 1.0;
+        particle2.vy = 2.0;
+        particle2.density = parameters.rest_density;
+        let mut images = Vec::new();
+        Walls::near(&particle1, &parameters)
+            .for_each_image(&particle2, |image| images.push(image.clone()));
+        assert_eq!(images.len(), 3);
+        for image in images.iter() {
+            let mirrored_x = image.x != particle2.x;
+            let mirrored_y = image.y != particle2.y;
+            assert!(mirrored_x || mirrored_y);
+            if mirrored_x {
+                assert!((image.x - (domain.min_x - 0.2)).abs() < 1e-12);
+                assert_eq!(image.vx, -1.0);
+            }
+            if mirrored_y {
+                // Below the floor, deeper in the continued hydrostatic field
+                assert!((image.y - (domain.max_y + 0.3)).abs() < 1e-12);
+                assert_eq!(image.vy, -2.0);
+                let weight = parameters.rest_density * parameters.gravity * 0.6;
+                assert!((image.pressure - weight).abs() < 1e-6 * weight);
+            } else {
+                assert_eq!(image.pressure, particle2.pressure);
+            }
+            assert_eq!(image.density, particle2.density);
+        }
+
+        // Away from the walls there are none
+        let centre = parameters.particle(2.5, 2.5);
+        Walls::near(&centre, &parameters).for_each_image(&centre, |_| panic!());
+        // Nor unless asked for
+        Walls::near(&particle1, &Parameters::new()).for_each_image(&particle2, |_| panic!());
+    }
+
+    #[test]
+    fn test_no_image_on_the_wall() {
+        // A particle clamped onto a wall is not its own neighbour, whose
+        // kernel may be singular at the origin
+        let parameters = Parameters {
+            wall_images: true,
+            ..Parameters::new()
+        };
+        let domain = parameters.domain;
+        let particle = parameters.particle(domain.min_x, 2.5);
+        Walls::near(&particle, &parameters).for_each_image(&particle, |_| panic!());
+        let neighbour = parameters.particle(domain.min_x + 0.1, 2.5);
+        let mut images = 0;
+        Walls::near(&particle, &parameters).for_each_image(&neighbour, |_| images += 1);
+        assert_eq!(images, 1);
+    }
+}